@@ -0,0 +1,39 @@
+#!/usr/bin/env bash
+# Runs the configured simulation with 1 and 2 processes and compares their results. Agent ids,
+# paths and fates do not depend on how floors are split, only timings may differ
+set -euo pipefail
+
+root="$(cd "$(dirname "$0")/.." && pwd)"
+work="$(mktemp -d)"
+trap 'rm -rf "$work"' EXIT
+
+cargo build --release --manifest-path "$root/Cargo.toml"
+
+for processes in 1 2; do
+    run="$work/$processes"
+    mkdir -p "$run/data"
+    ln -s "$root/resources" "$run/resources"
+
+    sed -e "s|^processes = [0-9]*|processes = $processes|" \
+        -e "s|^results_dir = .*|results_dir = \"./data/\"|" \
+        -e "s|^print_in_console = .*|print_in_console = false|" \
+        "$root/IoTwins_config.toml" >"$run/IoTwins_config.toml"
+
+    (cd "$run" && "$root/target/release/pandorast")
+done
+
+# Rows are written in map order, they are compared sorted
+status=0
+for file in paths.csv lifecycle_agents.csv lifecycle.csv gates.csv gates_queues.csv \
+    services.csv seating.csv incidents.csv; do
+    [ -f "$work/1/data/$file" ] || continue
+
+    if diff <(sort "$work/1/data/$file") <(sort "$work/2/data/$file") >"$work/$file.diff"; then
+        echo "$file: same"
+    else
+        echo "$file: differs ($(grep -c '^[<>]' "$work/$file.diff") rows)"
+        status=1
+    fi
+done
+
+exit $status
@@ -1,28 +1,97 @@
 pub mod configuration {
 
-    use crate::iotwins_model::config as model;
+    use crate::{
+        engine::logging::{self, Level, TargetLevels},
+        iotwins_model::{
+            config as model,
+            legend::{deserialize_legend, Legend},
+        },
+    };
     use serde::Deserialize;
-    use std::{fs::File, io::Write};
+    use std::{fs::File, io::Write, path::Path};
 
+    // Every output is written inside results_dir
     #[derive(Deserialize)]
-    struct Output {
-        results_dir: String,
-        results_file: String,
-        logs_file: String,
+    pub struct Output {
+        pub results_dir: String,
+        #[allow(dead_code)] // Consolidated results, not written by the simulation yet
+        pub results_file: String,
+        pub logs_file: String,
+        pub paths_file: String,
+        pub gates_file: String,
+        pub gates_queues_file: String,
+        pub services_file: String,
+        pub structures_file: String,
+        pub layer_paths_dir: String,
+        pub bincode_file: String,
+        pub instrumentation_file: String,
+        pub instrumentation_floors_file: String,
+        pub lifecycle_file: String,
+        pub lifecycle_agents_file: String,
+        pub validation_dir: String,
+        pub seating_file: String,
+        pub incidents_file: String,
+    }
+
+    impl Output {
+        pub fn path(&self, file: &str) -> String {
+            let path = Path::new(&self.results_dir).join(file);
+
+            // Nested outputs get their directories
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .expect("[ERROR] Unable to create results directory");
+            }
+
+            path.to_string_lossy().to_string()
+        }
     }
 
     #[derive(Debug, Deserialize)]
     struct Logs {
         print_in_console: bool,
         print_instrumentation: bool,
+        #[serde(default)]
+        level: Level,
+        #[serde(default)]
+        targets: TargetLevels, // Level of single modules, overriding the general one
+    }
+
+    // Multi-process run, floors are split between the processes
+    #[derive(Debug, Deserialize)]
+    pub struct Mpi {
+        pub processes: usize,
+        pub uneven_partitioning: bool, // Floors balanced by load instead of by count
+    }
+
+    // Work is repartitioned when the load measured since the last check is too uneven
+    #[derive(Debug, Deserialize)]
+    pub struct MpiAutoAdjustment {
+        pub auto_mode: bool,
+        pub rebalancing_freq: u32,
+        pub maximum_perc_of_unbalance: f64, // Heaviest partition over the mean load
+    }
+
+    // Steps simulated, the match clock turns them into minutes
+    #[derive(Debug, Deserialize)]
+    pub struct NumSteps {
+        pub value: u32,
+    }
+
+    // Seed of the random draws of agents while they walk
+    #[derive(Debug, Deserialize)]
+    pub struct Seed {
+        pub value: u64,
     }
 
+    #[allow(dead_code)]
     #[derive(Debug, Deserialize)]
     struct Size {
         height: usize,
         width: usize,
     }
 
+    #[allow(dead_code)]
     #[derive(Debug, Deserialize)]
     struct Simulation {
         num_agents: u64,
@@ -32,17 +101,35 @@ pub mod configuration {
     #[derive(Deserialize)]
     pub struct Parameters {
         // General engine configuration
-        output: Output,
+        pub output: Output,
+        pub num_steps: NumSteps,
+        pub seed: Seed,
         logs: Logs,
+        pub mpi: Mpi,
+        pub mpi_auto_adjustment: MpiAutoAdjustment,
+        #[allow(dead_code)]
         size: Size,
+        #[allow(dead_code)]
         input_data: Simulation,
 
         // Model-specific configuration
-        agent_data: model::AgentStats,
+        pub agent_data: model::AgentStats,
+        #[allow(dead_code)]
         coefficients: model::Coeffs,
         pub topology: model::Topology,
         pub venue_tags: model::Venue,
         match_timings: model::Match,
+        pub connections: model::Connections,
+        pub gates: model::Gates,
+        #[serde(deserialize_with = "deserialize_legend")]
+        pub codification: Legend,
+        pub services: model::Services,
+        pub timeline: model::Timeline,
+        pub conflicts: model::Conflicts,
+        #[serde(default)]
+        pub scenario: Option<model::Scenario>,
+        #[serde(default)]
+        pub incidents: Option<model::IncidentScript>,
     }
 
     impl Parameters {
@@ -62,33 +149,70 @@ pub mod configuration {
         }
 
         // Grid size for computation
+        #[allow(dead_code)]
         pub fn get_world_size(&self) -> (usize, usize) {
             (self.size.height, self.size.width)
         }
 
         // Consolidate results
+        #[allow(dead_code)]
         pub fn write_results(&self, data: String) {
-            let mut file =
-                File::create(&self.output.results_file).expect("[ERROR] Unable to create file");
+            let mut file = File::create(self.output.path(&self.output.results_file))
+                .expect("[ERROR] Unable to create file");
             file.write_all(data.as_bytes())
                 .expect("[Error] Unable to write data");
         }
 
         // Display logs info on terminal and write down to log historial
-        pub fn logs(&self, info: String) {
-            if self.logs.print_in_console {
-                println!("{info}");
-            }
+        pub fn init_logs(&self) {
+            logging::init(
+                &self.output.path(&self.output.logs_file),
+                self.logs.print_in_console,
+                self.logs.level,
+                &self.logs.targets,
+            );
+        }
 
-            let mut file =
-                File::create(&self.output.logs_file).expect("[ERROR] Unable to create file");
-            file.write_all(info.as_bytes())
-                .expect("[Error] Unable to write data");
+        // Timings of every step and floor are recorded
+        pub fn instrumentation(&self) -> bool {
+            self.logs.print_instrumentation
+        }
+
+        // Real time of a simulation step
+        pub fn seconds_per_step(&self) -> f64 {
+            self.match_timings.seconds_per_step
+        }
+
+        // People known only by their arrival minute are spread along it
+        pub fn distribute_arrivals(&self) -> bool {
+            self.match_timings.distribute_agents_along_minutes
+        }
+
+        // Minute of the first step relative to kick-off
+        pub fn match_start(&self) -> f64 {
+            self.match_timings.match_start
         }
 
         // Total agents to be simulated
+        #[allow(dead_code)]
         pub fn total_agents(&self) -> u64 {
             self.input_data.num_agents
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::iotwins_model::legend::Class;
+
+        #[test]
+        fn repository_config_loads() {
+            let configuration = Parameters::load_configuration(String::from("IoTwins_config.toml"));
+
+            assert!([Class::Walkable, Class::UpEscalator, Class::DownEscalator]
+                .iter()
+                .all(|class| configuration.codification.contains_key(class)));
+            assert!(configuration.connections.stair_speed > 0.0);
+        }
+    }
 }
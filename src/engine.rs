@@ -20,24 +20,6 @@ pub mod matrix {
     }
 
     impl Matrix<u8> {
-        // Given a HashMap, converts blueprint to a standard form for path finding algorithm to work in
-        // Codification should be stablised for obstacles (value = 1)
-        pub fn ground_thruth(layer: &Matrix<u8>, codification: HashMap<u8, u8>) -> Matrix<u8> {
-            let gt: Vec<u8> = layer
-                .data
-                .iter()
-                .map(|value| match codification.get(value) {
-                    Some(value) => *value,
-                    _ => 0_u8,
-                })
-                .collect();
-
-            Matrix {
-                data: gt,
-                n_rows: layer.n_rows,
-            }
-        }
-
         pub fn contiguous(&self, position: usize) -> Vec<usize> {
             let limit = self.n_rows;
 
@@ -104,18 +86,39 @@ pub mod matrix {
             }
         }
 
-        // Load blueprint from resources
-        pub fn load_layer(path: &str) -> Matrix<u8> {
-            let image = ImageReader::open(path)
-                .unwrap()
-                .decode()
-                .unwrap()
-                .to_luma8();
+        // Load blueprint in colour, pixels are codified by exact RGB first and by luma otherwise
+        // Pixel values matching nothing are walkable (0) and returned with their count
+        pub fn load_codified(
+            path: &str,
+            rgb: &HashMap<[u8; 3], u8>,
+            luma: &HashMap<u8, u8>,
+        ) -> (Matrix<u8>, HashMap<[u8; 3], usize>) {
+            let image = ImageReader::open(path).unwrap().decode().unwrap();
+
+            let colour = image.to_rgb8();
+            let gray = image.to_luma8();
+
+            let mut unmatched: HashMap<[u8; 3], usize> = HashMap::new();
+
+            let data = colour
+                .pixels()
+                .zip(gray.pixels())
+                .map(|(c, g)| match rgb.get(&c.0).or_else(|| luma.get(&g.0[0])) {
+                    Some(code) => *code,
+                    None => {
+                        *unmatched.entry(c.0).or_insert(0) += 1;
+                        0_u8
+                    }
+                })
+                .collect();
 
-            Matrix {
-                data: image.as_raw().to_vec(),
-                n_rows: image.height() as usize,
-            }
+            (
+                Matrix {
+                    data,
+                    n_rows: image.height() as usize,
+                },
+                unmatched,
+            )
         }
     }
 
@@ -184,7 +187,10 @@ pub mod path_finding {
         collections::{BinaryHeap, HashMap},
     };
 
-    use crate::engine::{matrix::Matrix, path_finding};
+    use crate::{
+        engine::{matrix::Matrix, path_finding},
+        iotwins_model::structures::SERVICES,
+    };
 
     // A* algorithm form origin to destination, grid must be squared
     // origin and destination will be supposed to be in grid. Blueprint should be passed.
@@ -278,6 +284,40 @@ pub mod path_finding {
         std::cmp::max(row, col) as u64
     }
 
+    // Walkable areas reachable from each other with the moves of the pathfinder. Walls have no region
+    pub fn regions(gt: &Matrix<u8>) -> Vec<u32> {
+        let mut labels = vec![u32::MAX; gt.data.len()];
+        let mut region = 0;
+
+        for cell in 0..gt.data.len() {
+            if !walkable(gt.data[cell]) || labels[cell] != u32::MAX {
+                continue;
+            }
+
+            labels[cell] = region;
+            let mut frontier = vec![cell];
+
+            while let Some(current) = frontier.pop() {
+                movements(current, gt).into_iter().for_each(|next| {
+                    if labels[next] == u32::MAX {
+                        labels[next] = region;
+                        frontier.push(next);
+                    }
+                });
+            }
+
+            region += 1;
+        }
+
+        labels
+    }
+
+    // Walls and service footprints (counters, restrooms) cannot be walked through
+    #[inline(always)]
+    pub fn walkable(code: u8) -> bool {
+        code != 1 && !SERVICES.contains(&code)
+    }
+
     pub fn movements(position: usize, gt: &Matrix<u8>) -> Vec<usize> {
         let x = position / gt.n_rows;
         let y = position % gt.n_rows;
@@ -288,16 +328,16 @@ pub mod path_finding {
                 // First column
                 0 => Vec::from([position + 1, position + gt.n_rows])
                     .into_iter()
-                    .filter(|pos| *pos < gt.n_rows * gt.n_rows && gt.data[*pos] != 1)
+                    .filter(|pos| *pos < gt.n_rows * gt.n_rows && walkable(gt.data[*pos]))
                     .collect(),
                 // Last column
                 626 => Vec::from([position - 1, position + gt.n_rows])
                     .into_iter()
-                    .filter(|pos| *pos < gt.n_rows * gt.n_rows && gt.data[*pos] != 1)
+                    .filter(|pos| *pos < gt.n_rows * gt.n_rows && walkable(gt.data[*pos]))
                     .collect(),
                 _ => Vec::from([position - 1, position + 1, position + gt.n_rows])
                     .into_iter()
-                    .filter(|pos| *pos < gt.n_rows * gt.n_rows && gt.data[*pos] != 1)
+                    .filter(|pos| *pos < gt.n_rows * gt.n_rows && walkable(gt.data[*pos]))
                     .collect(),
             },
             // Last row
@@ -305,25 +345,25 @@ pub mod path_finding {
                 // First column
                 0 => Vec::from([position + 1, position - gt.n_rows])
                     .into_iter()
-                    .filter(|pos| *pos < gt.n_rows * gt.n_rows && gt.data[*pos] != 1)
+                    .filter(|pos| *pos < gt.n_rows * gt.n_rows && walkable(gt.data[*pos]))
                     .collect(),
                 // Last column
                 626 => Vec::from([position - 1, position - gt.n_rows]),
                 _ => Vec::from([position - 1, position + 1, position - gt.n_rows])
                     .into_iter()
-                    .filter(|pos| *pos < gt.n_rows * gt.n_rows && gt.data[*pos] != 1)
+                    .filter(|pos| *pos < gt.n_rows * gt.n_rows && walkable(gt.data[*pos]))
                     .collect(),
             },
             _ => match y {
                 // First column
                 0 => Vec::from([position + 1, position - gt.n_rows, position + gt.n_rows])
                     .into_iter()
-                    .filter(|pos| *pos < gt.n_rows * gt.n_rows && gt.data[*pos] != 1)
+                    .filter(|pos| *pos < gt.n_rows * gt.n_rows && walkable(gt.data[*pos]))
                     .collect(),
                 // Last column
                 626 => Vec::from([position - 1, position - gt.n_rows, position + gt.n_rows])
                     .into_iter()
-                    .filter(|pos| *pos < gt.n_rows * gt.n_rows && gt.data[*pos] != 1)
+                    .filter(|pos| *pos < gt.n_rows * gt.n_rows && walkable(gt.data[*pos]))
                     .collect(),
                 _ => Vec::from([
                     position - 1,
@@ -332,7 +372,7 @@ pub mod path_finding {
                     position - gt.n_rows,
                 ])
                 .into_iter()
-                .filter(|pos| *pos < gt.n_rows * gt.n_rows && gt.data[*pos] != 1)
+                .filter(|pos| *pos < gt.n_rows * gt.n_rows && walkable(gt.data[*pos]))
                 .collect(),
             },
         }
@@ -368,14 +408,17 @@ pub mod saving {
 
     use serde::{Deserialize, Serialize};
 
-    use crate::{engine::matrix::Position, iotwins_model::agent::Agent};
+    use crate::{
+        engine::matrix::Position,
+        iotwins_model::{agent::Agent, registry::LayerId},
+    };
 
     #[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
     pub struct PathSegment {
         init_step: u32,
         path: Vec<usize>,
         agent_id: usize,
-        layer: String,
+        layer: LayerId,
     }
 
     // Stuff needed for correctly ordering bottom-to-top the segments
@@ -395,12 +438,18 @@ pub mod saving {
     }
 
     impl PathSegment {
-        pub fn new(agent: &Agent, path: Vec<usize>, layer: &str, current_step: u32) -> PathSegment {
+        pub fn new(
+            agent: &Agent,
+            path: Vec<usize>,
+            layer: LayerId,
+            current_step: u32,
+        ) -> PathSegment {
+            // Agents leave a floor standing on the last cell of their path
             PathSegment {
-                init_step: current_step - agent.steps as u32,
+                init_step: current_step - path.len().saturating_sub(1) as u32,
                 path,
                 agent_id: agent.id,
-                layer: layer.to_string(),
+                layer,
             }
         }
 
@@ -413,11 +462,12 @@ pub mod saving {
         }
     }
 
-    // agent_id, x, y, step
+    // agent_id, x, y, step. Layers are given by name
     pub fn generate_path(
         id: usize,
         path: &mut BinaryHeap<PathSegment>,
         target_mouth: &u16,
+        layers: &[String],
     ) -> Vec<Vec<String>> {
         let mut global_path = Vec::new();
 
@@ -431,7 +481,7 @@ pub mod saving {
                         format!("{id}"),
                         format!("{}", point.x),
                         format!("{}", point.y),
-                        format!("{}", segment.layer),
+                        layers[segment.layer as usize].to_string(),
                         format!("{step}"),
                         format!("{target_mouth}"),
                     ]);
@@ -441,3 +491,422 @@ pub mod saving {
         global_path
     }
 }
+
+pub mod mpi {
+    use std::{
+        io::{BufReader, BufWriter, Write},
+        net::{TcpListener, TcpStream},
+        process::{Child, Command},
+    };
+
+    use bincode::{deserialize_from, serialize_into};
+    use serde::{de::DeserializeOwned, Serialize};
+
+    use crate::engine::logging::info;
+
+    // Workers are the same binary started with: --rank <rank> <size> <root address>
+    const RANK_FLAG: &str = "--rank";
+
+    struct Peer {
+        reader: BufReader<TcpStream>,
+        writer: BufWriter<TcpStream>,
+    }
+
+    /// Message passing between the processes of a run. Rank 0 spawns the workers on the
+    /// local machine and relays every message (star topology)
+    pub struct Communicator {
+        pub rank: usize,
+        pub size: usize,
+        peers: Vec<Peer>, // Root: workers by rank - 1. Workers: the root
+        workers: Vec<Child>,
+    }
+
+    impl Peer {
+        fn new(stream: TcpStream) -> Peer {
+            stream.set_nodelay(true).unwrap();
+
+            Peer {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: BufWriter::new(stream),
+            }
+        }
+
+        fn send<T: Serialize>(&mut self, data: &T) {
+            serialize_into(&mut self.writer, data).expect("[ERROR] Unable to send message");
+            self.writer.flush().expect("[ERROR] Unable to send message");
+        }
+
+        fn receive<T: DeserializeOwned>(&mut self) -> T {
+            deserialize_from(&mut self.reader).expect("[ERROR] Unable to receive message")
+        }
+    }
+
+    impl Communicator {
+        // Root process when started normally, worker when started by the root
+        pub fn init(processes: usize) -> Communicator {
+            let args: Vec<String> = std::env::args().collect();
+
+            match args.iter().position(|arg| arg == RANK_FLAG) {
+                Some(idx) => {
+                    let rank: usize = args[idx + 1].parse().expect("[ERROR] Wrong rank");
+                    let size: usize = args[idx + 2].parse().expect("[ERROR] Wrong size");
+
+                    Communicator::join(rank, size, &args[idx + 3])
+                }
+                None => Communicator::spawn(processes.max(1)),
+            }
+        }
+
+        // Worker connected to the root, it tells its rank first
+        fn join(rank: usize, size: usize, address: &str) -> Communicator {
+            let mut root =
+                Peer::new(TcpStream::connect(address).expect("[ERROR] Root not reachable"));
+            root.send(&rank);
+
+            Communicator {
+                rank,
+                size,
+                peers: vec![root],
+                workers: Vec::new(),
+            }
+        }
+
+        // Every process of a run inside this one, by rank, to be driven from threads
+        #[cfg(test)]
+        pub fn local(size: usize) -> Vec<Communicator> {
+            let listener =
+                TcpListener::bind("127.0.0.1:0").expect("[ERROR] Unable to open root socket");
+            let address = listener.local_addr().unwrap().to_string();
+
+            let workers: Vec<Communicator> = (1..size)
+                .map(|rank| Communicator::join(rank, size, &address))
+                .collect();
+
+            let root = Communicator {
+                rank: 0,
+                size,
+                peers: Communicator::accept(&listener, size),
+                workers: Vec::new(),
+            };
+
+            std::iter::once(root).chain(workers).collect()
+        }
+
+        fn spawn(size: usize) -> Communicator {
+            let mut communicator = Communicator {
+                rank: 0,
+                size,
+                peers: Vec::new(),
+                workers: Vec::new(),
+            };
+
+            if size == 1 {
+                return communicator;
+            }
+
+            let listener =
+                TcpListener::bind("127.0.0.1:0").expect("[ERROR] Unable to open root socket");
+            let address = listener.local_addr().unwrap().to_string();
+            let executable = std::env::current_exe().unwrap();
+
+            communicator.workers = (1..size)
+                .map(|rank| {
+                    Command::new(&executable)
+                        .args([RANK_FLAG, &rank.to_string(), &size.to_string(), &address])
+                        .spawn()
+                        .expect("[ERROR] Unable to start worker")
+                })
+                .collect();
+
+            communicator.peers = Communicator::accept(&listener, size);
+
+            info!(Engine, "{size} processes running");
+
+            communicator
+        }
+
+        // Workers connect in any order, they tell their rank first
+        fn accept(listener: &TcpListener, size: usize) -> Vec<Peer> {
+            let mut peers: Vec<(usize, Peer)> = (1..size)
+                .map(|_| {
+                    let (stream, _) = listener.accept().expect("[ERROR] Worker not connected");
+                    let mut peer = Peer::new(stream);
+                    let rank: usize = peer.receive();
+
+                    (rank, peer)
+                })
+                .collect();
+
+            peers.sort_by_key(|(rank, _)| *rank);
+            peers.into_iter().map(|(_, peer)| peer).collect()
+        }
+
+        pub fn is_root(&self) -> bool {
+            self.rank == 0
+        }
+
+        // Root data for every process
+        pub fn broadcast<T: Serialize + DeserializeOwned>(&mut self, data: Option<T>) -> T {
+            match self.is_root() {
+                true => {
+                    let data = data.expect("[ERROR] Broadcast without data");
+                    self.peers.iter_mut().for_each(|peer| peer.send(&data));
+                    data
+                }
+                false => self.peers[0].receive(),
+            }
+        }
+
+        // Data of every process by rank, only the root gets it
+        pub fn gather<T: Serialize + DeserializeOwned>(&mut self, data: T) -> Option<Vec<T>> {
+            match self.is_root() {
+                true => {
+                    let mut all = vec![data];
+                    all.extend(self.peers.iter_mut().map(|peer| peer.receive::<T>()));
+                    Some(all)
+                }
+                false => {
+                    self.peers[0].send(&data);
+                    None
+                }
+            }
+        }
+
+        // Every process gets its part of the root data
+        pub fn scatter<T: Serialize + DeserializeOwned>(&mut self, data: Option<Vec<T>>) -> T {
+            match self.is_root() {
+                true => {
+                    let mut parts = data.expect("[ERROR] Scatter without data").into_iter();
+                    let own = parts.next().expect("[ERROR] Scatter without root part");
+
+                    self.peers
+                        .iter_mut()
+                        .zip(parts)
+                        .for_each(|(peer, part)| peer.send(&part));
+
+                    own
+                }
+                false => self.peers[0].receive(),
+            }
+        }
+
+        // Items travel to the process given by rank_of, every process gets the ones sent to it
+        pub fn route<T: Serialize + DeserializeOwned>(
+            &mut self,
+            items: Vec<T>,
+            rank_of: impl Fn(&T) -> usize,
+        ) -> Vec<T> {
+            if self.size == 1 {
+                return items;
+            }
+
+            let all = self.gather(items).map(|all| {
+                let mut mailboxes: Vec<Vec<T>> = (0..self.size).map(|_| Vec::new()).collect();
+
+                all.into_iter().flatten().for_each(|item| {
+                    let rank = rank_of(&item);
+                    mailboxes[rank].push(item);
+                });
+
+                mailboxes
+            });
+
+            self.scatter(all)
+        }
+
+        // Root waits for its workers
+        pub fn finalize(self) {
+            self.workers.into_iter().for_each(|mut worker| {
+                worker.wait().expect("[ERROR] Worker failed");
+            });
+        }
+    }
+}
+
+pub mod logging {
+    use std::{
+        fmt::Arguments,
+        fs::{File, OpenOptions},
+        io::Write,
+        sync::{
+            atomic::{AtomicU32, AtomicUsize, Ordering},
+            Mutex, OnceLock,
+        },
+    };
+
+    use serde::Deserialize;
+
+    #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Level {
+        Debug,
+        #[default]
+        Info,
+        Warning,
+        Error,
+    }
+
+    impl Level {
+        fn tag(&self) -> &str {
+            match self {
+                Level::Debug => "[DEBG]",
+                Level::Info => "[INFO]",
+                Level::Warning => "[WARR]",
+                Level::Error => "[ERROR]",
+            }
+        }
+    }
+
+    // Part of the simulation a message comes from
+    #[derive(Clone, Copy, Debug)]
+    pub enum Target {
+        Engine,
+        World,
+        Floor,
+        Loaders,
+    }
+
+    impl Target {
+        fn name(&self) -> &str {
+            match self {
+                Target::Engine => "engine",
+                Target::World => "world",
+                Target::Floor => "floor",
+                Target::Loaders => "loaders",
+            }
+        }
+    }
+
+    // Levels of single targets, the general one otherwise
+    #[derive(Debug, Default, Deserialize)]
+    pub struct TargetLevels {
+        pub engine: Option<Level>,
+        pub world: Option<Level>,
+        pub floor: Option<Level>,
+        pub loaders: Option<Level>,
+    }
+
+    struct Logger {
+        console: bool,
+        levels: [Level; 4], // By target
+        file: Mutex<File>,
+    }
+
+    static LOGGER: OnceLock<Logger> = OnceLock::new();
+    static LAYERS: OnceLock<Vec<String>> = OnceLock::new();
+    static STEP: AtomicU32 = AtomicU32::new(u32::MAX); // No step before the simulation starts
+    static RANK: AtomicUsize = AtomicUsize::new(0);
+
+    /// Messages are appended to the logs file and printed if asked to. Until then, everything is printed
+    pub fn init(path: &str, console: bool, level: Level, targets: &TargetLevels) {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect("[ERROR] Unable to open logs file");
+
+        let levels = [
+            targets.engine,
+            targets.world,
+            targets.floor,
+            targets.loaders,
+        ]
+        .map(|target| target.unwrap_or(level));
+
+        let _ = LOGGER.set(Logger {
+            console,
+            levels,
+            file: Mutex::new(file),
+        });
+    }
+
+    // Layer names for the messages given with a layer id
+    pub fn set_layers(layers: &[String]) {
+        let _ = LAYERS.set(layers.to_vec());
+    }
+
+    pub fn set_step(step: u32) {
+        STEP.store(step, Ordering::Relaxed);
+    }
+
+    // Messages after the simulation have no step
+    pub fn clear_step() {
+        STEP.store(u32::MAX, Ordering::Relaxed);
+    }
+
+    pub fn set_rank(rank: usize) {
+        RANK.store(rank, Ordering::Relaxed);
+    }
+
+    pub fn log(level: Level, target: Target, layer: Option<u16>, message: Arguments) {
+        let logger = LOGGER.get();
+
+        if let Some(logger) = logger {
+            if level < logger.levels[target as usize] {
+                return;
+            }
+        }
+
+        let mut line = format!("{} [{}]", level.tag(), target.name());
+
+        match RANK.load(Ordering::Relaxed) {
+            0 => {}
+            rank => line.push_str(&format!(" [rank {rank}]")),
+        }
+
+        match STEP.load(Ordering::Relaxed) {
+            u32::MAX => {}
+            step => line.push_str(&format!(" [step {step}]")),
+        }
+
+        if let Some(layer) = layer {
+            match LAYERS.get().and_then(|layers| layers.get(layer as usize)) {
+                Some(name) => line.push_str(&format!(" [{name}]")),
+                None => line.push_str(&format!(" [layer {layer}]")),
+            }
+        }
+
+        line.push_str(&format!(" {message}\n"));
+
+        match logger {
+            Some(logger) => {
+                if logger.console {
+                    print!("{line}");
+                }
+
+                // Whole lines at once, processes of a run share the file
+                let _ = logger.file.lock().unwrap().write_all(line.as_bytes());
+            }
+            None => print!("{line}"),
+        }
+    }
+
+    macro_rules! debug {
+        ($target:ident @ $layer:expr, $($arg:tt)*) => {
+            $crate::engine::logging::log($crate::engine::logging::Level::Debug, $crate::engine::logging::Target::$target, Some($layer), format_args!($($arg)*))
+        };
+        ($target:ident, $($arg:tt)*) => {
+            $crate::engine::logging::log($crate::engine::logging::Level::Debug, $crate::engine::logging::Target::$target, None, format_args!($($arg)*))
+        };
+    }
+
+    macro_rules! info {
+        ($target:ident @ $layer:expr, $($arg:tt)*) => {
+            $crate::engine::logging::log($crate::engine::logging::Level::Info, $crate::engine::logging::Target::$target, Some($layer), format_args!($($arg)*))
+        };
+        ($target:ident, $($arg:tt)*) => {
+            $crate::engine::logging::log($crate::engine::logging::Level::Info, $crate::engine::logging::Target::$target, None, format_args!($($arg)*))
+        };
+    }
+
+    macro_rules! warning {
+        ($target:ident @ $layer:expr, $($arg:tt)*) => {
+            $crate::engine::logging::log($crate::engine::logging::Level::Warning, $crate::engine::logging::Target::$target, Some($layer), format_args!($($arg)*))
+        };
+        ($target:ident, $($arg:tt)*) => {
+            $crate::engine::logging::log($crate::engine::logging::Level::Warning, $crate::engine::logging::Target::$target, None, format_args!($($arg)*))
+        };
+    }
+
+    pub(crate) use {debug, info, warning as warn};
+}
@@ -1,26 +1,51 @@
-use std::hash::{Hash, Hasher};
+use std::{
+    collections::VecDeque,
+    hash::{Hash, Hasher},
+};
 
 use rand::{
     distributions::Uniform,
     prelude::{Distribution, SliceRandom},
+    Rng,
 };
 use serde::{Deserialize, Serialize};
 
 use crate::{
     engine::{matrix::Matrix, path_finding},
-    iotwins_model::structures::Structure,
+    iotwins_model::{
+        crowd::PathMut,
+        registry::{GateId, LayerId, StructureId},
+        routes::Leg,
+        services::Service,
+    },
 };
 
 #[derive(Clone, Default, Serialize, Deserialize, Debug)]
 pub struct Agent {
     pub id: usize,
     pub destination: u16, // Final mouth
-    pub destination_layer: String,
-    pub target: Structure, // Current target
+    pub destination_layer: LayerId,
+    pub seat: Option<u32>, // Block of the destination floor, claimed at the mouth
+    pub gate: GateId,      // Entrance to the venue
+    pub target: StructureId, // Current target
+    pub accessibility: bool, // Prefers ramps and elevators over stairs
+    pub plan: VecDeque<Leg>, // Remaining floors of the trip
+    pub agenda: VecDeque<Service>, // Services to visit before the mouth
+    pub visit: Option<(usize, StructureId)>, // Path step where the service point is reached
+    pub egress: bool,      // Leaving the venue after the match
+    pub leader: Option<usize>, // Group leader, None for leaders and lone agents
     interest: f64,
-    pub steps: usize,
-    pub next_step: usize,
+}
+
+/// Walking state of an agent on a floor. The crowd stores it apart from the agent, movement and
+/// conflicts only write these counters and the paths, trips and agendas are left untouched
+#[derive(Clone, Copy, Default, Serialize, Deserialize, Debug)]
+pub struct Walk {
+    pub steps: usize,     // Position on the path
+    pub next_step: usize, // Cell announced for the next step, 0 at the end of the path
     pub next_wandering: usize,
+    pub blocked: usize, // Consecutive steps lost in conflicts
+    interest: f64,
 }
 
 impl PartialEq for Agent {
@@ -41,26 +66,63 @@ impl Agent {
     // Does not assign inmediate destination, only final target
     pub fn new(
         id: usize,
-        target: Structure,
+        target: StructureId,
         destination: u16,
-        destination_layer: String,
+        destination_layer: LayerId,
         between: Uniform<f64>,
+        accessibility: bool,
+        rng: &mut impl Rng,
     ) -> Agent {
-        let mut rng = rand::thread_rng();
-
         Agent {
             id,
             target,
             destination,
             destination_layer,
-            interest: between.sample(&mut rng),
+            accessibility,
+            interest: between.sample(rng),
+            ..Default::default()
+        }
+    }
+
+    // Group of the agent, identified by its leader
+    pub fn group(&self) -> usize {
+        self.leader.unwrap_or(self.id)
+    }
+}
+
+impl Walk {
+    // Walk from the start of the path, with the interest the agent has left
+    pub fn start(agent: &Agent, path: &[usize]) -> Walk {
+        Walk {
+            next_step: *path.get(1).unwrap_or(&0),
+            interest: agent.interest,
             ..Default::default()
         }
     }
 
-    pub fn action(&mut self, interest: Uniform<f64>, path: &mut Vec<usize>, gt: &Matrix<u8>) {
+    // Interest is kept by the agent once it leaves the floor
+    pub fn end(&self, agent: &mut Agent) {
+        agent.interest = self.interest;
+    }
+
+    // Stays in place this step
+    pub fn wait(&mut self, path: &mut PathMut) {
+        path.insert(self.steps, path[self.steps]);
+        self.steps += 1;
+
+        self.next_step = *path.get(self.steps + 1).unwrap_or(&0);
+    }
+
+    // Draws come from rng, seeded by the floor so runs are repeatable
+    pub fn action(
+        &mut self,
+        interest: Uniform<f64>,
+        path: &mut PathMut,
+        gt: &Matrix<u8>,
+        rng: &mut impl Rng,
+    ) {
         // Interest decrement by 3%
-        if self.steps % 100 == 0 {
+        if self.steps.is_multiple_of(100) {
             self.interest *= 0.97
         }
 
@@ -68,12 +130,11 @@ impl Agent {
             // Avoid multiple wanderings together as wander route is already calculated
             self.next_wandering -= 1;
         } else {
-            let choice = interest.sample(&mut rand::thread_rng());
+            let choice = interest.sample(rng);
 
             // Choice > interest -> regular walk everything else here:
             if choice < self.interest * 0.20 {
                 // Stop
-                self.steps += 1;
                 path.insert(self.steps, path[self.steps]);
             } else if choice < self.interest {
                 // Wandering
@@ -81,9 +142,9 @@ impl Agent {
 
                 let mut wander_path = vec![path[self.steps]; 15];
 
-                (1_usize..15).into_iter().for_each(|i| {
+                (1_usize..15).for_each(|i| {
                     wander_path[i] = *path_finding::movements(wander_path[i - 1], gt)
-                        .choose(&mut rand::thread_rng())
+                        .choose(rng)
                         .unwrap();
                 });
 
@@ -94,14 +155,14 @@ impl Agent {
                         wander_path.extend(join_path.drain(1..));
 
                         // Path is updated with wandering
-                        path.splice(self.steps..join_position, wander_path);
+                        path.splice(self.steps..join_position, &wander_path);
                     }
                     None => {
                         // If there is no aviable path, agent will backtrack to their previous position
                         let mut join_path: Vec<usize> = wander_path.iter().copied().rev().collect();
                         wander_path.extend(join_path.drain(1..));
 
-                        path.splice(self.steps..self.steps, wander_path);
+                        path.splice(self.steps..self.steps, &wander_path);
                     }
                 }
             }
@@ -111,10 +172,6 @@ impl Agent {
         self.steps += 1;
 
         // Agent announces its next movement
-        if path.len() > self.steps {
-            self.next_step = 0;
-        } else {
-            self.next_step = path[self.steps + 1];
-        }
+        self.next_step = *path.get(self.steps + 1).unwrap_or(&0);
     }
 }
@@ -1,107 +1,695 @@
-use rand::distributions::Uniform;
+use rand::{
+    distributions::{Distribution, Uniform, WeightedIndex},
+    prelude::SliceRandom,
+    rngs::StdRng,
+    Rng, SeedableRng,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, BTreeMap, HashMap, VecDeque};
 
-use crate::iotwins_model::{agent::Agent, structures::Structure};
+use crate::{
+    config::configuration::Parameters,
+    engine::logging::{info, warn},
+    iotwins_model::{
+        agent::Agent,
+        config::{ArrivalDistribution, Scenario, Services},
+        registry::{LayerId, StructureId},
+        services::Service,
+        structures::LayerMap,
+        timeline::MatchClock,
+    },
+};
 
+// Aggregated counts per minute or individual tickets to the second
 #[derive(Deserialize)]
 struct RawArrival {
     gate: String,
     mouth: u16,
+    #[serde(default)]
+    minutes_to_game: Option<f64>,
+    #[serde(default)]
+    seconds_to_game: Option<f64>,
+    #[serde(default)]
+    agents: Option<u32>, // One person if missing
+}
+
+#[derive(Deserialize)]
+struct RawProfile {
     minutes_to_game: i32,
-    agents: u8,
+    weight: f64,
+}
+
+#[derive(Deserialize)]
+struct RawSeats {
+    mouth: u16,
+    seats: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Arrival {
     pub gate: String, // Origin
     pub mouth: u16,   // Destination
-    pub agents: u8,
+    pub agents: u32,
+    pub first: usize, // Id of the first agent, the batch takes the following ones
+}
+
+// People reaching a gate at a match minute
+struct Entrance {
+    minute: f64,
+    whole_minute: bool, // Only the minute is known, people may be spread along it
+    gate: String,
+    mouth: u16,
+    agents: u32,
 }
 
 impl Arrival {
     pub fn generate_agents(
         &self,
-        target: Structure,
-        id_counting: usize,
-        interest: Uniform<f64>,
+        target: StructureId,
+        accessibility_ratio: f64,
+        destination_layer: LayerId,
+        services: &Services,
+        rng: &mut impl Rng,
     ) -> Vec<Agent> {
+        let interest = Uniform::from(0_f64..1_f64);
+
         (0..self.agents as usize)
             .map(|counter| {
-                Agent::new(
-                    id_counting + counter,
-                    target.to_owned(),
+                let mut agent = Agent::new(
+                    self.first + counter,
+                    target,
                     self.mouth,
-                    self.mouth_layer(),
+                    destination_layer,
                     interest,
-                )
+                    interest.sample(rng) < accessibility_ratio,
+                    rng,
+                );
+
+                agent.agenda = services.agenda(1.0, rng);
+                agent
             })
             .collect()
     }
 
-    pub fn gate_layer(&self) -> String {
-        let id = self.gate.split('-').collect::<Vec<&str>>()[1].to_string();
+    pub fn gate_layer(&self, layers: &LayerMap) -> String {
+        layers.gate_layer(&self.gate).cloned().unwrap_or_default()
+    }
 
-        if vec![62, 63, 64, 73].contains(&id.parse::<i32>().unwrap()) {
-            String::from("S1")
-        } else {
-            String::from("PB")
-        }
+    pub fn mouth_layer(&self, layers: &LayerMap) -> String {
+        layers.mouth_layer(self.mouth).cloned().unwrap_or_default()
     }
+}
+
+// Agents of a batch follow the leader of the previous one with the given probability
+// Followers share accessibility and agenda with their leader so the group is planned together
+pub fn form_groups(agents: &mut [Agent], follow: f64, rng: &mut impl Rng) {
+    let between = Uniform::from(0_f64..1_f64);
 
-    pub fn mouth_layer(&self) -> String {
-        if (100..154).contains(&self.mouth) {
-            String::from("S1")
-        } else if (200..212).contains(&self.mouth) {
-            String::from("PB")
-        } else if (212..223).contains(&self.mouth) || (240..251).contains(&self.mouth) {
-            String::from("P0-5")
-        } else if (223..240).contains(&self.mouth)
-            || (300..326).contains(&self.mouth)
-            || (345..359).contains(&self.mouth)
-        {
-            String::from("P1")
-        } else if (313..358).contains(&self.mouth) {
-            String::from("P2")
-        } else if (414..457).contains(&self.mouth) {
-            String::from("P3")
-        } else if (521..550).contains(&self.mouth) {
-            String::from("P4")
-        } else {
-            String::from("")
+    let mut leader: Option<(usize, bool, VecDeque<Service>)> = None;
+
+    agents.iter_mut().for_each(|agent| match &leader {
+        Some((id, accessibility, agenda)) if between.sample(rng) < follow => {
+            agent.leader = Some(*id);
+            agent.accessibility = *accessibility;
+            agent.agenda = agenda.to_owned();
         }
-    }
+        _ => leader = Some((agent.id, agent.accessibility, agent.agenda.to_owned())),
+    });
 }
 
-// Returns a hashmap with the list of agents to enter for each given time
-pub fn load_arrivals() -> HashMap<i32, Vec<Arrival>> {
-    let mut arrivals: HashMap<i32, Vec<Arrival>> = HashMap::new();
+// Returns a hashmap with the list of agents to enter at each step
+pub fn load_arrivals(
+    path: &str,
+    clock: &MatchClock,
+    spread: bool,
+    follow: f64,
+    rng: &mut impl Rng,
+) -> HashMap<u32, Vec<Arrival>> {
+    let mut reader = csv::Reader::from_path(path).expect("[ERROR] Arrivals file not found");
 
-    let mut reader = csv::Reader::from_path("resources/tagging/BOCA_arrivals.csv")
-        .expect("[ERROR] Arrivals file not found");
+    let entrances = reader.deserialize().map(|result| {
+        let record: RawArrival = result.expect("[ERROR] Incorrect arrival format");
 
-    for result in reader.deserialize() {
-        let record: RawArrival = result.expect("[ERROR] Incorrect gate format");
+        let (minute, whole_minute) = match (record.seconds_to_game, record.minutes_to_game) {
+            (Some(seconds), _) => (seconds / 60.0, false),
+            (None, Some(minute)) => (minute, minute.fract() == 0.0),
+            (None, None) => panic!("[ERROR] Arrival without minutes_to_game nor seconds_to_game"),
+        };
 
-        match arrivals.entry(record.minutes_to_game) {
-            Entry::Occupied(mut arrivals) => {
-                arrivals.get_mut().push(Arrival {
-                    gate: record.gate,
-                    mouth: record.mouth,
-                    agents: record.agents,
-                });
+        Entrance {
+            minute,
+            whole_minute,
+            gate: record.gate,
+            mouth: record.mouth,
+            agents: record.agents.unwrap_or(1),
+        }
+    });
+
+    let arrivals = schedule(entrances, clock, spread, follow, rng);
+
+    info!(Loaders, "Arrivals loaded");
+
+    arrivals
+}
+
+// People of the same step, gate and mouth arrive together. With spread, people known only by
+// their minute get a random second of it, shared with the ones after them that join them with
+// the follow probability, so groups can still be formed in their batch. Batches of a step are
+// sorted by gate and mouth and take agent ids in that order, ids are the same whatever the processes
+fn schedule(
+    entrances: impl Iterator<Item = Entrance>,
+    clock: &MatchClock,
+    spread: bool,
+    follow: f64,
+    rng: &mut impl Rng,
+) -> HashMap<u32, Vec<Arrival>> {
+    let within = Uniform::from(0_f64..1_f64);
+
+    let mut batches: BTreeMap<(u32, String, u16), u32> = BTreeMap::new();
+    let mut early = 0;
+
+    entrances.for_each(|entrance| {
+        if entrance.minute < clock.minute(0) {
+            early += entrance.agents;
+        }
+
+        match spread && entrance.whole_minute {
+            true => {
+                let mut left = entrance.agents;
+
+                while left > 0 {
+                    let mut together = 1;
+                    while together < left && within.sample(rng) < follow {
+                        together += 1;
+                    }
+
+                    let step = clock.step(entrance.minute + within.sample(rng));
+                    *batches
+                        .entry((step, entrance.gate.to_string(), entrance.mouth))
+                        .or_default() += together;
+
+                    left -= together;
+                }
             }
-            Entry::Vacant(arrivals) => {
-                arrivals.insert(vec![Arrival {
-                    gate: record.gate,
-                    mouth: record.mouth,
-                    agents: record.agents,
-                }]);
+            false => {
+                let step = clock.step(entrance.minute);
+                *batches
+                    .entry((step, entrance.gate, entrance.mouth))
+                    .or_default() += entrance.agents;
             }
         }
+    });
+
+    if early > 0 {
+        warn!(
+            Loaders,
+            "{early} people arrive before minute {}, they enter at the first step",
+            clock.minute(0)
+        );
     }
 
-    println!("[INFO] Arrivals loaded");
+    let mut arrivals: HashMap<u32, Vec<Arrival>> = HashMap::new();
+    let mut first = 0;
+
+    batches
+        .into_iter()
+        .for_each(|((step, gate, mouth), agents)| {
+            let arrival = Arrival {
+                gate,
+                mouth,
+                agents,
+                first,
+            };
+            first += agents as usize;
+
+            match arrivals.entry(step) {
+                Entry::Occupied(mut arrivals) => arrivals.get_mut().push(arrival),
+                Entry::Vacant(arrivals) => {
+                    arrivals.insert(vec![arrival]);
+                }
+            }
+        });
 
     arrivals
 }
+
+// Arrivals of the scenario if there is one, the historical CSV otherwise
+pub fn select_arrivals(
+    configuration: &Parameters,
+    layer_map: &LayerMap,
+) -> HashMap<u32, Vec<Arrival>> {
+    let clock = MatchClock::new(
+        configuration.match_start(),
+        configuration.seconds_per_step(),
+        &configuration.timeline,
+    );
+    let spread = configuration.distribute_arrivals();
+    let follow = configuration.agent_data.prov_follow as f64 / 100.0;
+
+    // Every process draws the same arrivals
+    let mut rng = StdRng::seed_from_u64(configuration.seed.value);
+
+    match &configuration.scenario {
+        Some(scenario) if scenario.enabled => {
+            generate_arrivals(scenario, layer_map, &clock, spread, &mut rng)
+        }
+        _ => load_arrivals(
+            &configuration.venue_tags.files().arrivals_info_csv,
+            &clock,
+            spread,
+            follow,
+            &mut rng,
+        ),
+    }
+}
+
+/// Arrivals of a synthetic scenario. The attendance is split between mouths by their seats,
+/// every person gets an arrival minute from the configured distribution and a gate by weight.
+/// People are drawn in random order, so the ones left out when arrivals end early are spread
+/// over every mouth
+pub fn generate_arrivals(
+    scenario: &Scenario,
+    layer_map: &LayerMap,
+    clock: &MatchClock,
+    spread: bool,
+    rng: &mut impl Rng,
+) -> HashMap<u32, Vec<Arrival>> {
+    let mut mouths = seat_allocation(scenario, layer_map);
+    mouths.shuffle(rng);
+
+    let minutes = arrival_minutes(scenario, mouths.len(), rng);
+
+    let mut gates: Vec<&String> = layer_map.gates.keys().collect();
+    gates.sort_unstable();
+
+    scenario
+        .gate_weights
+        .keys()
+        .filter(|gate| !layer_map.gates.contains_key(*gate))
+        .for_each(|gate| warn!(Loaders, "Weight given to unknown gate {gate}"));
+
+    let weights = gates
+        .iter()
+        .map(|gate| *scenario.gate_weights.get(*gate).unwrap_or(&1.0));
+    let choice = WeightedIndex::new(weights).expect("[ERROR] Wrong gate weights");
+
+    // Empirical profiles only give the minute
+    let whole_minute = matches!(scenario.distribution, ArrivalDistribution::Empirical);
+    let people = minutes.len();
+
+    let entrances: Vec<Entrance> = mouths
+        .into_iter()
+        .zip(minutes)
+        .map(|(mouth, minute)| Entrance {
+            minute,
+            whole_minute,
+            gate: gates[choice.sample(rng)].to_string(),
+            mouth,
+            agents: 1,
+        })
+        .collect();
+
+    // Generated people arrive one by one
+    let arrivals = schedule(entrances.into_iter(), clock, spread, 0.0, rng);
+
+    info!(
+        Loaders,
+        "Arrivals generated: {people} people, {:?} between minutes {} and {}",
+        scenario.distribution,
+        scenario.first_minute,
+        scenario.last_minute
+    );
+
+    arrivals
+}
+
+// Destination mouth of every person. Mouths get their share of the attendance by seats,
+// largest remainders first so the shares add up to the attendance
+fn seat_allocation(scenario: &Scenario, layer_map: &LayerMap) -> Vec<u16> {
+    let mut seats: Vec<(u16, usize)> = match &scenario.seats_csv {
+        Some(path) => {
+            let mut reader = csv::Reader::from_path(path).expect("[ERROR] Seats file not found");
+
+            reader
+                .deserialize()
+                .map(|result| {
+                    let record: RawSeats = result.expect("[ERROR] Incorrect seats format");
+
+                    if layer_map.mouth_layer(record.mouth).is_none() {
+                        warn!(Loaders, "Seats of unknown mouth {}", record.mouth);
+                    }
+
+                    (record.mouth, record.seats)
+                })
+                .collect()
+        }
+        None => layer_map.mouths.keys().map(|mouth| (*mouth, 1)).collect(),
+    };
+
+    seats.sort_unstable();
+
+    let capacity: usize = seats.iter().map(|(_, seats)| seats).sum();
+
+    if capacity == 0 {
+        panic!("[ERROR] No seats to allocate the attendance");
+    }
+
+    let attendance = match scenario.seats_csv.is_some() && scenario.attendance > capacity {
+        true => {
+            warn!(
+                Loaders,
+                "Attendance of {} over {capacity} seats, venue is sold out", scenario.attendance
+            );
+            capacity
+        }
+        false => scenario.attendance,
+    };
+
+    let quotas: Vec<f64> = seats
+        .iter()
+        .map(|(_, seats)| (attendance * seats) as f64 / capacity as f64)
+        .collect();
+    let mut shares: Vec<usize> = quotas.iter().map(|quota| quota.floor() as usize).collect();
+
+    let mut order: Vec<usize> = (0..seats.len()).collect();
+    order.sort_by(|a, b| quotas[*b].fract().total_cmp(&quotas[*a].fract()));
+
+    let missing = attendance - shares.iter().sum::<usize>();
+    order
+        .into_iter()
+        .take(missing)
+        .for_each(|idx| shares[idx] += 1);
+
+    seats
+        .iter()
+        .zip(shares)
+        .flat_map(|((mouth, _), share)| std::iter::repeat_n(*mouth, share))
+        .collect()
+}
+
+// Minute of arrival of every person, inside the window
+fn arrival_minutes(scenario: &Scenario, people: usize, rng: &mut impl Rng) -> Vec<f64> {
+    let u = Uniform::from(f64::EPSILON..1_f64);
+
+    let (first, last) = (scenario.first_minute, scenario.last_minute);
+    let window = (last - first + 1) as f64;
+
+    match scenario.distribution {
+        ArrivalDistribution::Empirical => {
+            let path = scenario
+                .profile_csv
+                .as_ref()
+                .expect("[ERROR] Empirical arrivals need a profile_csv");
+            let mut reader = csv::Reader::from_path(path).expect("[ERROR] Profile file not found");
+
+            let profile: Vec<(i32, f64)> = reader
+                .deserialize()
+                .map(|result| {
+                    let record: RawProfile = result.expect("[ERROR] Incorrect profile format");
+                    (record.minutes_to_game, record.weight)
+                })
+                .filter(|(minute, _)| (first..=last).contains(minute))
+                .collect();
+
+            let choice = WeightedIndex::new(profile.iter().map(|(_, weight)| *weight))
+                .expect("[ERROR] Wrong profile weights");
+
+            (0..people)
+                .map(|_| profile[choice.sample(rng)].0 as f64)
+                .collect()
+        }
+        // Box-Muller transform, minutes out of the window are drawn again
+        ArrivalDistribution::Normal => {
+            let (start, end) = (first as f64, first as f64 + window);
+
+            (0..people)
+                .map(|_| {
+                    let mut draw = || {
+                        let z = (-2.0 * u.sample(rng).ln()).sqrt()
+                            * (2.0 * std::f64::consts::PI * u.sample(rng)).cos();
+                        scenario.mean + scenario.deviation * z
+                    };
+
+                    (0..100)
+                        .map(|_| draw())
+                        .find(|minute| (start..end).contains(minute))
+                        .unwrap_or_else(|| draw().clamp(start, end - 1e-9))
+                })
+                .collect()
+        }
+        // Poisson process given the attendance: its arrival times are uniform over the window, in order
+        ArrivalDistribution::Poisson => {
+            let within = Uniform::from(0_f64..window);
+
+            let mut minutes: Vec<f64> = (0..people)
+                .map(|_| first as f64 + within.sample(rng))
+                .collect();
+            minutes.sort_by(f64::total_cmp);
+
+            minutes
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iotwins_model::config::Timeline;
+
+    fn rng() -> StdRng {
+        StdRng::seed_from_u64(0)
+    }
+
+    fn scenario(attendance: usize, seats_csv: Option<String>) -> Scenario {
+        Scenario {
+            enabled: true,
+            attendance,
+            distribution: ArrivalDistribution::Normal,
+            first_minute: -60,
+            last_minute: 0,
+            mean: -30.0,
+            deviation: 10.0,
+            profile_csv: None,
+            gate_weights: HashMap::new(),
+            seats_csv,
+        }
+    }
+
+    fn layer_map(mouths: &[u16]) -> LayerMap {
+        LayerMap {
+            mouths: mouths
+                .iter()
+                .map(|mouth| (*mouth, String::from("PB")))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn clock(start: f64) -> MatchClock {
+        let timeline = Timeline {
+            first_half: 0.0,
+            half_time: 45.0,
+            second_half: 60.0,
+            post_match: 105.0,
+            half_time_visits: 1.0,
+        };
+
+        MatchClock::new(start, 0.5, &timeline)
+    }
+
+    fn entrance(minute: f64, whole_minute: bool, mouth: u16, agents: u32) -> Entrance {
+        Entrance {
+            minute,
+            whole_minute,
+            gate: String::from("G1"),
+            mouth,
+            agents,
+        }
+    }
+
+    fn count(mouths: &[u16], mouth: u16) -> usize {
+        mouths.iter().filter(|other| **other == mouth).count()
+    }
+
+    #[test]
+    fn attendance_is_split_between_mouths() {
+        let mouths = seat_allocation(&scenario(10, None), &layer_map(&[1, 2, 3]));
+
+        assert_eq!(mouths.len(), 10);
+        assert!([1, 2, 3]
+            .iter()
+            .all(|mouth| (3..=4).contains(&count(&mouths, *mouth))));
+    }
+
+    #[test]
+    fn seats_cap_the_attendance() {
+        let path = std::env::temp_dir().join(format!("pandorast_seats_{}.csv", std::process::id()));
+        std::fs::write(&path, "mouth,seats\n1,2\n2,6\n").unwrap();
+
+        let mouths = seat_allocation(
+            &scenario(100, Some(path.to_string_lossy().to_string())),
+            &layer_map(&[1, 2]),
+        );
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!((count(&mouths, 1), count(&mouths, 2)), (2, 6));
+    }
+
+    #[test]
+    fn batches_join_people_of_the_same_step() {
+        let arrivals = schedule(
+            [
+                entrance(-9.5, false, 1, 3),
+                entrance(-9.5, false, 1, 2),
+                entrance(-9.5, false, 2, 1),
+            ]
+            .into_iter(),
+            &clock(-10.0),
+            false,
+            0.0,
+            &mut rng(),
+        );
+
+        assert_eq!(arrivals.len(), 1);
+
+        let mut batches: Vec<(u16, u32)> = arrivals[&60]
+            .iter()
+            .map(|arrival| (arrival.mouth, arrival.agents))
+            .collect();
+        batches.sort();
+
+        assert_eq!(batches, [(1, 5), (2, 1)]);
+    }
+
+    #[test]
+    fn whole_minutes_are_spread_along_the_minute() {
+        let arrivals = schedule(
+            [entrance(-9.0, true, 1, 50)].into_iter(),
+            &clock(-10.0),
+            true,
+            0.0,
+            &mut rng(),
+        );
+
+        assert!(arrivals.keys().all(|step| (120..240).contains(step)));
+        assert_eq!(
+            arrivals
+                .values()
+                .flatten()
+                .map(|arrival| arrival.agents)
+                .sum::<u32>(),
+            50
+        );
+    }
+
+    #[test]
+    fn people_arriving_together_share_their_step() {
+        let arrivals = schedule(
+            [entrance(-9.0, true, 1, 50)].into_iter(),
+            &clock(-10.0),
+            true,
+            1.0,
+            &mut rng(),
+        );
+
+        assert_eq!(arrivals.len(), 1);
+        assert_eq!(arrivals.values().next().unwrap()[0].agents, 50);
+    }
+
+    fn gated(mouths: &[u16]) -> LayerMap {
+        LayerMap {
+            gates: HashMap::from([(String::from("G1"), String::from("PB"))]),
+            ..layer_map(mouths)
+        }
+    }
+
+    fn people_by_mouth(arrivals: &HashMap<u32, Vec<Arrival>>) -> BTreeMap<u16, u32> {
+        let mut people = BTreeMap::new();
+
+        arrivals
+            .values()
+            .flatten()
+            .for_each(|arrival| *people.entry(arrival.mouth).or_default() += arrival.agents);
+
+        people
+    }
+
+    #[test]
+    fn same_seed_same_arrivals() {
+        let generate = || {
+            generate_arrivals(
+                &scenario(200, None),
+                &gated(&[1, 2, 3]),
+                &clock(-60.0),
+                true,
+                &mut StdRng::seed_from_u64(7),
+            )
+        };
+
+        let (first, second) = (generate(), generate());
+
+        assert_eq!(first.len(), second.len());
+        assert!(first.iter().all(|(step, arrivals)| {
+            let same = |a: &Arrival, b: &Arrival| {
+                (&a.gate, a.mouth, a.agents) == (&b.gate, b.mouth, b.agents)
+            };
+
+            second[step].len() == arrivals.len()
+                && arrivals.iter().zip(&second[step]).all(|(a, b)| same(a, b))
+        }));
+    }
+
+    #[test]
+    fn poisson_arrivals_bring_the_whole_attendance() {
+        let poisson = Scenario {
+            distribution: ArrivalDistribution::Poisson,
+            ..scenario(1000, None)
+        };
+
+        (0..5).for_each(|seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let arrivals =
+                generate_arrivals(&poisson, &gated(&[1, 2]), &clock(-60.0), false, &mut rng);
+
+            let people = people_by_mouth(&arrivals);
+            assert_eq!(people.values().sum::<u32>(), 1000);
+
+            // Arrival times come in order, without shuffling the first half would all go to mouth 1
+            let mut steps: Vec<u32> = arrivals.keys().copied().collect();
+            steps.sort_unstable();
+            let half = steps[steps.len() / 2];
+
+            let early: HashMap<u32, Vec<Arrival>> = arrivals
+                .into_iter()
+                .filter(|(step, _)| *step < half)
+                .collect();
+
+            assert!(people_by_mouth(&early).values().all(|people| *people > 100));
+            assert_eq!(people_by_mouth(&early).len(), 2);
+        });
+    }
+
+    #[test]
+    fn batches_take_consecutive_ids() {
+        let arrivals = schedule(
+            [
+                entrance(-9.0, false, 2, 1),
+                entrance(-9.5, false, 1, 3),
+                entrance(-9.5, false, 2, 2),
+            ]
+            .into_iter(),
+            &clock(-10.0),
+            false,
+            0.0,
+            &mut rng(),
+        );
+
+        let ids: Vec<(usize, u32)> = [60, 120]
+            .iter()
+            .flat_map(|step| &arrivals[step])
+            .map(|arrival| (arrival.first, arrival.agents))
+            .collect();
+
+        assert_eq!(ids, [(0, 3), (3, 2), (5, 1)]);
+    }
+}
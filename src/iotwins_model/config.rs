@@ -0,0 +1,276 @@
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+
+// Speeds divide lengths, zero, negative or NaN values are refused when loading
+fn positive<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let value = f64::deserialize(deserializer)?;
+
+    match value > 0.0 {
+        true => Ok(value),
+        false => Err(de::Error::custom(format!(
+            "{value} is not a positive speed"
+        ))),
+    }
+}
+
+#[allow(dead_code)] // Only the group keys are read by the model
+#[derive(Debug, Deserialize)]
+pub struct AgentStats {
+    pub min_vision: u32,
+    pub max_vision: u32,
+    pub min_velocity: u32,
+    pub max_velocity: u32,
+    pub min_age: u32,
+    pub max_age: u32,
+    pub porv_tourist: f64,
+    pub min_wall_distance: u32,
+    pub max_wall_distance: u32,
+    pub min_agent_distance: u32,
+    pub max_agent_distance: u32,
+    pub max_distance_b_agents: u32, // Cells between a follower and its group leader
+    pub prov_follow: u32, // Percentage of agents following the previous one of their batch
+    pub prov_museum: u32,
+}
+
+#[allow(dead_code)] // Movement coefficients, not read by the model yet
+#[derive(Debug, Deserialize)]
+pub struct Coeffs {
+    pub calpha: f64,
+    pub cbeta: f64,
+    pub cdelta: f64,
+    pub csigma: f64,
+    pub ualpha: f64,
+    pub ubeta: f64,
+    pub udelta: f64,
+    pub usigma: f64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Topology {
+    pub layout_pb: String,
+    pub layout_p05: String,
+    pub layout_p1: String,
+    pub layout_p15: String,
+    pub layout_p2: String,
+    pub layout_p3: String,
+    pub layout_p35: String,
+    pub layout_p4: String,
+    pub layout_p5: String,
+    pub layout_s1: String,
+}
+
+impl Topology {
+    // Layer name -> blueprint path. Venue bundles keep their blueprints in maps/<layer>.png
+    pub fn layers(&self, bundle: &Option<String>) -> Vec<(&str, String)> {
+        let layers = Vec::from([
+            ("PB", self.layout_pb.to_string()),
+            ("P0-5", self.layout_p05.to_string()),
+            ("P1", self.layout_p1.to_string()),
+            ("P1-5", self.layout_p15.to_string()),
+            ("P2", self.layout_p2.to_string()),
+            ("P3", self.layout_p3.to_string()),
+            ("P3-5", self.layout_p35.to_string()),
+            ("P4", self.layout_p4.to_string()),
+            ("P5", self.layout_p5.to_string()),
+            ("S1", self.layout_s1.to_string()),
+        ]);
+
+        match bundle {
+            Some(dir) => layers
+                .into_iter()
+                .map(|(layer, _)| (layer, format!("{dir}/maps/{layer}.png")))
+                .collect(),
+            None => layers,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Venue {
+    pub bundle: Option<String>, // Venue directory, overrides every path below
+    pub gates_info: String,
+    pub mouths_info: String,
+    pub arrivals_info_csv: String,
+    pub connections_info: Option<String>, // Inter-floor topology, nearest structures if missing
+    pub seats_info: Option<String>, // Seat blocks behind the mouths, agents stop at the mouth if missing
+}
+
+impl Venue {
+    /// Input files of the venue. A bundle directory is laid out as
+    ///
+    /// <bundle>/maps/<layer>.png
+    /// <bundle>/tagging/gates.csv
+    /// <bundle>/tagging/mouths.csv
+    /// <bundle>/tagging/arrivals.csv
+    /// <bundle>/tagging/connections.csv (optional)
+    /// <bundle>/tagging/seats.csv (optional)
+    pub fn files(&self) -> Venue {
+        match &self.bundle {
+            Some(dir) => {
+                let connections = format!("{dir}/tagging/connections.csv");
+                let seats = format!("{dir}/tagging/seats.csv");
+
+                Venue {
+                    bundle: self.bundle.to_owned(),
+                    gates_info: format!("{dir}/tagging/gates.csv"),
+                    mouths_info: format!("{dir}/tagging/mouths.csv"),
+                    arrivals_info_csv: format!("{dir}/tagging/arrivals.csv"),
+                    connections_info: std::path::Path::new(&connections)
+                        .exists()
+                        .then_some(connections),
+                    seats_info: std::path::Path::new(&seats).exists().then_some(seats),
+                }
+            }
+            None => self.to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArrivalDistribution {
+    Empirical, // Weights per minute from profile_csv
+    Normal,
+    Poisson, // Constant rate over the window
+}
+
+// Synthetic arrivals, generated instead of reading arrivals_info_csv. Minutes relative to kick-off
+#[derive(Debug, Clone, Deserialize)]
+pub struct Scenario {
+    pub enabled: bool,
+    pub attendance: usize,
+    pub distribution: ArrivalDistribution,
+    pub first_minute: i32, // Arrival window
+    pub last_minute: i32,
+    pub mean: f64, // Normal distribution, in minutes
+    pub deviation: f64,
+    #[serde(default)]
+    pub profile_csv: Option<String>, // minutes_to_game,weight
+    #[serde(default)]
+    pub gate_weights: HashMap<String, f64>, // Gate name -> weight, 1 for the rest
+    #[serde(default)]
+    pub seats_csv: Option<String>, // mouth,seats. Same seats for every mouth if missing
+}
+
+// Closures scripted by step: step,kind (gate|connection|area),layer,name,x,y,width,height
+#[derive(Debug, Clone, Deserialize)]
+pub struct IncidentScript {
+    pub enabled: bool,
+    pub file: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Match {
+    pub match_start: f64,
+    pub seconds_per_step: f64,
+    pub distribute_agents_along_minutes: bool,
+}
+
+// Traversal of inter-floor connections, times in steps
+#[derive(Debug, Clone, Deserialize)]
+pub struct Connections {
+    pub accessibility_ratio: f64, // Share of agents that avoid stairs
+    pub stair_length: usize,      // Cells walked between floors
+    #[serde(deserialize_with = "positive")]
+    pub stair_speed: f64, // Speed reduction on stairs (1 = flat floor)
+    pub stair_capacity: usize,    // Simultaneous users
+    pub stair_flow: usize,        // Agents stepping in per step
+    pub ramp_traversal: usize,
+    pub ramp_capacity: usize,
+    pub escalator_traversal: usize,
+    pub escalator_capacity: usize,
+    pub elevator_traversal: usize,
+    pub elevator_capacity: usize, // Car capacity
+    pub elevator_cycle: usize,    // Steps between car departures
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceDistribution {
+    Fixed,
+    Uniform,
+    Exponential,
+    Normal,
+}
+
+// Turnstiles at every gate, times in seconds
+#[derive(Debug, Clone, Deserialize)]
+pub struct Gates {
+    pub turnstiles: usize,
+    pub service_time: ServiceDistribution,
+    pub service_mean: f64,
+    pub service_deviation: f64, // Half-width for uniform, standard deviation for normal
+    #[serde(default)]
+    pub turnstiles_per_gate: HashMap<String, usize>, // Gate name -> open turnstiles
+}
+
+impl Gates {
+    pub fn turnstiles(&self, gate: &str) -> usize {
+        *self
+            .turnstiles_per_gate
+            .get(gate)
+            .unwrap_or(&self.turnstiles)
+    }
+}
+
+// Points of interest, times in seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Services {
+    pub bar_probability: f64,
+    pub restroom_probability: f64,
+    pub kiosk_probability: f64,
+    pub bar_capacity: usize, // Agents served at once
+    pub bar_service: f64,
+    pub restroom_capacity: usize,
+    pub restroom_service: f64,
+    pub kiosk_capacity: usize,
+    pub kiosk_service: f64,
+}
+
+// Match phases, minutes relative to kick-off. Pre-match runs until first_half
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Timeline {
+    pub first_half: f64,
+    pub half_time: f64,
+    pub second_half: f64,
+    pub post_match: f64,
+    pub half_time_visits: f64, // Factor over the service probabilities at half-time
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictRule {
+    #[default]
+    Random,
+    Distance, // Closest to the end of its path
+    Age,      // First to enter the venue
+}
+
+// Agents contending for the same cell
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Conflicts {
+    pub rule: ConflictRule,
+    pub sidestep_after: usize, // Steps waited before stepping aside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connections(stair_speed: &str) -> Result<Connections, toml::de::Error> {
+        toml::from_str(&format!(
+            "accessibility_ratio = 0.1\nstair_length = 10\nstair_speed = {stair_speed}\n\
+             stair_capacity = 40\nstair_flow = 2\nramp_traversal = 20\nramp_capacity = 30\n\
+             escalator_traversal = 15\nescalator_capacity = 20\nelevator_traversal = 10\n\
+             elevator_capacity = 12\nelevator_cycle = 60\n"
+        ))
+    }
+
+    #[test]
+    fn stair_speed_must_be_positive() {
+        assert!(connections("0.8").is_ok());
+        assert!(connections("0.0").is_err());
+        assert!(connections("-1.0").is_err());
+        assert!(connections("nan").is_err());
+    }
+}
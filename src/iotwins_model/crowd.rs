@@ -0,0 +1,357 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::{Deref, Range},
+};
+
+use crate::iotwins_model::agent::{Agent, Walk};
+
+// Spare cells a path gets when it is written, waits and wanderings grow paths in place
+const SLACK: usize = 64;
+
+/// Agents of a floor stored by slot, slot i of every array belongs to the same agent.
+/// Paths live back to back in one arena, in slot order, each slot keeps where its path starts,
+/// its length and the room it has to grow. Occupancy and group checks only scan positions,
+/// movement writes walks and paths side by side without any lookup by id
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Crowd {
+    pub agents: Vec<Agent>,          // Trip, agenda and group
+    pub walks: Vec<Walk>,            // Path position, announced cell and conflict counters
+    pub positions: Vec<usize>,       // Current cell, refreshed once movements are resolved
+    taken: HashSet<usize>,           // Cells of the positions and of the agents placed since
+    spans: Vec<Span>,                // Path of every slot in the arena
+    spills: Vec<Option<Vec<usize>>>, // Paths that outgrew their span, moved back on settle
+    arena: Vec<usize>,               // Paths of every agent
+    garbage: usize,                  // Arena cells of removed or moved paths
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+struct Span {
+    offset: usize,
+    len: usize,
+    capacity: usize,
+}
+
+impl Crowd {
+    pub fn push(&mut self, agent: Agent, walk: Walk, path: Vec<usize>) {
+        let position = path.get(walk.steps).or(path.last()).copied().unwrap_or(0);
+
+        let span = self.write(&path);
+
+        self.spans.push(span);
+        self.spills.push(None);
+        self.taken.insert(position);
+        self.positions.push(position);
+        self.walks.push(walk);
+        self.agents.push(agent);
+    }
+
+    // Cells with an agent on them, agents joining the floor start on free ones
+    pub fn taken(&self) -> &HashSet<usize> {
+        &self.taken
+    }
+
+    // Path at the end of the arena, with room to grow
+    fn write(&mut self, path: &[usize]) -> Span {
+        let span = Span {
+            offset: self.arena.len(),
+            len: path.len(),
+            capacity: path.len() + SLACK.max(path.len() / 4),
+        };
+
+        self.arena.extend_from_slice(path);
+        self.arena.resize(span.offset + span.capacity, 0);
+
+        span
+    }
+
+    pub fn path(&self, slot: usize) -> &[usize] {
+        let span = self.spans[slot];
+        &self.arena[span.offset..span.offset + span.len]
+    }
+
+    // Agent id -> slot
+    pub fn slots(&self) -> HashMap<usize, usize> {
+        HashMap::from_iter(
+            self.agents
+                .iter()
+                .enumerate()
+                .map(|(slot, ag)| (ag.id, slot)),
+        )
+    }
+
+    // Agents, their walks and their paths, slot by slot, walks and paths to be edited in parallel
+    pub fn walks_paths_mut(&mut self) -> (&[Agent], &mut Vec<Walk>, Vec<PathMut<'_>>) {
+        let mut rest: &mut [usize] = &mut self.arena;
+        let mut end = 0;
+
+        let paths = self
+            .spans
+            .iter_mut()
+            .zip(self.spills.iter_mut())
+            .map(|(span, spill)| {
+                let (_, tail) = std::mem::take(&mut rest).split_at_mut(span.offset - end);
+                let (cells, tail) = tail.split_at_mut(span.capacity);
+
+                rest = tail;
+                end = span.offset + span.capacity;
+
+                PathMut {
+                    cells,
+                    len: &mut span.len,
+                    spill,
+                }
+            })
+            .collect();
+
+        (&self.agents, &mut self.walks, paths)
+    }
+
+    // Sequential edit of every path as an owned vector, for rare changes such as reroutings
+    pub fn for_each_path(&mut self, mut edit: impl FnMut(&mut Agent, &mut Walk, &mut Vec<usize>)) {
+        (0..self.agents.len()).for_each(|slot| {
+            let mut path = self.path(slot).to_vec();
+            edit(&mut self.agents[slot], &mut self.walks[slot], &mut path);
+
+            let span = &mut self.spans[slot];
+
+            match path.len() <= span.capacity {
+                true => {
+                    self.arena[span.offset..span.offset + path.len()].copy_from_slice(&path);
+                    span.len = path.len();
+                }
+                false => self.spills[slot] = Some(path),
+            }
+        });
+
+        self.relocate();
+    }
+
+    // Removes the agents meeting the condition with their walks and paths, the rest keep their order
+    pub fn drain_where(
+        &mut self,
+        condition: impl Fn(&Agent, &Walk) -> bool,
+    ) -> Vec<(Agent, Walk, Vec<usize>)> {
+        if !self
+            .agents
+            .iter()
+            .zip(self.walks.iter())
+            .any(|(agent, walk)| condition(agent, walk))
+        {
+            return Vec::new();
+        }
+
+        let agents = std::mem::take(&mut self.agents);
+        let walks = std::mem::take(&mut self.walks);
+        let positions = std::mem::take(&mut self.positions);
+        let spans = std::mem::take(&mut self.spans);
+        let spills = std::mem::take(&mut self.spills);
+
+        let mut drained = Vec::new();
+
+        agents
+            .into_iter()
+            .zip(walks.into_iter().zip(positions))
+            .zip(spans.into_iter().zip(spills))
+            .for_each(|((mut agent, (walk, position)), (span, spill))| {
+                match condition(&agent, &walk) {
+                    true => {
+                        let path = spill.unwrap_or_else(|| {
+                            self.arena[span.offset..span.offset + span.len].to_vec()
+                        });
+
+                        self.garbage += span.capacity;
+                        walk.end(&mut agent);
+                        drained.push((agent, walk, path));
+                    }
+                    false => {
+                        self.agents.push(agent);
+                        self.walks.push(walk);
+                        self.positions.push(position);
+                        self.spans.push(span);
+                        self.spills.push(spill);
+                    }
+                }
+            });
+
+        self.taken = self.positions.iter().copied().collect();
+
+        drained
+    }
+
+    // Positions after the step, paths that outgrew their span go back to the arena
+    pub fn settle(&mut self) {
+        self.relocate();
+
+        self.positions
+            .iter_mut()
+            .zip(self.walks.iter().zip(self.spans.iter()))
+            .for_each(|(position, (walk, span))| {
+                let path = &self.arena[span.offset..span.offset + span.len];
+                *position = path.get(walk.steps).or(path.last()).copied().unwrap_or(0)
+            });
+
+        self.taken = self.positions.iter().copied().collect();
+    }
+
+    // Spilled paths are written at the end of the arena and their slots moved last, so slots
+    // keep the arena order. The arena is compacted once most of it is garbage
+    fn relocate(&mut self) {
+        let spilled: Vec<usize> = (0..self.spills.len())
+            .filter(|slot| self.spills[*slot].is_some())
+            .collect();
+
+        if !spilled.is_empty() {
+            spilled.iter().for_each(|slot| {
+                let path = self.spills[*slot].take().unwrap();
+
+                self.garbage += self.spans[*slot].capacity;
+                self.spans[*slot] = self.write(&path);
+            });
+
+            let moved: Vec<bool> = (0..self.agents.len())
+                .map(|slot| spilled.binary_search(&slot).is_ok())
+                .collect();
+
+            let order: Vec<usize> = (0..self.agents.len())
+                .filter(|slot| !moved[*slot])
+                .chain(spilled)
+                .collect();
+
+            self.reorder(&order);
+        }
+
+        if self.garbage > self.arena.len() / 2 {
+            self.compact();
+        }
+    }
+
+    // Slots in the given order
+    fn reorder(&mut self, order: &[usize]) {
+        let mut agents: Vec<Option<Agent>> = std::mem::take(&mut self.agents)
+            .into_iter()
+            .map(Some)
+            .collect();
+
+        self.agents = order
+            .iter()
+            .map(|slot| agents[*slot].take().unwrap())
+            .collect();
+        self.walks = order.iter().map(|slot| self.walks[*slot]).collect();
+        self.positions = order.iter().map(|slot| self.positions[*slot]).collect();
+        self.spans = order.iter().map(|slot| self.spans[*slot]).collect();
+    }
+
+    // Arena without garbage, every path gets its slack back
+    fn compact(&mut self) {
+        let arena = std::mem::take(&mut self.arena);
+        let spans = std::mem::take(&mut self.spans);
+
+        self.garbage = 0;
+        self.spans = spans
+            .iter()
+            .map(|span| self.write(&arena[span.offset..span.offset + span.len]))
+            .collect();
+    }
+}
+
+/// Path of one agent, edited inside its arena span and spilled out of it when it outgrows it
+pub struct PathMut<'a> {
+    cells: &'a mut [usize], // Whole span, the first len cells are the path
+    len: &'a mut usize,
+    spill: &'a mut Option<Vec<usize>>,
+}
+
+impl PathMut<'_> {
+    pub fn insert(&mut self, index: usize, cell: usize) {
+        self.splice(index..index, &[cell]);
+    }
+
+    // Replaces a range of the path with the given cells
+    pub fn splice(&mut self, range: Range<usize>, cells: &[usize]) {
+        if let Some(path) = self.spill.as_mut() {
+            path.splice(range, cells.iter().copied());
+            return;
+        }
+
+        let len = *self.len;
+        let new_len = len - range.len() + cells.len();
+
+        if new_len > self.cells.len() {
+            let mut path = self.cells[..len].to_vec();
+            path.splice(range, cells.iter().copied());
+
+            *self.spill = Some(path);
+            return;
+        }
+
+        self.cells
+            .copy_within(range.end..len, range.start + cells.len());
+        self.cells[range.start..range.start + cells.len()].copy_from_slice(cells);
+
+        *self.len = new_len;
+    }
+}
+
+// Read like a slice, whether spilled or not
+impl Deref for PathMut<'_> {
+    type Target = [usize];
+
+    fn deref(&self) -> &[usize] {
+        match self.spill.as_deref() {
+            Some(path) => path,
+            None => &self.cells[..*self.len],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(id: usize) -> Agent {
+        let mut agent = Agent::default();
+        agent.id = id;
+        agent
+    }
+
+    #[test]
+    fn paths_grow_in_place_and_spill() {
+        let mut crowd = Crowd::default();
+        crowd.push(agent(0), Walk::default(), vec![1, 2, 3]);
+        crowd.push(agent(1), Walk::default(), vec![4, 5]);
+
+        {
+            let (_, _, mut paths) = crowd.walks_paths_mut();
+
+            paths[0].splice(1..1, &vec![9; 2 * SLACK]);
+            paths[1].insert(1, 4);
+            assert_eq!(*paths[1], [4, 4, 5]);
+        }
+
+        crowd.settle();
+
+        // The grown path moved last in the arena, and so did its slot
+        assert_eq!(crowd.agents[0].id, 1);
+        assert_eq!(crowd.path(0), [4, 4, 5]);
+        assert_eq!(crowd.agents[1].id, 0);
+        assert_eq!(crowd.path(1).len(), 3 + 2 * SLACK);
+        assert_eq!(crowd.positions, [4, 1]);
+    }
+
+    #[test]
+    fn drained_paths_are_reclaimed() {
+        let mut crowd = Crowd::default();
+        (0..4).for_each(|id| crowd.push(agent(id), Walk::default(), vec![id; 10]));
+
+        let drained = crowd.drain_where(|ag, _| ag.id < 3);
+        assert_eq!(drained.len(), 3);
+        assert_eq!(drained[1].2, vec![1; 10]);
+
+        crowd.for_each_path(|ag, _, path| path.push(ag.id));
+
+        // Most of the arena was garbage, it is compacted
+        assert_eq!(crowd.garbage, 0);
+        assert_eq!(crowd.path(0), [vec![3; 10], vec![3]].concat());
+    }
+}
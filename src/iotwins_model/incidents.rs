@@ -0,0 +1,496 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io::BufWriter,
+};
+
+use crate::{
+    engine::{
+        logging::info,
+        matrix::{Matrix, Position},
+        path_finding::a_star,
+    },
+    iotwins_model::{
+        agent::{Agent, Walk},
+        arrivals::Arrival,
+        registry::{LayerId, Registry, StructureId},
+        routes::{LayerGraph, RouteUpdate},
+        timeline::MatchClock,
+    },
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Gate,       // Gate by name, its queue moves to the closest open gate of the floor
+    Connection, // Stair, ramp, escalator or elevator covering x,y
+    Area,       // Cells from x,y spanning height rows and width columns become walls
+}
+
+/// Closure scripted at a step
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Incident {
+    pub step: u32,
+    pub kind: Kind,
+    #[serde(default)]
+    pub layer: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub x: Option<usize>,
+    #[serde(default)]
+    pub y: Option<usize>,
+    #[serde(default)]
+    pub width: Option<usize>,
+    #[serde(default)]
+    pub height: Option<usize>,
+}
+
+impl Incident {
+    pub fn cell(&self) -> usize {
+        627 * self.x.unwrap_or(0) + self.y.unwrap_or(0)
+    }
+
+    // Cells of a closed area, single cell if no size is given
+    pub fn cells(&self) -> HashSet<usize> {
+        let (x, y) = (self.x.unwrap_or(0), self.y.unwrap_or(0));
+        let rows = x..(x + self.height.unwrap_or(1).max(1)).min(627);
+        let columns = y..(y + self.width.unwrap_or(1).max(1)).min(627);
+
+        rows.flat_map(|row| columns.clone().map(move |column| 627 * row + column))
+            .collect()
+    }
+
+    pub fn describe(&self) -> String {
+        let (x, y) = (self.x.unwrap_or(0), self.y.unwrap_or(0));
+
+        match self.kind {
+            Kind::Gate => format!("gate {}", self.name),
+            Kind::Connection => format!("connection at {x},{y} on {}", self.layer),
+            Kind::Area => format!(
+                "area {}x{} at {x},{y} on {}",
+                self.height.unwrap_or(1),
+                self.width.unwrap_or(1),
+                self.layer
+            ),
+        }
+    }
+}
+
+// Effects of the incidents of a step
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Impact {
+    pub rerouted: usize,
+    pub lost: usize,
+    pub extra_steps: i64,  // Path length added by rerouting
+    pub redirected: usize, // People sent to another gate
+    pub routes_changed: usize,
+    pub routes_removed: usize,
+}
+
+impl Impact {
+    pub fn add(&mut self, other: &Impact) {
+        self.rerouted += other.rerouted;
+        self.lost += other.lost;
+        self.extra_steps += other.extra_steps;
+        self.redirected += other.redirected;
+        self.routes_changed += other.routes_changed;
+        self.routes_removed += other.routes_removed;
+    }
+
+    // Routes of the caches of a floor
+    pub fn count_routes(&mut self, updates: &[RouteUpdate]) {
+        updates.iter().for_each(|update| match update.cost {
+            Some(_) => self.routes_changed += 1,
+            None => self.routes_removed += 1,
+        });
+    }
+}
+
+/// Scripted closures, the state they leave behind and their impact on travel times
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Incidents {
+    script: Vec<Incident>,
+    pub redirects: HashMap<String, Option<String>>, // Closed gate -> closest open gate of its floor
+    pub closed_areas: HashSet<LayerId>,             // Floors whose gate routes changed
+    impacts: BTreeMap<u32, Impact>,
+}
+
+impl Incidents {
+    pub fn load(path: &str) -> Incidents {
+        let mut reader = csv::Reader::from_path(path).expect("[ERROR] Incidents file not found");
+
+        let mut script: Vec<Incident> = reader
+            .deserialize()
+            .map(|result| result.expect("[ERROR] Incorrect incident format"))
+            .collect();
+
+        script.sort_by_key(|incident| incident.step);
+
+        info!(Loaders, "{} incidents loaded", script.len());
+
+        Incidents {
+            script,
+            ..Default::default()
+        }
+    }
+
+    // Closures happen between steps, the ones of step 0 before step 1
+    pub fn due(&self, step: u32) -> Vec<Incident> {
+        self.script
+            .iter()
+            .filter(|incident| incident.step.max(1) == step)
+            .cloned()
+            .collect()
+    }
+
+    pub fn impact(&mut self, step: u32) -> &mut Impact {
+        self.impacts.entry(step).or_default()
+    }
+
+    // Arrivals at closed gates go to their replacement, the ones without any are returned apart
+    pub fn redirect(&self, arrivals: &[Arrival]) -> (Vec<Arrival>, Vec<Arrival>) {
+        let mut open = Vec::new();
+        let mut lost = Vec::new();
+
+        arrivals
+            .iter()
+            .for_each(|arrival| match self.redirects.get(&arrival.gate) {
+                None => open.push(arrival.to_owned()),
+                Some(Some(gate)) => open.push(Arrival {
+                    gate: gate.to_string(),
+                    ..arrival.to_owned()
+                }),
+                Some(None) => lost.push(arrival.to_owned()),
+            });
+
+        (open, lost)
+    }
+
+    // Impacts of another process
+    pub fn merge(&mut self, other: Incidents) {
+        other.impacts.into_iter().for_each(|(step, impact)| {
+            self.impacts.entry(step).or_default().add(&impact);
+        });
+    }
+
+    // Impact of the incidents of every step
+    pub fn save(&self, path: &str, clock: &MatchClock) {
+        if self.script.is_empty() {
+            return;
+        }
+
+        let mut writter = csv::Writer::from_writer(BufWriter::new(File::create(path).unwrap()));
+
+        writter
+            .write_record([
+                "step",
+                "minute",
+                "incidents",
+                "rerouted",
+                "lost",
+                "extra_steps",
+                "mean_extra_seconds",
+                "redirected",
+                "routes_changed",
+                "routes_removed",
+            ])
+            .unwrap();
+
+        self.impacts.iter().for_each(|(step, impact)| {
+            let incidents: Vec<String> = self
+                .due(*step)
+                .iter()
+                .map(|incident| incident.describe())
+                .collect();
+
+            let mean_extra = match impact.rerouted {
+                0 => 0.0,
+                rerouted => impact.extra_steps as f64 * clock.seconds_per_step / rerouted as f64,
+            };
+
+            info!(
+                World,
+                "Incidents at step {step}: {} rerouted (+{mean_extra:.1}s each), {} lost, {} redirected",
+                impact.rerouted,
+                impact.lost,
+                impact.redirected
+            );
+
+            writter
+                .write_record(&[
+                    format!("{step}"),
+                    format!("{:.1}", clock.minute(*step)),
+                    incidents.join("; "),
+                    format!("{}", impact.rerouted),
+                    format!("{}", impact.lost),
+                    format!("{}", impact.extra_steps),
+                    format!("{mean_extra:.1}"),
+                    format!("{}", impact.redirected),
+                    format!("{}", impact.routes_changed),
+                    format!("{}", impact.routes_removed),
+                ])
+                .unwrap();
+        });
+    }
+}
+
+// What became of an agent after the closures
+pub enum Rerouting {
+    Unaffected,
+    Rerouted(i64), // Steps added to its path
+    Lost,
+}
+
+/// Ways around the closures of a floor
+pub struct Detour<'a> {
+    pub layer: LayerId,
+    pub gt: &'a Matrix<u8>,
+    pub blocked: &'a HashSet<usize>,
+    pub graph: &'a LayerGraph,
+    pub registry: &'a Registry,
+    pub mouths: &'a HashMap<(LayerId, u16), StructureId>,
+}
+
+impl Detour<'_> {
+    // Agents crossing closed cells walk around them, closed trips are planned again
+    pub fn agent(&self, agent: &mut Agent, walk: &mut Walk, path: &mut Vec<usize>) -> Rerouting {
+        let at = walk.steps.min(path.len() - 1);
+        let here = path[at];
+
+        let crossing = path[at..].iter().any(|cell| self.blocked.contains(cell));
+        let open = self.trip_open(agent);
+
+        if !crossing && open {
+            return Rerouting::Unaffected;
+        }
+
+        let before = path.len() - at;
+
+        let detour = match open {
+            true => self.around(agent, path, at),
+            false => None,
+        };
+
+        match detour.or_else(|| self.replan(agent, here)) {
+            Some(detour) => {
+                path.truncate(at);
+                path.extend(detour);
+
+                walk.steps = at;
+                walk.next_step = *path.get(at + 1).unwrap_or(&0);
+
+                Rerouting::Rerouted((path.len() - at) as i64 - before as i64)
+            }
+            None => Rerouting::Lost,
+        }
+    }
+
+    // Target and every further leg of the trip still open
+    pub fn trip_open(&self, agent: &Agent) -> bool {
+        !self.graph.is_closed(self.layer, agent.target)
+            && agent.plan.iter().all(|leg| self.graph.walkable(leg))
+    }
+
+    // Same end of the path on another way, a pending service visit is kept.
+    // a_star paths start at their origin and stop right before their destination
+    fn around(&self, agent: &mut Agent, path: &[usize], at: usize) -> Option<Vec<usize>> {
+        let end = *path.last()?;
+
+        let mut way = match agent.visit {
+            Some((idx, point)) if idx > at => {
+                let mut going = a_star(self.gt, path[at], path[idx])?;
+                let leaving = a_star(self.gt, path[idx], end)?;
+
+                agent.visit = Some((at + going.len(), point));
+                going.extend(&leaving);
+
+                going
+            }
+            _ => a_star(self.gt, path[at], end)?,
+        };
+
+        way.push(end);
+
+        Some(way)
+    }
+
+    // New trip from the agent's cell and the path to its first structure
+    fn replan(&self, agent: &mut Agent, here: usize) -> Option<Vec<usize>> {
+        let target = self.trip(agent, here)?;
+        let position = Position::new(here, self.gt.n_rows);
+
+        let cell = self
+            .registry
+            .structure(target)
+            .location
+            .iter()
+            .min_by_key(|cell| Position::new(**cell, self.gt.n_rows).distance(&position))?;
+
+        a_star(self.gt, here, *cell)
+    }
+
+    /// Trip planned again from a cell of the floor, the agent gets its new target and legs.
+    /// Pending service visits are given up
+    pub fn trip(&self, agent: &mut Agent, here: usize) -> Option<StructureId> {
+        let position = Position::new(here, self.gt.n_rows);
+        let distance = |structure: StructureId| {
+            (self
+                .registry
+                .structure(structure)
+                .position
+                .distance(&position) as f64)
+                .sqrt() as u64
+        };
+
+        let mouth = match agent.egress {
+            true => None,
+            false => Some((
+                agent.destination_layer,
+                *self
+                    .mouths
+                    .get(&(agent.destination_layer, agent.destination))?,
+            )),
+        };
+
+        let (target, legs) = self
+            .graph
+            .replan(self.layer, distance, mouth, agent.accessibility)?;
+
+        agent.target = target;
+        agent.plan = legs;
+        agent.visit = None;
+
+        Some(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::path_finding::movements,
+        iotwins_model::{routes::Role, structures::Structure},
+    };
+
+    const SIDE: usize = 627;
+
+    // Open floor with a wall along the first row
+    fn floor() -> Matrix<u8> {
+        let mut data = vec![0; SIDE * SIDE];
+        data[..SIDE].iter_mut().for_each(|cell| *cell = 1);
+
+        Matrix { data, n_rows: SIDE }
+    }
+
+    // Every step of the path stays or moves to a walkable cell next to it
+    fn legal(path: &[usize], gt: &Matrix<u8>) -> bool {
+        path.windows(2)
+            .all(|pair| pair[0] == pair[1] || movements(pair[0], gt).contains(&pair[1]))
+    }
+
+    fn detour<'a>(
+        gt: &'a Matrix<u8>,
+        blocked: &'a HashSet<usize>,
+        graph: &'a LayerGraph,
+        registry: &'a Registry,
+        mouths: &'a HashMap<(LayerId, u16), StructureId>,
+    ) -> Detour<'a> {
+        Detour {
+            layer: 0,
+            gt,
+            blocked,
+            graph,
+            registry,
+            mouths,
+        }
+    }
+
+    #[test]
+    fn around_steps_through_the_service_cell() {
+        // Path down a column, closed halfway by a wall across it
+        let path: Vec<usize> = (10..40).map(|row| row * SIDE + 10).collect();
+        let blocked: HashSet<usize> = (5..16).map(|col| 20 * SIDE + col).collect();
+
+        let mut gt = floor();
+        blocked.iter().for_each(|cell| gt.data[*cell] = 1);
+
+        let (graph, registry, mouths) = Default::default();
+        let detour = detour(&gt, &blocked, &graph, &registry, &mouths);
+
+        let mut agent = Agent::default();
+        agent.visit = Some((25, 7));
+
+        let at = 2;
+        let way = detour.around(&mut agent, &path, at).unwrap();
+
+        assert_eq!(way.first(), Some(&path[at]));
+        assert_eq!(way.last(), path.last());
+        assert!(legal(&way, &gt));
+        assert!(way.iter().all(|cell| !blocked.contains(cell)));
+
+        // The visit index points at the service cell once the way is joined to the path
+        let (idx, point) = agent.visit.unwrap();
+        assert_eq!(point, 7);
+        assert_eq!(way[idx - at], path[25]);
+    }
+
+    #[test]
+    fn around_without_visits_keeps_the_end() {
+        let path: Vec<usize> = (10..40).map(|row| row * SIDE + 10).collect();
+        let blocked: HashSet<usize> = (5..16).map(|col| 20 * SIDE + col).collect();
+
+        let mut gt = floor();
+        blocked.iter().for_each(|cell| gt.data[*cell] = 1);
+
+        let (graph, registry, mouths) = Default::default();
+        let detour = detour(&gt, &blocked, &graph, &registry, &mouths);
+
+        let mut agent = Agent::default();
+        let way = detour.around(&mut agent, &path, 0).unwrap();
+
+        assert_eq!(way.first(), path.first());
+        assert_eq!(way.last(), path.last());
+        assert!(legal(&way, &gt));
+        assert!(agent.visit.is_none());
+    }
+
+    #[test]
+    fn replan_walks_to_the_closest_cell_of_the_mouth() {
+        let gt = floor();
+        let here = 10 * SIDE + 10;
+
+        let mut registry = Registry::default();
+        let mouth = registry.intern(
+            0,
+            &Structure {
+                position: Position::new(40 * SIDE + 31, SIDE),
+                location: (30..33).map(|col| 40 * SIDE + col).collect(),
+                code: 0,
+            },
+        );
+
+        let mut graph = LayerGraph::default();
+        graph.node(0, mouth, Role::Mouth);
+
+        let mouths = HashMap::from([((0, 3), mouth)]);
+        let blocked = HashSet::new();
+        let detour = detour(&gt, &blocked, &graph, &registry, &mouths);
+
+        let mut agent = Agent::default();
+        agent.destination = 3;
+        agent.visit = Some((5, 7));
+
+        let way = detour.replan(&mut agent, here).unwrap();
+
+        assert_eq!(way.first(), Some(&here));
+        assert!(legal(&way, &gt));
+        assert!(movements(*way.last().unwrap(), &gt).contains(&(40 * SIDE + 30)));
+
+        // The trip now ends at the mouth and pending visits are given up
+        assert_eq!(agent.target, mouth);
+        assert!(agent.plan.is_empty());
+        assert!(agent.visit.is_none());
+    }
+}
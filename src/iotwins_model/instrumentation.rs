@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+use std::{fs::File, io::BufWriter, time::Duration};
+
+use crate::{
+    engine::logging::info,
+    iotwins_model::registry::{LayerId, Registry},
+};
+
+// Microseconds spent on every part of a step
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct StepTimes {
+    pub rank: usize,
+    pub step: u32,
+    pub arrivals: u64,
+    pub gate_entrance: u64,
+    pub timeline: u64, // Half-time visits and egress when a phase starts
+    pub floors: u64,
+    pub swapping: u64,
+    pub saving: u64,
+    pub exchange: u64, // Agents sent to other processes
+}
+
+// Microseconds spent on a floor and the agents in it at the end of the step
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FloorTimes {
+    pub rank: usize,
+    pub step: u32,
+    pub layer: LayerId,
+    pub evolve: u64,
+    pub conflicts: u64,
+    pub walking: usize,
+    pub in_connections: usize,
+    pub at_services: usize,
+}
+
+/// Timings of every step and floor, written as CSV to be compared across versions
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Instrumentation {
+    pub enabled: bool,
+    pub rank: usize,
+    steps: Vec<StepTimes>,
+    floors: Vec<FloorTimes>,
+}
+
+pub fn micros(elapsed: Duration) -> u64 {
+    elapsed.as_micros() as u64
+}
+
+impl Instrumentation {
+    pub fn new(enabled: bool) -> Instrumentation {
+        Instrumentation {
+            enabled,
+            ..Default::default()
+        }
+    }
+
+    pub fn push_step(&mut self, times: StepTimes) {
+        if self.enabled {
+            self.steps.push(StepTimes {
+                rank: self.rank,
+                ..times
+            });
+        }
+    }
+
+    pub fn push_floor(&mut self, times: FloorTimes) {
+        if self.enabled {
+            self.floors.push(FloorTimes {
+                rank: self.rank,
+                ..times
+            });
+        }
+    }
+
+    // Exchange happens once the step is over
+    pub fn add_exchange(&mut self, elapsed: Duration) {
+        if let Some(times) = self.steps.last_mut() {
+            times.exchange += micros(elapsed);
+        }
+    }
+
+    // Records of another process
+    pub fn merge(&mut self, other: Instrumentation) {
+        self.steps.extend(other.steps);
+        self.floors.extend(other.floors);
+    }
+
+    pub fn save(&self, steps_path: &str, floors_path: &str, registry: &Registry) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut writter =
+            csv::Writer::from_writer(BufWriter::new(File::create(steps_path).unwrap()));
+
+        writter
+            .write_record([
+                "rank",
+                "step",
+                "arrivals_us",
+                "gate_entrance_us",
+                "timeline_us",
+                "floors_us",
+                "swapping_us",
+                "saving_us",
+                "exchange_us",
+            ])
+            .unwrap();
+
+        self.steps.iter().for_each(|times| {
+            writter
+                .write_record(&[
+                    format!("{}", times.rank),
+                    format!("{}", times.step),
+                    format!("{}", times.arrivals),
+                    format!("{}", times.gate_entrance),
+                    format!("{}", times.timeline),
+                    format!("{}", times.floors),
+                    format!("{}", times.swapping),
+                    format!("{}", times.saving),
+                    format!("{}", times.exchange),
+                ])
+                .unwrap();
+        });
+
+        let mut writter =
+            csv::Writer::from_writer(BufWriter::new(File::create(floors_path).unwrap()));
+
+        writter
+            .write_record([
+                "rank",
+                "step",
+                "layer",
+                "evolve_us",
+                "conflicts_us",
+                "walking",
+                "in_connections",
+                "at_services",
+            ])
+            .unwrap();
+
+        self.floors.iter().for_each(|times| {
+            writter
+                .write_record(&[
+                    format!("{}", times.rank),
+                    format!("{}", times.step),
+                    registry.layer_name(times.layer).to_string(),
+                    format!("{}", times.evolve),
+                    format!("{}", times.conflicts),
+                    format!("{}", times.walking),
+                    format!("{}", times.in_connections),
+                    format!("{}", times.at_services),
+                ])
+                .unwrap();
+        });
+    }
+
+    // Mean time per step of every part, for the console
+    pub fn summary(&self) {
+        if !self.enabled || self.steps.is_empty() {
+            return;
+        }
+
+        let steps = self.steps.len() as f64;
+        let mean = |part: fn(&StepTimes) -> u64| {
+            self.steps.iter().map(part).sum::<u64>() as f64 / steps / 1000.0
+        };
+
+        info!(Engine,
+            "Mean ms per step: arrivals {:.3}, gates {:.3}, timeline {:.3}, floors {:.3}, swapping {:.3}, saving {:.3}, exchange {:.3}",
+            mean(|times| times.arrivals),
+            mean(|times| times.gate_entrance),
+            mean(|times| times.timeline),
+            mean(|times| times.floors),
+            mean(|times| times.swapping),
+            mean(|times| times.saving),
+            mean(|times| times.exchange),
+        );
+    }
+}
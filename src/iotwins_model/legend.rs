@@ -0,0 +1,165 @@
+use serde::{
+    de::{self, IntoDeserializer},
+    Deserialize, Deserializer, Serialize,
+};
+use std::{collections::HashMap, path::Path};
+
+use crate::{
+    engine::logging::warn,
+    iotwins_model::structures::{
+        BAR, DOWN_ESCALATOR, DOWN_RAMP, DOWN_STAIR, ELEVATOR, KIOSK, RESTROOM, UP_ESCALATOR,
+        UP_RAMP, UP_STAIR,
+    },
+};
+
+// Semantic classes of a floor plan
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Class {
+    Walkable,
+    Obstacle,
+    Elevator,
+    DownStair,
+    UpStair,
+    DownRamp,
+    UpRamp,
+    DownEscalator,
+    UpEscalator,
+    Bar,
+    Restroom,
+    Kiosk,
+}
+
+impl Class {
+    // Ground truth code used by path finding and structure detection
+    pub fn code(&self) -> u8 {
+        match self {
+            Class::Walkable => 0,
+            Class::Obstacle => 1,
+            Class::Elevator => ELEVATOR,
+            Class::DownStair => DOWN_STAIR,
+            Class::UpStair => UP_STAIR,
+            Class::DownRamp => DOWN_RAMP,
+            Class::UpRamp => UP_RAMP,
+            Class::DownEscalator => DOWN_ESCALATOR,
+            Class::UpEscalator => UP_ESCALATOR,
+            Class::Bar => BAR,
+            Class::Restroom => RESTROOM,
+            Class::Kiosk => KIOSK,
+        }
+    }
+}
+
+// Pixel values of a class, either grayscale or colour
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Swatch {
+    #[serde(default)]
+    pub luma: Vec<u8>,
+    #[serde(default)]
+    pub rgb: Vec<[u8; 3]>,
+}
+
+pub type Legend = HashMap<Class, Swatch>;
+
+// Toml keys are plain strings, classes are parsed from them
+fn classes<E: de::Error>(raw: HashMap<String, Swatch>) -> Result<Legend, E> {
+    raw.into_iter()
+        .map(|(class, swatch)| {
+            Class::deserialize(IntoDeserializer::<E>::into_deserializer(class.as_str()))
+                .map(|class| (class, swatch))
+        })
+        .collect()
+}
+
+pub fn deserialize_legend<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Legend, D::Error> {
+    classes(HashMap::deserialize(deserializer)?)
+}
+
+/// Pixel value -> ground truth code. RGB and luma are matched separately
+pub fn codification(legend: &Legend) -> (HashMap<[u8; 3], u8>, HashMap<u8, u8>) {
+    let mut rgb = HashMap::new();
+    let mut luma = HashMap::new();
+
+    legend.iter().for_each(|(class, swatch)| {
+        swatch.rgb.iter().for_each(|colour| {
+            if let Some(other) = rgb.insert(*colour, class.code()) {
+                warn!(
+                    Loaders,
+                    "Colour {colour:?} used by {class:?} and code {other}"
+                );
+            }
+        });
+
+        swatch.luma.iter().for_each(|value| {
+            if let Some(other) = luma.insert(*value, class.code()) {
+                warn!(Loaders, "Gray {value} used by {class:?} and code {other}");
+            }
+        });
+    });
+
+    (rgb, luma)
+}
+
+/// Legend next to a blueprint (<map>.legend.toml), if any
+pub fn map_legend(map: &str) -> Option<Legend> {
+    let path = Path::new(map).with_extension("legend.toml");
+
+    let data = std::fs::read_to_string(&path).ok()?;
+
+    match toml::from_str(&data).and_then(classes::<toml::de::Error>) {
+        Ok(legend) => Some(legend),
+        Err(error) => panic!("[ERROR] Wrong legend {:?}: {:?}", path, error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Config {
+        #[serde(deserialize_with = "deserialize_legend")]
+        codification: Legend,
+    }
+
+    #[test]
+    fn classes_are_parsed_from_keys() {
+        let config: Config = toml::from_str(
+            "[codification]\nobstacle = { luma = [84, 202] }\nbar = { rgb = [[255, 0, 0]] }\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.codification[&Class::Obstacle].luma, [84, 202]);
+        assert_eq!(config.codification[&Class::Bar].rgb, [[255, 0, 0]]);
+    }
+
+    #[test]
+    fn unknown_classes_are_rejected() {
+        assert!(toml::from_str::<Config>("[codification]\nstage = { luma = [1] }\n").is_err());
+    }
+
+    #[test]
+    fn pixels_map_to_ground_truth_codes() {
+        let legend = Legend::from([
+            (
+                Class::Walkable,
+                Swatch {
+                    luma: vec![255],
+                    ..Default::default()
+                },
+            ),
+            (
+                Class::Restroom,
+                Swatch {
+                    rgb: vec![[0, 0, 255]],
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        let (rgb, luma) = codification(&legend);
+
+        assert_eq!(rgb[&[0, 0, 255]], RESTROOM);
+        assert_eq!(luma[&255], 0);
+    }
+}
@@ -0,0 +1,264 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::BufWriter,
+};
+
+use crate::{
+    engine::logging::{debug, info, warn},
+    iotwins_model::{
+        agent::Agent,
+        arrivals::Arrival,
+        registry::{LayerId, Registry},
+    },
+};
+
+// Why an agent left the simulation before reaching its mouth or gate
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug)]
+pub enum Reason {
+    UnknownGate,        // Arrival at a gate missing from the gates file
+    GateOffFloor,       // Gate on no simulated floor
+    MouthOffFloor,      // Mouth on no simulated floor
+    UnknownMouth,       // Mouth missing from its floor
+    NoPathToMouth,      // No path from the gate to a mouth on the same floor
+    NoTrip,             // No trip over the building or no route to its first connection
+    NoConnectionBuffer, // Next connection of the trip missing on its floor
+    NoRouteOnFloor,     // No route from the connection the agent came through
+    NoEgressPath,       // No path to the gate after the match
+    GateClosed,         // Arrival at a closed gate with no open gate on its floor
+    ClosedOff,          // No way around the closures of an incident
+}
+
+impl Reason {
+    pub fn name(&self) -> &str {
+        match self {
+            Reason::UnknownGate => "unknown_gate",
+            Reason::GateOffFloor => "gate_off_floor",
+            Reason::MouthOffFloor => "mouth_off_floor",
+            Reason::UnknownMouth => "unknown_mouth",
+            Reason::NoPathToMouth => "no_path_to_mouth",
+            Reason::NoTrip => "no_trip",
+            Reason::NoConnectionBuffer => "no_connection_buffer",
+            Reason::NoRouteOnFloor => "no_route_on_floor",
+            Reason::NoEgressPath => "no_egress_path",
+            Reason::GateClosed => "gate_closed",
+            Reason::ClosedOff => "closed_off",
+        }
+    }
+}
+
+// Where an agent is, or how it ended
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug)]
+pub enum Fate {
+    QueuedAtGate, // Not an agent yet
+    Walking,
+    InConnection,
+    AtService,
+    Seated,
+    Exited,
+    Lost(Reason),
+}
+
+impl Fate {
+    pub fn name(&self) -> &str {
+        match self {
+            Fate::QueuedAtGate => "queued_at_gate",
+            Fate::Walking => "walking",
+            Fate::InConnection => "in_connection",
+            Fate::AtService => "at_service",
+            Fate::Seated => "seated",
+            Fate::Exited => "exited",
+            Fate::Lost(_) => "lost",
+        }
+    }
+
+    fn reason(&self) -> &str {
+        match self {
+            Fate::Lost(reason) => reason.name(),
+            _ => "",
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub id: Option<usize>, // Arrivals lost before becoming agents have no id
+    pub agents: usize,
+    pub fate: Fate,
+    pub gate: String,
+    pub mouth: u16,
+    pub layer: Option<LayerId>, // Last floor of the agent
+}
+
+/// State of every agent at the end of the simulation and the reason of every loss
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Lifecycle {
+    pub rank: usize,
+    records: Vec<Record>,
+}
+
+impl Lifecycle {
+    // Whole arrival dropped before its agents are created
+    pub fn lose_arrival(&mut self, arrival: &Arrival, reason: Reason, layer: Option<LayerId>) {
+        self.records.push(Record {
+            id: None,
+            agents: arrival.agents as usize,
+            fate: Fate::Lost(reason),
+            gate: arrival.gate.to_string(),
+            mouth: arrival.mouth,
+            layer,
+        });
+    }
+
+    pub fn lose(&mut self, agent: &Agent, reason: Reason, layer: LayerId, registry: &Registry) {
+        debug!(World @ layer, "Agent {} lost: {}", agent.id, reason.name());
+        self.record(agent, Fate::Lost(reason), layer, registry);
+    }
+
+    pub fn record(&mut self, agent: &Agent, fate: Fate, layer: LayerId, registry: &Registry) {
+        self.records.push(Record {
+            id: Some(agent.id),
+            agents: 1,
+            fate,
+            gate: registry.gate_name(agent.gate).to_string(),
+            mouth: agent.destination,
+            layer: Some(layer),
+        });
+    }
+
+    // People still queued at a gate, by destination mouth
+    pub fn queued(&mut self, gate: &str, mouths: impl Iterator<Item = u16>, layer: LayerId) {
+        let mut counts: BTreeMap<u16, usize> = BTreeMap::new();
+        mouths.for_each(|mouth| *counts.entry(mouth).or_default() += 1);
+
+        self.records
+            .extend(counts.into_iter().map(|(mouth, agents)| Record {
+                id: None,
+                agents,
+                fate: Fate::QueuedAtGate,
+                gate: gate.to_string(),
+                mouth,
+                layer: Some(layer),
+            }));
+    }
+
+    // Records of another process
+    pub fn merge(&mut self, other: Lifecycle) {
+        self.records.extend(other.records);
+    }
+
+    // Every agent with its fate, and agents by fate, gate, mouth and layer
+    pub fn save(&self, agents_path: &str, breakdown_path: &str, registry: &Registry) {
+        let layer_name = |layer: Option<LayerId>| {
+            layer
+                .map(|layer| registry.layer_name(layer).to_string())
+                .unwrap_or_default()
+        };
+
+        let mut writter =
+            csv::Writer::from_writer(BufWriter::new(File::create(agents_path).unwrap()));
+
+        writter
+            .write_record(["agent_id", "fate", "reason", "gate", "mouth", "layer"])
+            .unwrap();
+
+        let mut agents: Vec<&Record> = self.records.iter().filter(|r| r.id.is_some()).collect();
+        agents.sort_by_key(|record| record.id);
+
+        agents.into_iter().for_each(|record| {
+            writter
+                .write_record([
+                    format!("{}", record.id.unwrap()),
+                    record.fate.name().to_string(),
+                    record.fate.reason().to_string(),
+                    record.gate.to_string(),
+                    format!("{}", record.mouth),
+                    layer_name(record.layer),
+                ])
+                .unwrap();
+        });
+
+        let mut breakdown: BTreeMap<(Fate, &str, u16, Option<LayerId>), usize> = BTreeMap::new();
+
+        self.records.iter().for_each(|record| {
+            *breakdown
+                .entry((record.fate, &record.gate, record.mouth, record.layer))
+                .or_default() += record.agents;
+        });
+
+        let mut writter =
+            csv::Writer::from_writer(BufWriter::new(File::create(breakdown_path).unwrap()));
+
+        writter
+            .write_record(["fate", "reason", "gate", "mouth", "layer", "agents"])
+            .unwrap();
+
+        breakdown
+            .into_iter()
+            .for_each(|((fate, gate, mouth, layer), agents)| {
+                writter
+                    .write_record([
+                        fate.name().to_string(),
+                        fate.reason().to_string(),
+                        gate.to_string(),
+                        format!("{mouth}"),
+                        layer_name(layer),
+                        format!("{agents}"),
+                    ])
+                    .unwrap();
+            });
+    }
+
+    // Agents by fate and losses by reason, created agents must all be accounted for
+    pub fn summary(&self, created: usize) {
+        let mut fates: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut reasons: HashMap<Reason, (usize, usize)> = HashMap::new(); // Agents and arrivals
+
+        self.records.iter().for_each(|record| {
+            *fates.entry(record.fate.name()).or_default() += record.agents;
+
+            if let Fate::Lost(reason) = record.fate {
+                let (agents, arrivals) = reasons.entry(reason).or_default();
+                match record.id {
+                    Some(_) => *agents += 1,
+                    None => *arrivals += record.agents,
+                }
+            }
+        });
+
+        info!(Engine, "Agents by fate: {fates:?}");
+
+        let mut reasons: Vec<(Reason, (usize, usize))> = reasons.into_iter().collect();
+        reasons.sort_by_key(|(_, (agents, arrivals))| std::cmp::Reverse(agents + arrivals));
+
+        reasons
+            .into_iter()
+            .for_each(|(reason, (agents, arrivals))| {
+                info!(
+                    Engine,
+                    "Lost by {}: {agents} agents, {arrivals} people never created",
+                    reason.name()
+                );
+            });
+
+        let accounted = self.records.iter().filter(|r| r.id.is_some()).count();
+
+        if accounted != created {
+            warn!(
+                Engine,
+                "{} agents unaccounted for ({created} created, {accounted} tracked)",
+                created as i64 - accounted as i64
+            );
+        }
+    }
+
+    // Agents lost, created or not
+    pub fn lost(&self) -> usize {
+        self.records
+            .iter()
+            .filter(|record| matches!(record.fate, Fate::Lost(_)))
+            .map(|record| record.agents)
+            .sum()
+    }
+}
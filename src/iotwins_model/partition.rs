@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, collections::HashMap, time::Duration};
+
+use crate::{
+    config::configuration::MpiAutoAdjustment,
+    iotwins_model::{registry::LayerId, stadium::Floor},
+};
+
+// Load of a floor before the simulation starts: mouths attract the agents
+pub fn initial_loads(building: &HashMap<LayerId, Floor>) -> Vec<(LayerId, usize)> {
+    building
+        .iter()
+        .map(|(layer, floor)| (*layer, floor.mouths.len() + 1))
+        .collect()
+}
+
+/// Process owning each floor. Floors only share agents through their connections,
+/// so every floor is simulated whole by one process
+pub fn split_floors(
+    mut loads: Vec<(LayerId, usize)>,
+    processes: usize,
+    uneven: bool,
+) -> HashMap<LayerId, usize> {
+    loads.sort_by_key(|(layer, _)| *layer);
+
+    match uneven {
+        // Same number of floors per process
+        false => HashMap::from_iter(
+            loads
+                .iter()
+                .enumerate()
+                .map(|(idx, (layer, _))| (*layer, idx % processes)),
+        ),
+        // Heaviest floors first, each one to the least loaded process
+        true => {
+            let mut totals = vec![0; processes];
+
+            loads.sort_by_key(|(_, load)| Reverse(*load));
+
+            HashMap::from_iter(loads.into_iter().map(|(layer, load)| {
+                let rank = (0..processes).min_by_key(|rank| totals[*rank]).unwrap();
+                totals[rank] += load;
+
+                (layer, rank)
+            }))
+        }
+    }
+}
+
+// Percentage of load the heaviest partition carries over the mean one
+pub fn unbalance(costs: &HashMap<LayerId, u64>, owners: &HashMap<LayerId, usize>) -> f64 {
+    let mut totals: HashMap<usize, u64> = HashMap::new();
+
+    owners.iter().for_each(|(layer, owner)| {
+        *totals.entry(*owner).or_default() += costs.get(layer).copied().unwrap_or(0);
+    });
+
+    let max = totals.values().copied().max().unwrap_or(0) as f64;
+    let mean = totals.values().sum::<u64>() as f64 / totals.len().max(1) as f64;
+
+    match mean > 0.0 {
+        true => (max / mean - 1.0) * 100.0,
+        false => 0.0,
+    }
+}
+
+/// Measured cost of every floor. Floors are packed into thread groups, run one after the other
+/// inside a group, and groups are packed again when their load drifts apart
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Balancer {
+    enabled: bool,
+    frequency: u32,
+    pub max_unbalance: f64,
+    costs: HashMap<LayerId, u64>, // Nanoseconds spent on each floor since the last check
+    groups: HashMap<LayerId, usize>, // Thread group of each floor, every floor alone until the first check
+}
+
+impl Balancer {
+    pub fn new(settings: &MpiAutoAdjustment) -> Balancer {
+        Balancer {
+            enabled: settings.auto_mode,
+            frequency: settings.rebalancing_freq.max(1),
+            max_unbalance: settings.maximum_perc_of_unbalance,
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&mut self, layer: LayerId, elapsed: Duration) {
+        *self.costs.entry(layer).or_default() += elapsed.as_nanos() as u64;
+    }
+
+    pub fn due(&self, step: u32) -> bool {
+        self.enabled && step > 0 && step.is_multiple_of(self.frequency)
+    }
+
+    // Costs of the last window, a new one starts
+    pub fn take_costs(&mut self) -> HashMap<LayerId, u64> {
+        std::mem::take(&mut self.costs)
+    }
+
+    // Floors are packed again into the threads if their groups are too uneven
+    pub fn pack(&mut self, costs: &HashMap<LayerId, u64>, layers: Vec<LayerId>, threads: usize) {
+        let unknown = layers.iter().any(|layer| !self.groups.contains_key(layer));
+
+        if !unknown && unbalance(costs, &self.groups) <= self.max_unbalance {
+            return;
+        }
+
+        let loads = layers
+            .into_iter()
+            .map(|layer| (layer, costs.get(&layer).copied().unwrap_or(0) as usize))
+            .collect();
+
+        self.groups = split_floors(loads, threads.max(1), true);
+    }
+
+    // Floors of every thread group
+    pub fn schedule<'a>(
+        &self,
+        building: &'a mut HashMap<LayerId, Floor>,
+    ) -> Vec<Vec<(LayerId, &'a mut Floor)>> {
+        let mut groups: HashMap<usize, Vec<(LayerId, &'a mut Floor)>> = HashMap::new();
+        let mut alone = Vec::new();
+
+        building
+            .iter_mut()
+            .for_each(|(layer, floor)| match self.groups.get(layer) {
+                Some(group) => groups.entry(*group).or_default().push((*layer, floor)),
+                None => alone.push(vec![(*layer, floor)]),
+            });
+
+        groups.into_values().chain(alone).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn even_split_deals_floors_in_turn() {
+        let owners = split_floors(vec![(2, 9), (0, 5), (3, 1), (1, 1)], 2, false);
+
+        assert_eq!(owners, HashMap::from([(0, 0), (1, 1), (2, 0), (3, 1)]));
+    }
+
+    #[test]
+    fn uneven_split_balances_loads() {
+        let owners = split_floors(vec![(0, 9), (1, 5), (2, 4), (3, 1)], 2, true);
+
+        assert_eq!(owners[&0], owners[&3]);
+        assert_eq!(owners[&1], owners[&2]);
+        assert_ne!(owners[&0], owners[&1]);
+    }
+
+    #[test]
+    fn every_floor_gets_an_owner() {
+        let owners = split_floors(vec![(0, 1), (1, 1)], 4, true);
+
+        assert_eq!(owners.len(), 2);
+        assert!(owners.values().all(|rank| *rank < 4));
+    }
+
+    #[test]
+    fn unbalance_over_the_mean() {
+        let costs = HashMap::from([(0, 30), (1, 10)]);
+
+        assert_eq!(unbalance(&costs, &HashMap::from([(0, 0), (1, 1)])), 50.0);
+        assert_eq!(unbalance(&costs, &HashMap::from([(0, 0), (1, 0)])), 0.0);
+    }
+}
@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::iotwins_model::structures::Structure;
+
+pub type LayerId = u16;
+pub type StructureId = u32;
+pub type GateId = u16;
+
+/// Interned layers and structures. Names and structures are hashed once when the world is
+/// built, agents, legs and lookups during the simulation only carry the small ids
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Registry {
+    layers: Vec<String>,
+    layer_ids: HashMap<String, LayerId>,
+    structures: Vec<Structure>,
+    structure_ids: HashMap<(LayerId, Structure), StructureId>, // Positions repeat across layers
+    gates: Vec<String>,
+    gate_ids: HashMap<String, GateId>,
+}
+
+impl Registry {
+    pub fn intern_layer(&mut self, name: &str) -> LayerId {
+        if let Some(id) = self.layer_ids.get(name) {
+            return *id;
+        }
+
+        let id = self.layers.len() as LayerId;
+
+        self.layers.push(name.to_string());
+        self.layer_ids.insert(name.to_string(), id);
+
+        id
+    }
+
+    pub fn layer_id(&self, name: &str) -> Option<LayerId> {
+        self.layer_ids.get(name).copied()
+    }
+
+    pub fn layer_name(&self, id: LayerId) -> &str {
+        &self.layers[id as usize]
+    }
+
+    // Layer names by id
+    pub fn layers(&self) -> &[String] {
+        &self.layers
+    }
+
+    // Agents keep the gate they came through
+    pub fn intern_gate(&mut self, name: &str) -> GateId {
+        if let Some(id) = self.gate_ids.get(name) {
+            return *id;
+        }
+
+        let id = self.gates.len() as GateId;
+
+        self.gates.push(name.to_string());
+        self.gate_ids.insert(name.to_string(), id);
+
+        id
+    }
+
+    pub fn gate_id(&self, name: &str) -> Option<GateId> {
+        self.gate_ids.get(name).copied()
+    }
+
+    pub fn gate_name(&self, id: GateId) -> &str {
+        &self.gates[id as usize]
+    }
+
+    pub fn intern(&mut self, layer: LayerId, structure: &Structure) -> StructureId {
+        if let Some(id) = self.id(layer, structure) {
+            return id;
+        }
+
+        let id = self.structures.len() as StructureId;
+        let key = Structure {
+            position: structure.position,
+            ..Default::default()
+        };
+
+        self.structures.push(structure.to_owned());
+        self.structure_ids.insert((layer, key), id);
+
+        id
+    }
+
+    pub fn id(&self, layer: LayerId, structure: &Structure) -> Option<StructureId> {
+        // Structures are told apart by their position, no need to clone the location
+        let key = Structure {
+            position: structure.position,
+            ..Default::default()
+        };
+
+        self.structure_ids.get(&(layer, key)).copied()
+    }
+
+    pub fn structure(&self, id: StructureId) -> &Structure {
+        &self.structures[id as usize]
+    }
+
+    // Structure of the given codes covering a cell of the layer
+    pub fn structure_at(&self, layer: LayerId, cell: usize, codes: &[u8]) -> Option<StructureId> {
+        self.structure_ids
+            .iter()
+            .filter(|((l, _), _)| *l == layer)
+            .map(|(_, id)| *id)
+            .find(|id| {
+                let structure = self.structure(*id);
+                codes.contains(&structure.code) && structure.location.contains(&cell)
+            })
+    }
+}
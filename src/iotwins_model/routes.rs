@@ -1,12 +1,19 @@
-use rand::prelude::SliceRandom;
+use rand::{prelude::SliceRandom, Rng};
 use serde::{Deserialize, Serialize};
-use std::hash::{Hash, Hasher};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+};
 
 use rayon::prelude::*;
 
 use crate::{
     engine::{matrix::Matrix, path_finding::a_star},
-    iotwins_model::structures::Structure,
+    iotwins_model::{
+        registry::{LayerId, StructureId},
+        structures::{ConnectionKind, Structure},
+    },
 };
 #[inline(always)]
 pub fn find_route(gt: &Matrix<u8>, p1: &Structure, p2: &Structure) -> Option<Route> {
@@ -17,14 +24,7 @@ pub fn find_route(gt: &Matrix<u8>, p1: &Structure, p2: &Structure) -> Option<Rou
         .collect();
 
     // Checks if routes is empty for not creating such struct
-    match routes
-        .iter()
-        .flatten()
-        .copied()
-        .into_iter()
-        .next()
-        .is_none()
-    {
+    match routes.iter().flatten().copied().next().is_none() {
         true => None,
         false => Some(Route {
             origin: p1.to_owned(),
@@ -57,11 +57,29 @@ impl PartialEq for Route {
 
 impl Route {
     // Returns a random path between the points for the agents -> O(1)
-    pub fn get_path(&self) -> Vec<usize> {
+    pub fn get_path(&self, rng: &mut impl Rng) -> Vec<usize> {
+        self.paths.choose(rng).expect("").to_vec()
+    }
+
+    // Length of the shortest path
+    pub fn cost(&self) -> u64 {
+        self.paths.iter().map(|path| path.len()).min().unwrap_or(0) as u64
+    }
+
+    /// Paths crossing closed cells are dropped, the route is searched again if none is left.
+    /// None if no path is left, otherwise whether the route changed
+    pub fn avoid(&mut self, gt: &Matrix<u8>, closed: &HashSet<usize>) -> Option<bool> {
+        let before = self.paths.len();
+
         self.paths
-            .choose(&mut rand::thread_rng())
-            .expect("")
-            .to_vec()
+            .retain(|path| !path.iter().any(|cell| closed.contains(cell)));
+
+        if self.paths.is_empty() {
+            self.paths = find_route(gt, &self.origin, &self.destination)?.paths;
+            return Some(true);
+        }
+
+        Some(self.paths.len() != before)
     }
 
     // Route is reversed
@@ -77,3 +95,520 @@ impl Route {
         }
     }
 }
+
+/// Routes of a cache avoid the closed cells, changed and removed ones are returned for the graph
+pub fn avoid_closed<K: Copy + Eq + Hash>(
+    routes: &mut HashMap<K, Route>,
+    gt: &Matrix<u8>,
+    closed: &HashSet<usize>,
+    update: impl Fn(K, Option<u64>) -> RouteUpdate,
+) -> Vec<RouteUpdate> {
+    let mut updates = Vec::new();
+
+    routes.retain(|key, route| match route.avoid(gt, closed) {
+        Some(false) => true,
+        Some(true) => {
+            updates.push(update(*key, Some(route.cost())));
+            true
+        }
+        None => {
+            updates.push(update(*key, None));
+            false
+        }
+    });
+
+    updates
+}
+
+// Part of a multi-floor trip: agent appears at entry and walks to target (next exit or mouth)
+#[derive(Clone, Copy, Default, Serialize, Deserialize, Debug)]
+pub struct Leg {
+    pub layer: LayerId,
+    pub entry: StructureId,
+    pub target: StructureId,
+}
+
+// Role of a structure in the building graph. Elevators are both an entry and an exit
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub enum Role {
+    Gate,
+    Entry,
+    Exit,
+    Mouth,
+}
+
+// New cost of a walking route after an incident, None once the route is gone
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct RouteUpdate {
+    pub layer: LayerId,
+    pub ends: (StructureId, StructureId),
+    pub roles: (Role, Role),
+    pub cost: Option<u64>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct Edge {
+    to: usize,
+    cost: u64,
+    link: Option<ConnectionKind>, // Inter-floor connection, None for walking routes
+}
+
+// Penalty for stairs and escalators when planning step-free trips
+const STEP_FREE_PENALTY: u64 = 100_000;
+
+/// Building-level graph. Nodes are gates, entries, exits and mouths per layer,
+/// edges are the precomputed routes inside a floor and the links between floors
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct LayerGraph {
+    nodes: Vec<(LayerId, StructureId, Role)>,
+    index: HashMap<(LayerId, StructureId, Role), usize>,
+    edges: Vec<Vec<Edge>>,
+    reversed: Vec<Vec<Edge>>, // Same edges walked backwards, for egress
+    closed: HashSet<(LayerId, StructureId)>, // Gates and connections shut by incidents
+}
+
+impl LayerGraph {
+    pub fn node(&mut self, layer: LayerId, structure: StructureId, role: Role) -> usize {
+        let key = (layer, structure, role);
+
+        match self.index.get(&key) {
+            Some(idx) => *idx,
+            None => {
+                self.nodes.push(key);
+                self.edges.push(Vec::new());
+                self.reversed.push(Vec::new());
+                self.index.insert(key, self.nodes.len() - 1);
+
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    // Walking route inside a floor between the given structures, cost is the length of the shortest path
+    pub fn add_route(
+        &mut self,
+        layer: LayerId,
+        (origin, destination): (StructureId, StructureId),
+        (from, to): (Role, Role),
+        route: &Route,
+    ) {
+        let from = self.node(layer, origin, from);
+        let to = self.node(layer, destination, to);
+
+        self.add_edge(from, to, route.cost(), None);
+    }
+
+    fn add_edge(&mut self, from: usize, to: usize, cost: u64, link: Option<ConnectionKind>) {
+        self.edges[from].push(Edge { to, cost, link });
+        self.reversed[to].push(Edge {
+            to: from,
+            cost,
+            link,
+        });
+    }
+
+    // Connection between an exit and the entry it lands on
+    pub fn add_link(
+        &mut self,
+        (layer, exit): (LayerId, StructureId),
+        (destination_layer, entry): (LayerId, StructureId),
+        kind: Option<ConnectionKind>,
+        cost: u64,
+    ) {
+        let from = self.node(layer, exit, Role::Exit);
+        let to = self.node(destination_layer, entry, Role::Entry);
+
+        self.add_edge(from, to, cost, kind);
+    }
+
+    // Elevators are ridden past a floor: their entry on it leads to their exit without getting off
+    pub fn add_rides(&mut self, is_elevator: impl Fn(StructureId) -> bool) {
+        let rides: Vec<(usize, usize)> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, structure, role))| *role == Role::Entry && is_elevator(*structure))
+            .filter_map(|(entry, (layer, structure, _))| {
+                Some((entry, *self.index.get(&(*layer, *structure, Role::Exit))?))
+            })
+            .collect();
+
+        rides.into_iter().for_each(|(entry, exit)| {
+            self.add_edge(entry, exit, 0, Some(ConnectionKind::Elevator))
+        });
+    }
+
+    // Walking route changed or removed by an incident
+    pub fn update_route(&mut self, update: &RouteUpdate) {
+        let (origin, destination) = update.ends;
+        let (from, to) = update.roles;
+
+        let (from, to) = match (
+            self.index.get(&(update.layer, origin, from)),
+            self.index.get(&(update.layer, destination, to)),
+        ) {
+            (Some(from), Some(to)) => (*from, *to),
+            _ => return,
+        };
+
+        let walking = |edge: &Edge, target: usize| edge.to == target && edge.link.is_none();
+
+        match update.cost {
+            Some(cost) => {
+                self.edges[from]
+                    .iter_mut()
+                    .filter(|edge| walking(edge, to))
+                    .for_each(|edge| edge.cost = cost);
+                self.reversed[to]
+                    .iter_mut()
+                    .filter(|edge| walking(edge, from))
+                    .for_each(|edge| edge.cost = cost);
+            }
+            None => {
+                self.edges[from].retain(|edge| !walking(edge, to));
+                self.reversed[to].retain(|edge| !walking(edge, from));
+            }
+        }
+    }
+
+    // No trip goes through the structure anymore
+    pub fn close(&mut self, layer: LayerId, structure: StructureId) {
+        self.closed.insert((layer, structure));
+    }
+
+    pub fn is_closed(&self, layer: LayerId, structure: StructureId) -> bool {
+        self.closed.contains(&(layer, structure))
+    }
+
+    fn open(&self, node: usize) -> bool {
+        let (layer, structure, _) = self.nodes[node];
+        !self.is_closed(layer, structure)
+    }
+
+    // Leg still walkable: both ends open and a walking route between them
+    pub fn walkable(&self, leg: &Leg) -> bool {
+        let nodes = |structure: StructureId| {
+            [Role::Gate, Role::Entry, Role::Exit, Role::Mouth]
+                .into_iter()
+                .filter_map(move |role| self.index.get(&(leg.layer, structure, role)).copied())
+        };
+
+        if self.is_closed(leg.layer, leg.entry) || self.is_closed(leg.layer, leg.target) {
+            return false;
+        }
+
+        // Egress legs walk their routes backwards
+        let linked = |from: usize, to: usize| {
+            self.edges[from]
+                .iter()
+                .any(|edge| edge.to == to && edge.link.is_none())
+        };
+
+        nodes(leg.entry)
+            .any(|from| nodes(leg.target).any(|to| linked(from, to) || linked(to, from)))
+    }
+
+    // Dijkstra from the origins (node and starting cost) to the closest node meeting the goal,
+    // step-free trips avoid stairs and escalators if possible. Closed nodes are never walked into
+    fn shortest(
+        &self,
+        origins: &[(usize, u64)],
+        goal: impl Fn(usize) -> bool,
+        step_free: bool,
+        backwards: bool,
+    ) -> Option<Vec<usize>> {
+        let edges = match backwards {
+            true => &self.reversed,
+            false => &self.edges,
+        };
+
+        let mut dist = vec![u64::MAX; self.nodes.len()];
+        let mut previous = vec![usize::MAX; self.nodes.len()];
+        let mut heap = BinaryHeap::new();
+
+        origins.iter().for_each(|(origin, cost)| {
+            if *cost < dist[*origin] {
+                dist[*origin] = *cost;
+                heap.push(Reverse((*cost, *origin)));
+            }
+        });
+
+        while let Some(Reverse((cost, node))) = heap.pop() {
+            if goal(node) {
+                // Backtracking from destination
+                let mut path = vec![node];
+
+                while previous[*path.last().unwrap()] != usize::MAX {
+                    path.push(previous[*path.last().unwrap()]);
+                }
+
+                return Some(path.into_iter().rev().collect());
+            }
+
+            if cost > dist[node] {
+                continue;
+            }
+
+            edges[node]
+                .iter()
+                .filter(|edge| self.open(edge.to))
+                .for_each(|edge| {
+                    let penalty = match (step_free, edge.link) {
+                        (true, Some(kind)) if !kind.is_accessible() => STEP_FREE_PENALTY,
+                        _ => 0,
+                    };
+
+                    let new_cost = cost + edge.cost + penalty;
+
+                    if new_cost < dist[edge.to] {
+                        dist[edge.to] = new_cost;
+                        previous[edge.to] = node;
+                        heap.push(Reverse((new_cost, edge.to)));
+                    }
+                });
+        }
+
+        // Goal not reachable
+        None
+    }
+
+    /// Trip from a gate to a mouth: first exit to walk to and the legs on every other floor
+    pub fn plan(
+        &self,
+        (gate_layer, gate): (LayerId, StructureId),
+        (mouth_layer, mouth): (LayerId, StructureId),
+        step_free: bool,
+    ) -> Option<(StructureId, VecDeque<Leg>)> {
+        let origin = self.index.get(&(gate_layer, gate, Role::Gate))?;
+        let destination = self.index.get(&(mouth_layer, mouth, Role::Mouth))?;
+
+        // gate, exit, (entry, exit)*, entry, mouth
+        let path = self.shortest(
+            &[(*origin, 0)],
+            |node| node == *destination,
+            step_free,
+            false,
+        )?;
+
+        self.legs(&path)
+    }
+
+    /// Trip from a mouth to the closest gate, walking the building graph backwards
+    pub fn egress(
+        &self,
+        (layer, mouth): (LayerId, StructureId),
+        step_free: bool,
+    ) -> Option<(StructureId, VecDeque<Leg>)> {
+        let origin = self.index.get(&(layer, mouth, Role::Mouth))?;
+
+        // mouth, entry, (exit, entry)*, exit, gate
+        let path = self.shortest(
+            &[(*origin, 0)],
+            |node| self.nodes[node].2 == Role::Gate,
+            step_free,
+            true,
+        )?;
+
+        self.legs(&path)
+    }
+
+    /// New trip of an agent somewhere on a floor, walking costs to its structures are given by
+    /// distance. Towards the mouth through exits, or to the closest gate when leaving
+    pub fn replan(
+        &self,
+        layer: LayerId,
+        distance: impl Fn(StructureId) -> u64,
+        mouth: Option<(LayerId, StructureId)>,
+        step_free: bool,
+    ) -> Option<(StructureId, VecDeque<Leg>)> {
+        let roles = match mouth {
+            Some(_) => [Role::Exit, Role::Mouth],
+            None => [Role::Entry, Role::Gate],
+        };
+
+        let origins: Vec<(usize, u64)> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(node, (l, _, role))| *l == layer && roles.contains(role) && self.open(*node))
+            .map(|(node, (_, structure, _))| (node, distance(*structure)))
+            .collect();
+
+        let path = match mouth {
+            Some((mouth_layer, mouth)) => {
+                let destination = self.index.get(&(mouth_layer, mouth, Role::Mouth))?;
+                self.shortest(&origins, |node| node == *destination, step_free, false)?
+            }
+            None => self.shortest(
+                &origins,
+                |node| self.nodes[node].2 == Role::Gate,
+                step_free,
+                true,
+            )?,
+        };
+
+        // The first node is walked to on this floor
+        Some((self.nodes[path[0]].1, self.chunk(&path[1..])))
+    }
+
+    // First structure to walk to and the legs on every other floor of a trip
+    fn legs(&self, path: &[usize]) -> Option<(StructureId, VecDeque<Leg>)> {
+        let target = self.nodes[*path.get(1)?].1;
+
+        Some((target, self.chunk(&path[2..])))
+    }
+
+    // Pairs of (entry, target) nodes, floors an elevator rides past are no legs
+    fn chunk(&self, path: &[usize]) -> VecDeque<Leg> {
+        path.chunks_exact(2)
+            .map(|leg| Leg {
+                layer: self.nodes[leg[0]].0,
+                entry: self.nodes[leg[0]].1,
+                target: self.nodes[leg[1]].1,
+            })
+            .filter(|leg| leg.entry != leg.target)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Route with a single path of the given length
+    fn route(cost: usize) -> Route {
+        Route {
+            paths: vec![(0..cost).collect()],
+            ..Default::default()
+        }
+    }
+
+    fn leg(leg: &Leg) -> (LayerId, StructureId, StructureId) {
+        (leg.layer, leg.entry, leg.target)
+    }
+
+    // Floor 0: gate 1, mouth 2, stair exit 3 and a farther ramp exit 6.
+    // Floor 1: stair entry 4 and ramp entry 7, both leading to mouth 5
+    fn building() -> LayerGraph {
+        let mut graph = LayerGraph::default();
+
+        graph.add_route(0, (1, 2), (Role::Gate, Role::Mouth), &route(10));
+        graph.add_route(0, (1, 3), (Role::Gate, Role::Exit), &route(5));
+        graph.add_route(0, (1, 6), (Role::Gate, Role::Exit), &route(8));
+        graph.add_link((0, 3), (1, 4), Some(ConnectionKind::Stair), 2);
+        graph.add_link((0, 6), (1, 7), Some(ConnectionKind::Ramp), 2);
+        graph.add_route(1, (4, 5), (Role::Entry, Role::Mouth), &route(5));
+        graph.add_route(1, (7, 5), (Role::Entry, Role::Mouth), &route(5));
+
+        graph
+    }
+
+    #[test]
+    fn plan_takes_the_shortest_trip() {
+        let (target, legs) = building().plan((0, 1), (1, 5), false).unwrap();
+
+        assert_eq!(target, 3);
+        assert_eq!(legs.iter().map(leg).collect::<Vec<_>>(), [(1, 4, 5)]);
+    }
+
+    #[test]
+    fn plan_on_the_same_floor_has_no_legs() {
+        let (target, legs) = building().plan((0, 1), (0, 2), false).unwrap();
+
+        assert_eq!(target, 2);
+        assert!(legs.is_empty());
+    }
+
+    #[test]
+    fn step_free_plan_avoids_stairs() {
+        let (target, legs) = building().plan((0, 1), (1, 5), true).unwrap();
+
+        assert_eq!(target, 6);
+        assert_eq!(legs.iter().map(leg).collect::<Vec<_>>(), [(1, 7, 5)]);
+    }
+
+    #[test]
+    fn plan_avoids_closed_connections() {
+        let mut graph = building();
+        graph.close(0, 3);
+
+        assert_eq!(graph.plan((0, 1), (1, 5), false).unwrap().0, 6);
+
+        graph.close(0, 6);
+
+        assert!(graph.plan((0, 1), (1, 5), false).is_none());
+    }
+
+    #[test]
+    fn plan_starts_at_a_closed_gate() {
+        let mut graph = building();
+        graph.close(0, 1);
+
+        assert_eq!(graph.plan((0, 1), (1, 5), false).unwrap().0, 3);
+    }
+
+    #[test]
+    fn egress_walks_back_to_a_gate() {
+        let (target, legs) = building().egress((1, 5), false).unwrap();
+
+        assert_eq!(target, 4);
+        assert_eq!(legs.iter().map(leg).collect::<Vec<_>>(), [(0, 3, 1)]);
+    }
+
+    #[test]
+    fn egress_without_open_gates_fails() {
+        let mut graph = building();
+        graph.close(0, 1);
+
+        assert!(graph.egress((1, 5), false).is_none());
+    }
+
+    #[test]
+    fn replan_starts_from_the_closest_structure() {
+        let distance = |structure: StructureId| match structure {
+            6 => 1,
+            _ => 50,
+        };
+
+        let (target, legs) = building().replan(0, distance, Some((1, 5)), false).unwrap();
+
+        assert_eq!(target, 6);
+        assert_eq!(legs.iter().map(leg).collect::<Vec<_>>(), [(1, 7, 5)]);
+    }
+
+    #[test]
+    fn replan_egress_skips_closed_entries() {
+        let distance = |structure: StructureId| match structure {
+            4 => 1,
+            _ => 50,
+        };
+
+        let mut graph = building();
+        assert_eq!(graph.replan(1, distance, None, false).unwrap().0, 4);
+
+        graph.close(1, 4);
+        let (target, legs) = graph.replan(1, distance, None, false).unwrap();
+
+        assert_eq!(target, 7);
+        assert_eq!(legs.iter().map(leg).collect::<Vec<_>>(), [(0, 6, 1)]);
+    }
+
+    #[test]
+    fn elevators_ride_past_floors() {
+        // Elevator 10, 11 and 12 on floors 0, 1 and 2
+        let mut graph = LayerGraph::default();
+
+        graph.add_route(0, (1, 10), (Role::Gate, Role::Exit), &route(3));
+        graph.add_link((0, 10), (1, 11), Some(ConnectionKind::Elevator), 5);
+        graph.add_link((1, 11), (2, 12), Some(ConnectionKind::Elevator), 5);
+        graph.add_route(2, (12, 20), (Role::Entry, Role::Mouth), &route(3));
+
+        assert!(graph.plan((0, 1), (2, 20), false).is_none());
+
+        graph.add_rides(|structure| (10..=12).contains(&structure));
+        let (target, legs) = graph.plan((0, 1), (2, 20), false).unwrap();
+
+        assert_eq!(target, 10);
+        assert_eq!(legs.iter().map(leg).collect::<Vec<_>>(), [(2, 12, 20)]);
+    }
+}
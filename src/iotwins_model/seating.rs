@@ -0,0 +1,168 @@
+use rand::{prelude::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    engine::{logging::warn, matrix::Matrix, path_finding},
+    iotwins_model::structures::Structure,
+};
+
+#[derive(Deserialize)]
+struct RawSeats {
+    mouth: u16,
+    layer: String,
+    block: String,
+    x: usize,
+    y: usize,
+    seats: u32,
+}
+
+// Seats of a stand reached through a mouth
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Block {
+    pub name: String,
+    pub mouth: u16,
+    pub seats: u32,
+    pub claimed: u32,        // Seats given to agents, kept while they are away
+    aisles: Vec<Vec<usize>>, // Path from the mouth to every access cell of the block
+}
+
+// Agents sitting in a block at a step
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sample {
+    pub step: u32,
+    pub block: u32,
+    pub claimed: u32,
+    pub occupied: u32,
+}
+
+/// Seat blocks of a floor. Agents reaching their mouth claim a seat in the first block with room
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Seating {
+    pub blocks: Vec<Block>,
+    pub turned_away: usize, // Agents staying at their mouth, every block full
+    pub samples: Vec<Sample>,
+}
+
+impl Seating {
+    // Blocks of the floor's mouths, rows of the same block add access cells and seats
+    pub fn load(
+        path: &str,
+        layer: &str,
+        mouths: &HashMap<u16, Structure>,
+        gt: &Matrix<u8>,
+    ) -> Seating {
+        let mut reader = csv::Reader::from_path(path).expect("[ERROR] Seats file not found");
+        let mut blocks: Vec<Block> = Vec::new();
+
+        for result in reader.deserialize() {
+            let record: RawSeats = result.expect("[ERROR] Incorrect seats format");
+
+            if record.layer != *layer {
+                continue;
+            }
+
+            let mouth = match mouths.get(&record.mouth) {
+                Some(mouth) => mouth,
+                None => {
+                    warn!(
+                        Loaders,
+                        "Seat block {} of unknown mouth {} on {layer}", record.block, record.mouth
+                    );
+                    continue;
+                }
+            };
+
+            // Aisle from the mouth to the block
+            let cell = 627 * record.x + record.y;
+            let aisle = match path_finding::a_star(gt, mouth.location[0], cell) {
+                Some(aisle) => aisle,
+                None => {
+                    warn!(
+                        Loaders,
+                        "Seat block {} unreachable from mouth {} on {layer}",
+                        record.block,
+                        record.mouth
+                    );
+                    continue;
+                }
+            };
+
+            match blocks
+                .iter_mut()
+                .find(|block| block.mouth == record.mouth && block.name == record.block)
+            {
+                Some(block) => {
+                    block.seats += record.seats;
+                    block.aisles.push(aisle);
+                }
+                None => blocks.push(Block {
+                    name: record.block,
+                    mouth: record.mouth,
+                    seats: record.seats,
+                    claimed: 0,
+                    aisles: vec![aisle],
+                }),
+            }
+        }
+
+        Seating {
+            blocks,
+            ..Default::default()
+        }
+    }
+
+    // Seat in the first block of the mouth with room. Mouths without blocks keep their agents
+    pub fn claim(&mut self, mouth: u16) -> Option<u32> {
+        let mut blocks = self
+            .blocks
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, block)| block.mouth == mouth && !block.aisles.is_empty())
+            .peekable();
+
+        blocks.peek()?;
+
+        match blocks.find(|(_, block)| block.claimed < block.seats) {
+            Some((idx, block)) => {
+                block.claimed += 1;
+                Some(idx as u32)
+            }
+            None => {
+                self.turned_away += 1;
+                None
+            }
+        }
+    }
+
+    // Walk from the mouth to one of the block's access cells, None once incidents closed them all
+    pub fn aisle(&self, block: u32, rng: &mut impl Rng) -> Option<Vec<usize>> {
+        self.blocks[block as usize].aisles.choose(rng).cloned()
+    }
+
+    // Aisles through closed cells are searched again, blocks without any take no one else
+    pub fn avoid(&mut self, gt: &Matrix<u8>, closed: &HashSet<usize>) {
+        self.blocks.iter_mut().for_each(|block| {
+            block.aisles = std::mem::take(&mut block.aisles)
+                .into_iter()
+                .filter_map(
+                    |aisle| match aisle.iter().any(|cell| closed.contains(cell)) {
+                        true => path_finding::a_star(gt, aisle[0], *aisle.last()?),
+                        false => Some(aisle),
+                    },
+                )
+                .collect();
+        });
+    }
+
+    // Occupancy of every block, agents seated elsewhere are not counted
+    pub fn sample(&mut self, step: u32, occupied: &HashMap<u32, u32>) {
+        self.samples
+            .extend(self.blocks.iter().enumerate().map(|(idx, block)| Sample {
+                step,
+                block: idx as u32,
+                claimed: block.claimed,
+                occupied: *occupied.get(&(idx as u32)).unwrap_or(&0),
+            }));
+    }
+}
@@ -0,0 +1,170 @@
+use rand::{
+    distributions::{Distribution, Uniform},
+    Rng,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use crate::iotwins_model::{
+    agent::{Agent, Walk},
+    config::{ServiceDistribution, Services},
+    structures::{BAR, KIOSK, RESTROOM},
+    turnstiles::ServiceTime,
+};
+
+// Kind of point of interest an agent may visit on its way
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub enum Service {
+    Bar,
+    Restroom,
+    Kiosk,
+}
+
+impl Service {
+    pub fn code(&self) -> u8 {
+        match self {
+            Service::Bar => BAR,
+            Service::Restroom => RESTROOM,
+            Service::Kiosk => KIOSK,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Service> {
+        match code {
+            BAR => Some(Service::Bar),
+            RESTROOM => Some(Service::Restroom),
+            KIOSK => Some(Service::Kiosk),
+            _ => None,
+        }
+    }
+}
+
+impl Services {
+    // Probabilistic agenda, factor scales every visit probability (half-time rush)
+    pub fn agenda(&self, factor: f64, rng: &mut impl Rng) -> VecDeque<Service> {
+        let between = Uniform::from(0_f64..1_f64);
+
+        [
+            (Service::Restroom, self.restroom_probability),
+            (Service::Bar, self.bar_probability),
+            (Service::Kiosk, self.kiosk_probability),
+        ]
+        .into_iter()
+        .filter(|(_, probability)| between.sample(rng) < probability * factor)
+        .map(|(service, _)| service)
+        .collect()
+    }
+
+    // Agents served at once and service time (seconds) of a service point
+    fn parameters(&self, service: Service) -> (usize, f64) {
+        match service {
+            Service::Bar => (self.bar_capacity, self.bar_service),
+            Service::Restroom => (self.restroom_capacity, self.restroom_service),
+            Service::Kiosk => (self.kiosk_capacity, self.kiosk_service),
+        }
+    }
+}
+
+/// Bar, restroom or kiosk. Agents queue until one of its places is free
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ServicePoint {
+    pub capacity: usize,
+    pub time: ServiceTime,
+    queue: VecDeque<(usize, Agent, Walk, Vec<usize>)>, // Arrival clock, agent, its walk and path
+    serving: Vec<(usize, usize, Agent, Walk, Vec<usize>)>, // Completion clock, arrival clock, agent, walk and path
+    clock: usize,
+    pub visits: usize,
+}
+
+impl ServicePoint {
+    pub fn new(service: Service, services: &Services, seconds_per_step: f64) -> ServicePoint {
+        let (capacity, seconds) = services.parameters(service);
+
+        ServicePoint {
+            capacity,
+            time: ServiceTime {
+                distribution: ServiceDistribution::Exponential,
+                mean: seconds / seconds_per_step,
+                deviation: 0.0,
+            },
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, agent: Agent, walk: Walk, path: Vec<usize>) {
+        self.queue.push_back((self.clock, agent, walk, path));
+    }
+
+    pub fn queue_length(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn occupancy(&self) -> usize {
+        self.serving.len()
+    }
+
+    pub fn agents(&self) -> impl Iterator<Item = &Agent> {
+        self.queue
+            .iter()
+            .map(|(_, agent, _, _)| agent)
+            .chain(self.serving.iter().map(|(_, _, agent, _, _)| agent))
+    }
+
+    // Agents queued or served stay while keep holds, the rest leave the point
+    pub fn retain(
+        &mut self,
+        mut keep: impl FnMut(&mut Agent, &mut Walk, &mut Vec<usize>) -> bool,
+    ) -> Vec<Agent> {
+        let mut removed = Vec::new();
+        let mut remove = |agent: &mut Agent, walk: &Walk| {
+            walk.end(agent);
+            removed.push(std::mem::take(agent));
+            false
+        };
+
+        self.queue
+            .retain_mut(|(_, agent, walk, path)| keep(agent, walk, path) || remove(agent, walk));
+
+        self.serving
+            .retain_mut(|(_, _, agent, walk, path)| keep(agent, walk, path) || remove(agent, walk));
+
+        removed
+    }
+
+    // One step of the service point, returns served agents, their walks and paths and the steps they spent in it
+    pub fn advance(&mut self, rng: &mut impl Rng) -> Vec<(Agent, Walk, Vec<usize>, usize)> {
+        self.clock += 1;
+
+        let clock = self.clock;
+        let mut served = Vec::new();
+
+        let (done, busy): (Vec<_>, Vec<_>) = self
+            .serving
+            .drain(..)
+            .partition(|(end, _, _, _, _)| *end <= clock);
+
+        self.serving = busy;
+
+        done.into_iter().for_each(|(_, since, agent, walk, path)| {
+            served.push((agent, walk, path, clock - since))
+        });
+
+        while self.serving.len() < self.capacity {
+            match self.queue.pop_front() {
+                Some((since, agent, walk, path)) => {
+                    self.visits += 1;
+                    self.serving.push((
+                        clock + self.time.sample(rng) as usize,
+                        since,
+                        agent,
+                        walk,
+                        path,
+                    ));
+                }
+                None => break,
+            }
+        }
+
+        served
+    }
+}
@@ -1,192 +1,1006 @@
 use crate::{
-    engine::{matrix::Matrix, path_finding},
+    config::configuration::Parameters,
+    engine::{
+        logging::warn,
+        matrix::{Matrix, Position},
+        path_finding,
+    },
     iotwins_model::{
-        agent::Agent,
-        routes::{find_route, Route},
-        structures::{generate_structures, load_mouths, Structure},
+        agent::{Agent, Walk},
+        config::{ConflictRule, Conflicts},
+        crowd::Crowd,
+        incidents::{Detour, Impact, Rerouting},
+        legend::{codification, map_legend, Legend},
+        lifecycle::{Fate, Reason},
+        registry::{LayerId, Registry, StructureId},
+        routes::{avoid_closed, find_route, LayerGraph, Role, Route, RouteUpdate},
+        seating::Seating,
+        services::{Service, ServicePoint},
+        structures::{
+            generate_structures, load_mouths, Structure, CONNECTIONS, ENTRIES, EXITS, SERVICES,
+        },
+        transit::{Link, Transit},
     },
 };
-use dashmap::DashMap;
 use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
-use rand::{distributions::Uniform, prelude::SliceRandom};
+use rand::{distributions::Uniform, prelude::SliceRandom, rngs::StdRng, Rng, SeedableRng};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+// Side of the tiles conflicts are resolved in, tiles of the same colour are never adjacent
+const CONFLICT_TILE: usize = 16;
+
+// Random draws of a step, from the configured seed, the step and a key (agent id or cell)
+pub fn draws(seed: u64, step: u32, key: usize) -> StdRng {
+    StdRng::seed_from_u64(seed.rotate_left(48) ^ ((step as u64) << 32) ^ key as u64)
+}
+
+// Cells searched around a taken arrival cell for a free one to start from
+const SPREAD_LIMIT: usize = 4096;
+
+// Decision over a movement
+#[derive(Clone, Copy)]
+enum Outcome {
+    Moved,
+    Wait(usize), // Back to the cell the agent came from
+    Sidestep(usize),
+}
 
 #[derive(Clone, Default, Serialize, Deserialize)]
 pub struct Floor {
+    pub layer: LayerId,
     pub ground_truth: Matrix<u8>,
     pub structures: HashMap<u8, HashSet<Structure>>, // Mapping Position -> matrix by type of structure. MAYBE NOT NEEDED?
-    pub structures_buffer: HashMap<Structure, VecDeque<Agent>>,
-    pub structures_paths: HashSet<Route>,
-    pub mouths: HashMap<u16, Structure>, // Agent destinations
-    pub mouths_paths: HashMap<u16, HashSet<Route>>, // From down-stairs -> mouths (grandstands)
-    pub agents: Vec<Agent>,              // All agents in floor
-    pub agents_paths: DashMap<usize, Vec<usize>>, // Path to follow by every agent in layer (Only own agent modifies this, analytics)
+    pub structures_buffer: HashMap<StructureId, Transit>, // Agents travelling towards each connection
+    pub structures_paths: HashMap<(StructureId, StructureId), Route>, // Entry -> exit
+    pub mouths: HashMap<u16, StructureId>,                // Agent destinations
+    pub mouths_paths: HashMap<StructureId, HashMap<StructureId, Route>>, // Mouth -> down-stair -> route to the mouth (grandstands)
+    pub crowd: Crowd, // All agents in floor with their paths
+    pub services: HashMap<StructureId, ServicePoint>, // Bars, restrooms and kiosks
+    pub seating: Seating, // Seat blocks behind the mouths
+    pub group_distance: i32, // Squared distance a follower may keep from its leader
+    pub conflicts: Conflicts,
+    pub seed: u64,
+    pub step: u32,
+    pub conflicts_time: Duration, // Spent resolving conflicts on the last step
+    pub lost: Vec<(Agent, Reason)>, // Agents dropped since the world last collected them
 }
 
 impl Floor {
-    pub fn create_floor(path: String, name: String) -> Floor {
-        let ground_truth = Floor::ground_truth(&Matrix::load_layer(&path));
+    pub fn create_floor(
+        path: String,
+        name: String,
+        configuration: &Parameters,
+        mouths_info: &str,
+        seats_info: Option<&str>,
+        registry: &mut Registry,
+    ) -> Floor {
+        let layer = registry.intern_layer(&name);
+
+        let ground_truth = Floor::ground_truth(&path, &name, &configuration.codification);
         let structures = generate_structures(&ground_truth);
-        let mouths = load_mouths(&name);
+        let mouths = load_mouths(mouths_info, &name);
+        let seating = seats_info
+            .map(|path| Seating::load(path, &name, &mouths, &ground_truth))
+            .unwrap_or_default();
+
+        let structures_paths = HashMap::from_iter(
+            Floor::stairs_paths(&ground_truth, &structures, &name)
+                .into_iter()
+                .map(|route| {
+                    let origin = registry.intern(layer, &route.origin);
+                    let destination = registry.intern(layer, &route.destination);
+
+                    ((origin, destination), route)
+                }),
+        );
+
+        let mouths_paths = HashMap::from_iter(
+            Floor::mouth_paths(&ground_truth, &structures, &mouths, &name)
+                .into_iter()
+                .map(|(mouth, routes)| {
+                    let routes = HashMap::from_iter(
+                        routes
+                            .into_iter()
+                            .map(|route| (registry.intern(layer, &route.origin), route)),
+                    );
+
+                    (registry.intern(layer, &mouths[&mouth]), routes)
+                }),
+        );
 
         Floor {
-            structures_paths: Floor::stairs_paths(&ground_truth, &structures, &name),
-            mouths_paths: Floor::mouth_paths(&ground_truth, &structures, &mouths, &name),
-            mouths,
-            // Down-stairs get their buffer for arriving agents from other layers
-            structures_buffer: HashMap::from_iter(
-                structures
-                    .get(&10)
-                    .unwrap()
+            layer,
+            structures_paths,
+            mouths_paths,
+            mouths: HashMap::from_iter(
+                mouths
                     .iter()
-                    .map(|structure| (structure.to_owned(), VecDeque::new())),
+                    .map(|(mouth, structure)| (*mouth, registry.intern(layer, structure))),
+            ),
+            // Connections get their buffer for arriving agents from other layers (exits too, for egress)
+            structures_buffer: HashMap::from_iter(
+                Floor::filter_structures(&structures, &CONNECTIONS).map(|structure| {
+                    let link = Link::new(structure.kind().unwrap(), &configuration.connections);
+                    (registry.intern(layer, structure), Transit::new(link))
+                }),
             ),
+            services: HashMap::from_iter(Floor::filter_structures(&structures, &SERVICES).map(
+                |structure| {
+                    let service = Service::from_code(structure.code).unwrap();
+                    let point = ServicePoint::new(
+                        service,
+                        &configuration.services,
+                        configuration.seconds_per_step(),
+                    );
+
+                    (registry.intern(layer, structure), point)
+                },
+            )),
+            group_distance: configuration.agent_data.max_distance_b_agents.pow(2) as i32,
+            conflicts: configuration.conflicts.to_owned(),
+            seed: configuration.seed.value,
+            seating,
             structures,
             ground_truth,
             ..Default::default()
         }
     }
 
+    // Agents walking, inside or queued at connections and queued or served at service points
+    pub fn population(&self) -> (usize, usize, usize) {
+        let in_connections = self
+            .structures_buffer
+            .values()
+            .map(|transit| transit.occupancy() + transit.queue_length())
+            .sum();
+
+        let at_services = self
+            .services
+            .values()
+            .map(|point| point.occupancy() + point.queue_length())
+            .sum();
+
+        (self.crowd.agents.len(), in_connections, at_services)
+    }
+
+    // Every agent on the floor with where it is
+    pub fn census(&self) -> impl Iterator<Item = (&Agent, Fate)> {
+        let walking = self.crowd.agents.iter().map(|ag| (ag, Fate::Walking));
+
+        let in_connections = self
+            .structures_buffer
+            .values()
+            .flat_map(|transit| transit.agents())
+            .map(|ag| (ag, Fate::InConnection));
+
+        let at_services = self
+            .services
+            .values()
+            .flat_map(|point| point.agents())
+            .map(|ag| (ag, Fate::AtService));
+
+        walking.chain(in_connections).chain(at_services)
+    }
+
     pub fn insert_agents(
         &mut self,
         agents: &[Agent],
         route: Route, // Agent arrival on gate
+        registry: &Registry,
     ) -> usize {
-        let agents_paths = agents.iter().map(|agent| (agent.id, route.get_path()));
+        // Followers walk the same path as their leader
+        let mut shared: HashMap<usize, (Vec<usize>, Agent)> = HashMap::new();
+
+        agents.iter().for_each(|agent| {
+            let mut agent = agent.to_owned();
+
+            let path = match agent.leader.and_then(|leader| shared.get(&leader)) {
+                Some((path, leader)) => {
+                    agent.agenda = leader.agenda.to_owned();
+                    agent.visit = leader.visit.to_owned();
+
+                    path.to_vec()
+                }
+                None => {
+                    let mut rng = draws(self.seed, self.step, agent.id);
+                    let mut path = route.get_path(&mut rng);
+
+                    // Services are visited on the last floor of the trip
+                    if agent.plan.is_empty() {
+                        path = Floor::detour(
+                            &self.ground_truth,
+                            registry,
+                            &self.services,
+                            &mut agent,
+                            path,
+                            &mut rng,
+                        );
+                    }
+
+                    shared.insert(agent.id, (path.to_vec(), agent.to_owned()));
+                    path
+                }
+            };
 
-        self.agents_paths.extend(agents_paths);
-        self.agents.extend(agents.to_vec());
+            // One agent per cell, batches and groups spread around the arrival cell
+            self.place(agent, path);
+        });
 
         agents.len()
     }
 
-    pub fn swap_buffer(&mut self, agent: &mut Agent, stair: &Structure) {
-        match self.structures_buffer.get_mut(stair) {
-            Some(buffer) => buffer.push_back(std::mem::take(agent)),
+    // Agent joins the floor at the start of its path, or on the closest free cell if it is taken
+    fn place(&mut self, agent: Agent, path: Vec<usize>) {
+        let walk = Walk::start(&agent, &path);
+        self.rejoin(agent, walk, path);
+    }
+
+    // Agent goes on from the current step of its walk, or from the closest free cell if another
+    // agent stands there (it stepped in while the agent was at a service point)
+    fn rejoin(&mut self, mut agent: Agent, mut walk: Walk, mut path: Vec<usize>) {
+        let rest = path.split_off(walk.steps.min(path.len()));
+        let (rest, offset) = Floor::free_start(&self.ground_truth, rest, self.crowd.taken());
+
+        if let Some((idx, _)) = agent.visit.as_mut() {
+            if *idx >= walk.steps {
+                *idx += offset;
+            }
+        }
+
+        path.extend(rest);
+        walk.next_step = *path.get(walk.steps + 1).unwrap_or(&0);
+
+        self.crowd.push(agent, walk, path);
+    }
+
+    // Path from the closest free cell to a taken start, walking back onto the path.
+    // Returns the path and the number of cells added in front of it
+    fn free_start(
+        gt: &Matrix<u8>,
+        path: Vec<usize>,
+        taken: &HashSet<usize>,
+    ) -> (Vec<usize>, usize) {
+        let start = match path.first() {
+            Some(start) if taken.contains(start) => *start,
+            _ => return (path, 0),
+        };
+
+        let mut previous: HashMap<usize, usize> = HashMap::from([(start, start)]);
+        let mut frontier = VecDeque::from([start]);
+
+        while let Some(cell) = frontier.pop_front() {
+            if !taken.contains(&cell) {
+                let mut prefix = Vec::new();
+                let mut current = cell;
+
+                while current != start {
+                    prefix.push(current);
+                    current = previous[&current];
+                }
+
+                let offset = prefix.len();
+                prefix.extend(path);
+
+                return (prefix, offset);
+            }
+
+            if previous.len() > SPREAD_LIMIT {
+                break;
+            }
+
+            path_finding::movements(cell, gt)
+                .into_iter()
+                .for_each(|next| {
+                    if let Entry::Vacant(entry) = previous.entry(next) {
+                        entry.insert(cell);
+                        frontier.push_back(next);
+                    }
+                });
+        }
+
+        // Crowded surroundings, the agent waits its turn on the arrival cell
+        (path, 0)
+    }
+
+    // Single agent starting somewhere on the floor (leaving its seat)
+    pub fn insert_path(&mut self, mut agent: Agent, mut path: Vec<usize>, registry: &Registry) {
+        if agent.plan.is_empty() {
+            let mut rng = draws(self.seed, self.step, agent.id);
+
+            path = Floor::detour(
+                &self.ground_truth,
+                registry,
+                &self.services,
+                &mut agent,
+                path,
+                &mut rng,
+            );
+        }
+
+        self.place(agent, path);
+    }
+
+    // Path between two structures of the floor, precomputed routes are walked in either direction
+    pub fn path_between(
+        &self,
+        registry: &Registry,
+        from: StructureId,
+        to: StructureId,
+        rng: &mut impl Rng,
+    ) -> Option<Vec<usize>> {
+        let forward = self
+            .structures_paths
+            .get(&(from, to))
+            .or_else(|| self.mouths_paths.get(&to)?.get(&from));
+
+        if let Some(route) = forward {
+            return Some(route.get_path(rng));
+        }
+
+        let backward = self
+            .structures_paths
+            .get(&(to, from))
+            .or_else(|| self.mouths_paths.get(&from)?.get(&to));
+
+        match backward {
+            Some(route) => Some(route.inverse().get_path(rng)),
+            // Gates and seats have no precomputed route between them
+            None => path_finding::a_star(
+                &self.ground_truth,
+                *registry.structure(from).location.choose(rng)?,
+                *registry.structure(to).location.choose(rng)?,
+            ),
+        }
+    }
+
+    // Path through the closest service point of the next kind on the agent's agenda
+    // Same path if the floor has no such service or it cannot be reached
+    fn detour(
+        gt: &Matrix<u8>,
+        registry: &Registry,
+        services: &HashMap<StructureId, ServicePoint>,
+        agent: &mut Agent,
+        path: Vec<usize>,
+        rng: &mut impl Rng,
+    ) -> Vec<usize> {
+        let (origin, destination) = match (path.first(), path.last()) {
+            (Some(origin), Some(destination)) => (*origin, *destination),
+            _ => return path,
+        };
+
+        let here = Position::new(origin, gt.n_rows);
+
+        // Services missing on the floor are dropped from the agenda
+        while let Some(service) = agent.agenda.pop_front() {
+            let point = match services
+                .keys()
+                .filter(|id| registry.structure(**id).code == service.code())
+                .min_by_key(|id| (registry.structure(**id).position.distance(&here), **id))
+            {
+                Some(point) => *point,
+                None => continue,
+            };
+
+            // Footprints are not walkable, agents are served on a cell next to them
+            let counter: Vec<usize> = registry
+                .structure(point)
+                .location
+                .iter()
+                .flat_map(|cell| path_finding::movements(*cell, gt))
+                .collect();
+
+            let cell = match counter.choose(rng) {
+                Some(cell) => *cell,
+                None => continue,
+            };
+
+            // a_star paths start at their origin and stop right before their destination
+            return match (
+                path_finding::a_star(gt, origin, cell),
+                path_finding::a_star(gt, cell, destination),
+            ) {
+                (Some(mut going), Some(leaving)) => {
+                    agent.visit = Some((going.len(), point));
+
+                    going.extend(leaving);
+                    going.push(destination);
+                    going
+                }
+                _ => path,
+            };
+        }
+
+        path
+    }
+
+    // Agents reaching their service point queue there, served ones carry on where they left
+    fn visit_services(&mut self, registry: &Registry) {
+        let visiting = self
+            .crowd
+            .drain_where(|ag, walk| matches!(&ag.visit, Some((idx, _)) if walk.steps >= *idx));
+
+        visiting.into_iter().for_each(|(mut agent, walk, path)| {
+            let (_, point) = agent.visit.take().unwrap();
+
+            match self.services.get_mut(&point) {
+                Some(service) => service.push(agent, walk, path),
+                None => self.rejoin(agent, walk, path),
+            }
+        });
+
+        // Service points in id order, each with draws of its own
+        let (seed, step) = (self.seed, self.step);
+        let mut points: Vec<(&StructureId, &mut ServicePoint)> = self.services.iter_mut().collect();
+        points.sort_by_key(|(id, _)| **id);
+
+        let served: Vec<(Agent, Walk, Vec<usize>, usize)> = points
+            .into_iter()
+            .flat_map(|(id, service)| service.advance(&mut draws(seed, step, *id as usize)))
+            .collect();
+
+        served
+            .into_iter()
+            .for_each(|(mut agent, mut walk, mut path, spent)| {
+                // Time at the service point is spent in place
+                let position = path[walk.steps];
+                path.splice(walk.steps..walk.steps, vec![position; spent]);
+
+                walk.steps += spent;
+
+                // Next service of the agenda from where the agent stands
+                if !agent.agenda.is_empty() {
+                    let mut rng = draws(self.seed, self.step, agent.id);
+                    let rest = path.split_off(walk.steps);
+                    let rest = Floor::detour(
+                        &self.ground_truth,
+                        registry,
+                        &self.services,
+                        &mut agent,
+                        rest,
+                        &mut rng,
+                    );
+
+                    if let Some((idx, _)) = agent.visit.as_mut() {
+                        *idx += walk.steps;
+                    }
+                    path.extend(rest);
+                }
+
+                self.rejoin(agent, walk, path);
+            });
+    }
+
+    // Structures of the given codes
+    pub fn filter_structures<'a>(
+        structures: &'a HashMap<u8, HashSet<Structure>>,
+        codes: &'a [u8],
+    ) -> impl Iterator<Item = &'a Structure> {
+        codes
+            .iter()
+            .filter_map(|code| structures.get(code))
+            .flatten()
+    }
+
+    // Structure of the given codes covering a cell
+    pub fn structure_at(&self, codes: &[u8], cell: usize) -> Option<&Structure> {
+        codes
+            .iter()
+            .filter_map(|code| self.structures.get(code))
+            .flatten()
+            .find(|structure| structure.location.contains(&cell))
+    }
+
+    // Closed cells become walls, routes and aisles through them are searched again or dropped
+    pub fn close_cells(&mut self, cells: &HashSet<usize>) -> Vec<RouteUpdate> {
+        let layer = self.layer;
+
+        cells
+            .iter()
+            .for_each(|cell| self.ground_truth.data[*cell] = 1);
+
+        let mut updates = avoid_closed(
+            &mut self.structures_paths,
+            &self.ground_truth,
+            cells,
+            |ends, cost| RouteUpdate {
+                layer,
+                ends,
+                roles: (Role::Entry, Role::Exit),
+                cost,
+            },
+        );
+
+        self.mouths_paths.iter_mut().for_each(|(mouth, routes)| {
+            updates.extend(avoid_closed(
+                routes,
+                &self.ground_truth,
+                cells,
+                |entry, cost| RouteUpdate {
+                    layer,
+                    ends: (entry, *mouth),
+                    roles: (Role::Entry, Role::Mouth),
+                    cost,
+                },
+            ));
+        });
+
+        self.seating.avoid(&self.ground_truth, cells);
+
+        updates
+    }
+
+    // Agents walking, at services or arriving through connections get around the closures
+    pub fn reroute(
+        &mut self,
+        blocked: &HashSet<usize>,
+        graph: &LayerGraph,
+        registry: &Registry,
+        mouths: &HashMap<(LayerId, u16), StructureId>,
+    ) -> Impact {
+        let detour = Detour {
+            layer: self.layer,
+            gt: &self.ground_truth,
+            blocked,
+            graph,
+            registry,
+            mouths,
+        };
+
+        let mut impact = Impact::default();
+        let mut lost: HashSet<usize> = HashSet::new();
+
+        let mut count = |agent: &mut Agent, walk: &mut Walk, path: &mut Vec<usize>| {
+            let rerouting = detour.agent(agent, walk, path);
+
+            match rerouting {
+                Rerouting::Unaffected => true,
+                Rerouting::Rerouted(extra) => {
+                    impact.rerouted += 1;
+                    impact.extra_steps += extra;
+                    true
+                }
+                Rerouting::Lost => {
+                    impact.lost += 1;
+                    false
+                }
+            }
+        };
+
+        self.crowd.for_each_path(|agent, walk, path| {
+            if !count(agent, walk, path) {
+                lost.insert(agent.id);
+            }
+        });
+
+        let mut dropped: Vec<Agent> = self
+            .crowd
+            .drain_where(|agent, _| lost.contains(&agent.id))
+            .into_iter()
+            .map(|(agent, _, _)| agent)
+            .collect();
+
+        self.services
+            .values_mut()
+            .for_each(|point| dropped.extend(point.retain(&mut count)));
+
+        // Agents on their way to the floor only need a new trip, paths are given on landing
+        self.structures_buffer
+            .iter_mut()
+            .for_each(|(stair, transit)| {
+                let here = registry.structure(*stair).location[0];
+
+                transit
+                    .waiting
+                    .iter_mut()
+                    .chain(transit.moving.iter_mut().map(|(_, agent)| agent))
+                    .filter(|agent| !agent.egress || !agent.plan.is_empty())
+                    .for_each(|agent| {
+                        if !detour.trip_open(agent) && detour.trip(agent, here).is_some() {
+                            impact.rerouted += 1;
+                        }
+                    });
+            });
+
+        self.lost
+            .extend(dropped.into_iter().map(|agent| (agent, Reason::ClosedOff)));
+
+        impact
+    }
+
+    pub fn swap_buffer(&mut self, agent: &mut Agent, stair: StructureId) {
+        match self.structures_buffer.get_mut(&stair) {
+            Some(transit) => transit.push(std::mem::take(agent)),
             None => {
-                // Do nothing, agent will desappear
-                println!("[WARR] Agent {} deleted", agent.id);
+                // Agent will desappear
+                warn!(Floor @ self.layer, "Agent {} deleted, no buffer for stair {stair}", agent.id);
+                self.lost
+                    .push((std::mem::take(agent), Reason::NoConnectionBuffer));
             }
         }
     }
 
-    fn insert_buffered_agents(&mut self) {
+    fn insert_buffered_agents(&mut self, registry: &Registry) {
+        let mut arrived = Vec::new();
+        let mut egress = Vec::new();
+        let mut lost = Vec::new();
+
         self.structures_buffer
             .iter_mut()
-            .for_each(|(stair, buffer)| {
-                // If there is any agent on hold
-                if let Some(mut agent) = buffer.pop_front() {
-                    let destination = self.mouths.get(&agent.destination).unwrap();
-                    let routes = self.mouths_paths.get(&agent.destination).unwrap();
-
-                    if let Some(route) = routes.get(&Route {
-                        origin: stair.to_owned(),
-                        destination: destination.to_owned(),
-                        ..Default::default()
-                    }) {
-                        let path = route.get_path();
-                        agent.next_step = path[1];
-                        agent.steps = 1;
-
-                        self.agents_paths.insert(agent.id, path);
-                        self.agents.push(agent.to_owned());
+            .for_each(|(stair, transit)| {
+                // Agents reaching the floor this step
+                for mut agent in transit.advance() {
+                    // Leaving agents walk towards the next connection or their gate
+                    if agent.egress {
+                        egress.push((*stair, agent));
+                        continue;
+                    }
+
+                    let route = match agent.plan.is_empty() {
+                        // Last floor of the trip, heading to the mouth
+                        true => self
+                            .mouths
+                            .get(&agent.destination)
+                            .and_then(|mouth| self.mouths_paths.get(mouth)?.get(stair)),
+                        // Crossing the floor towards the next exit
+                        false => self.structures_paths.get(&(*stair, agent.target)),
+                    };
+
+                    if let Some(route) = route {
+                        let mut rng = draws(self.seed, self.step, agent.id);
+                        let mut path = route.get_path(&mut rng);
+
+                        if agent.plan.is_empty() {
+                            path = Floor::detour(
+                                &self.ground_truth,
+                                registry,
+                                &self.services,
+                                &mut agent,
+                                path,
+                                &mut rng,
+                            );
+                        }
+
+                        arrived.push((agent, path));
+                    } else {
+                        // If there is no route agent is lost
+                        lost.push((agent, Reason::NoRouteOnFloor));
                     }
                 }
-                // If there is no route agent is lost
             });
+
+        // Agents of every connection share the free cells, placed in id order so runs repeat
+        arrived.sort_by_key(|(agent, _)| agent.id);
+        arrived
+            .into_iter()
+            .for_each(|(agent, path)| self.place(agent, path));
+
+        egress.sort_by_key(|(_, agent)| agent.id);
+        egress.into_iter().for_each(|(stair, agent)| {
+            let mut rng = draws(self.seed, self.step, agent.id);
+
+            match self.path_between(registry, stair, agent.target, &mut rng) {
+                Some(path) => self.insert_path(agent, path, registry),
+                None => lost.push((agent, Reason::NoEgressPath)),
+            }
+        });
+
+        self.lost.extend(lost);
     }
 
-    pub fn evolve_floor(&mut self, interest: Uniform<f64>) -> HashMap<Agent, Vec<usize>> {
+    // Leaders waiting for far followers and followers catching up with their leader (slots)
+    fn groups(&self) -> (HashSet<usize>, HashSet<usize>) {
+        let slots = self.crowd.slots();
+        let position =
+            |slot: usize| Position::new(self.crowd.positions[slot], self.ground_truth.n_rows);
+
+        let mut waiting = HashSet::new();
+        let mut catching = HashSet::new();
+
+        self.crowd.agents.iter().enumerate().for_each(|(slot, ag)| {
+            if let Some(leader) = ag.leader.and_then(|leader| slots.get(&leader)) {
+                if position(slot).distance(&position(*leader)) > self.group_distance {
+                    waiting.insert(*leader);
+                    catching.insert(slot);
+                }
+            }
+        });
+
+        (waiting, catching)
+    }
+
+    pub fn evolve_floor(
+        &mut self,
+        interest: Uniform<f64>,
+        registry: &Registry,
+    ) -> HashMap<Agent, Vec<usize>> {
+        self.step += 1;
+
+        // Groups leave together, members at the end of their path wait for the rest
+        let walking: HashSet<usize> = self
+            .crowd
+            .agents
+            .iter()
+            .zip(self.crowd.walks.iter())
+            .filter(|(_, walk)| walk.next_step != 0)
+            .map(|(ag, _)| ag.group())
+            .collect();
+
         // Remove end of path agents
-        let leaving: HashMap<Agent, Vec<usize>> =
-            HashMap::from_iter(self.agents.drain_filter(|ag| ag.next_step == 0).map(|ag| {
-                let (_, path) = self.agents_paths.remove(&ag.id).unwrap();
-                (ag, path)
-            }));
+        let leaving: HashMap<Agent, Vec<usize>> = self
+            .crowd
+            .drain_where(|ag, walk| walk.next_step == 0 && !walking.contains(&ag.group()))
+            .into_iter()
+            .map(|(agent, _, path)| (agent, path))
+            .collect();
 
         // Add agents from stairs
-        self.insert_buffered_agents();
+        self.insert_buffered_agents(registry);
+
+        // Bars, restrooms and kiosks
+        self.visit_services(registry);
 
-        // Evolve non-conflicting ones in parallel
-        let no_conflict = self.conficts();
+        let (waiting, catching) = self.groups();
 
-        self.agents
+        // Groups are a unit: followers of a blocked leader hold their position
+        let held: HashSet<usize> = self
+            .crowd
+            .agents
+            .iter()
+            .zip(self.crowd.walks.iter())
+            .filter(|(ag, walk)| ag.leader.is_none() && walk.blocked > 0)
+            .map(|(ag, _)| ag.id)
+            .collect();
+
+        // Every agent decides its movement in parallel, with draws of its own
+        let (seed, step) = (self.seed, self.step);
+        let (agents, walks, mut paths) = self.crowd.walks_paths_mut();
+        let moves: Vec<(usize, usize, usize)> = walks
             .par_iter_mut()
-            .filter(|ag| no_conflict.contains(&ag.next_step))
-            .for_each(|ag| {
-                let ag_path = ag.action(
+            .zip(paths.par_iter_mut())
+            .enumerate()
+            .filter(|(_, (walk, _))| walk.next_step != 0)
+            .filter_map(|(slot, (walk, path))| {
+                let ag = &agents[slot];
+                let from = path[walk.steps];
+
+                if waiting.contains(&slot) || ag.leader.is_some_and(|l| held.contains(&l)) {
+                    walk.wait(path);
+                    return None;
+                }
+
+                // No stops nor wandering while catching up
+                if catching.contains(&slot) {
+                    walk.next_wandering = walk.next_wandering.max(1);
+                }
+
+                walk.action(
                     interest,
-                    &mut self.agents_paths.get_mut(&ag.id).unwrap(),
+                    path,
                     &self.ground_truth,
+                    &mut draws(seed, step, ag.id),
                 );
-            });
 
-        // Conflicting agents
-        self.agents
-            .iter_mut()
-            .filter(|ag| !no_conflict.contains(&ag.next_step))
-            .for_each(|ag| {
-                let movements: Vec<usize> =
-                    path_finding::movements(ag.next_step, &self.ground_truth)
-                        .into_iter()
-                        .filter(|idx| !no_conflict.contains(idx))
-                        .collect();
+                let to = path[walk.steps];
+                (from != to).then_some((slot, from, to))
+            })
+            .collect();
 
-                let mut ag_path = self.agents_paths.get_mut(&ag.id).unwrap();
+        // Cells kept by the agents that do not move this step
+        let moving: HashSet<usize> = moves.iter().map(|(slot, _, _)| *slot).collect();
+        let staying: HashSet<usize> = self
+            .crowd
+            .positions
+            .iter()
+            .enumerate()
+            .filter(|(slot, _)| !moving.contains(slot))
+            .map(|(_, cell)| *cell)
+            .collect();
 
-                if !movements.is_empty() {
-                    // Agent will move to other place and wander arround
-                    let mut wander_path =
-                        vec![*movements.choose(&mut rand::thread_rng()).unwrap(); 10];
+        // Movements are checked against each other
+        let start = Instant::now();
+        self.resolve_conflicts(moves, &staying);
+        self.conflicts_time = start.elapsed();
+        self.crowd.settle();
 
-                    ag.next_wandering = 15;
+        leaving
+    }
 
-                    (1_usize..10).into_iter().for_each(|i| {
-                        wander_path[i] =
-                            *path_finding::movements(wander_path[i - 1], &self.ground_truth)
-                                .choose(&mut rand::thread_rng())
-                                .unwrap();
-                    });
+    // One agent per cell. Movements into cells kept by agents that stay are undone, contested
+    // cells go to the winner of the configured rule. Losers wait in place and sidestep to a free
+    // neighbour after waiting for too long. Cells left by agents that end up waiting are kept,
+    // so movements into them are undone in turn.
+    // Tiles of the same colour are resolved in parallel, the outcome does not depend on threads
+    fn resolve_conflicts(&mut self, moves: Vec<(usize, usize, usize)>, occupied: &HashSet<usize>) {
+        let mut contenders: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+        let cells: HashMap<usize, (usize, usize)> = moves
+            .iter()
+            .map(|(idx, from, to)| (*idx, (*from, *to)))
+            .collect();
 
-                    let join_position = ag.steps + ((ag_path.len() - ag.steps) / 2);
+        moves.into_iter().for_each(|(idx, from, to)| {
+            contenders.entry(to).or_default().push((idx, from));
+        });
 
-                    match path_finding::a_star(&self.ground_truth, wander_path[9], join_position) {
-                        Some(mut join_path) => {
-                            // There is a possible path to rejoin
-                            wander_path.extend(join_path.drain(1..));
+        // Contested cells by colour and tile
+        let mut colours: [HashMap<(usize, usize), Vec<usize>>; 4] = Default::default();
 
-                            // Path is updated with wandering
-                            ag_path.splice(ag.steps..join_position, wander_path);
-                        }
-                        None => {
-                            // Path is reversed
-                            wander_path.extend(wander_path.to_owned().drain(1..).rev());
+        contenders.keys().for_each(|cell| {
+            let x = cell / self.ground_truth.n_rows / CONFLICT_TILE;
+            let y = cell % self.ground_truth.n_rows / CONFLICT_TILE;
+
+            colours[(x % 2) * 2 + y % 2]
+                .entry((x, y))
+                .or_default()
+                .push(*cell);
+        });
+
+        let mut claimed: HashSet<usize> = HashSet::new();
+        let mut outcomes: HashMap<usize, Outcome> = HashMap::new();
 
-                            ag_path.splice(ag.steps..ag.steps, wander_path);
+        for tiles in colours {
+            let resolved: Vec<_> = tiles
+                .into_par_iter()
+                .map(|(_, mut cells)| {
+                    cells.sort_unstable();
+                    self.resolve_tile(&cells, &contenders, occupied, &claimed)
+                })
+                .collect();
+
+            resolved.into_iter().for_each(|(cells, decisions)| {
+                claimed.extend(cells);
+                outcomes.extend(decisions);
+            });
+        }
+
+        Floor::keep_left_cells(&mut outcomes, &cells);
+
+        // Every agent has a single outcome
+        let (_, walks, mut paths) = self.crowd.walks_paths_mut();
+        walks
+            .par_iter_mut()
+            .zip(paths.par_iter_mut())
+            .enumerate()
+            .for_each(|(slot, (walk, path))| match outcomes.get(&slot) {
+                Some(Outcome::Moved) => walk.blocked = 0,
+                Some(outcome) => {
+                    match outcome {
+                        Outcome::Sidestep(side) => {
+                            path.insert(walk.steps, *side);
+                            walk.blocked = 0;
+                        }
+                        Outcome::Wait(from) => {
+                            path.insert(walk.steps, *from);
+                            walk.blocked += 1;
                         }
+                        Outcome::Moved => {}
                     }
-                } else {
-                    // No positions, stays in place
-                    let curr_pos = ag_path[ag.steps];
-                    ag.steps += 1;
-                    ag_path.insert(ag.steps, curr_pos);
-                    ag.next_wandering = 1; // This prevents from natural action.
+
+                    walk.next_step = path[walk.steps + 1];
                 }
+                None => {}
+            });
+    }
+
+    // Agents that wait keep the cell they were leaving, the agent moving into it waits too.
+    // Agents swapping cells head-on both wait
+    fn keep_left_cells(
+        outcomes: &mut HashMap<usize, Outcome>,
+        moves: &HashMap<usize, (usize, usize)>,
+    ) {
+        // Cell -> agent entering it
+        let mut entering: HashMap<usize, usize> = outcomes
+            .iter()
+            .filter_map(|(idx, outcome)| match outcome {
+                Outcome::Moved => Some((moves[idx].1, *idx)),
+                Outcome::Sidestep(side) => Some((*side, *idx)),
+                Outcome::Wait(_) => None,
+            })
+            .collect();
+
+        // Cell -> agent leaving it
+        let leaving: HashMap<usize, usize> =
+            moves.iter().map(|(idx, (from, _))| (*from, *idx)).collect();
+
+        let swaps = entering.iter().filter(|(cell, idx)| {
+            leaving
+                .get(cell)
+                .is_some_and(|other| entering.get(&moves[*idx].0) == Some(other))
+        });
+
+        let mut kept: Vec<usize> = outcomes
+            .values()
+            .filter_map(|outcome| match outcome {
+                Outcome::Wait(from) => Some(*from),
+                _ => None,
+            })
+            .chain(swaps.map(|(_, idx)| moves[idx].0))
+            .collect();
+
+        while let Some(cell) = kept.pop() {
+            if let Some(idx) = entering.remove(&cell) {
+                outcomes.insert(idx, Outcome::Wait(moves[&idx].0));
+                kept.push(moves[&idx].0);
+            }
+        }
+    }
+
+    // Contested cells of a tile, in order. Returns the cells taken and every contender outcome
+    fn resolve_tile(
+        &self,
+        cells: &[usize],
+        contenders: &HashMap<usize, Vec<(usize, usize)>>,
+        occupied: &HashSet<usize>,
+        claimed: &HashSet<usize>,
+    ) -> (HashSet<usize>, Vec<(usize, Outcome)>) {
+        let mut taken = HashSet::new();
+        let mut decisions = Vec::new();
+
+        cells.iter().for_each(|cell| {
+            let mut agents = contenders[cell].to_owned();
 
-                ag.action(interest, &mut ag_path, &self.ground_truth);
+            if !occupied.contains(cell) && !claimed.contains(cell) && !taken.contains(cell) {
+                let (idx, _) = agents.remove(self.winner(*cell, &agents));
+
+                decisions.push((idx, Outcome::Moved));
+                taken.insert(*cell);
+            }
+
+            agents.into_iter().for_each(|(idx, from)| {
+                // Free cell next to both positions, the agent rejoins its path from there.
+                // blocked counts the steps lost before this one, hence >=
+                let side = match self.crowd.walks[idx].blocked >= self.conflicts.sidestep_after {
+                    true => path_finding::movements(from, &self.ground_truth)
+                        .into_iter()
+                        .filter(|side| {
+                            !occupied.contains(side)
+                                && !claimed.contains(side)
+                                && !taken.contains(side)
+                        })
+                        .find(|side| {
+                            path_finding::movements(*cell, &self.ground_truth).contains(side)
+                        }),
+                    false => None,
+                };
+
+                match side {
+                    Some(side) => {
+                        taken.insert(side);
+                        decisions.push((idx, Outcome::Sidestep(side)));
+                    }
+                    None => decisions.push((idx, Outcome::Wait(from))),
+                }
             });
+        });
 
-        leaving
+        (taken, decisions)
     }
 
-    // Independent positions are returned
-    fn conficts(&mut self) -> HashSet<usize> {
-        self.agents
-            .iter()
-            .map(|ag| ag.next_step)
-            .collect::<HashSet<usize>>()
+    // Contender taking a cell, random draws are seeded by step and cell
+    fn winner(&self, cell: usize, agents: &[(usize, usize)]) -> usize {
+        let remaining = |slot: usize| self.crowd.path(slot).len() - self.crowd.walks[slot].steps;
+
+        let position = match self.conflicts.rule {
+            ConflictRule::Random => None,
+            // Closest to the end of its path
+            ConflictRule::Distance => agents
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, (idx, _))| remaining(*idx))
+                .map(|(position, _)| position),
+            // First to enter the venue
+            ConflictRule::Age => agents
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, (idx, _))| self.crowd.agents[*idx].id)
+                .map(|(position, _)| position),
+        };
+
+        position.unwrap_or_else(|| draws(self.seed, self.step, cell).gen_range(0..agents.len()))
     }
 
     fn stairs_paths(
@@ -194,8 +1008,9 @@ impl Floor {
         structures: &HashMap<u8, HashSet<Structure>>,
         layer: &str,
     ) -> HashSet<Route> {
-        let down = structures.get(&10).expect("");
-        let up = structures.get(&11).expect("");
+        // Elevators are both entries and exits
+        let down: Vec<&Structure> = Floor::filter_structures(structures, &ENTRIES).collect();
+        let up: Vec<&Structure> = Floor::filter_structures(structures, &EXITS).collect();
 
         // Progress bar
         let progress_bar = ProgressBar::new(down.len().try_into().unwrap());
@@ -211,10 +1026,11 @@ impl Floor {
         );
         // End of progress bar
 
-        let stairs_paths = down
-            .par_iter()
-            .progress_with(progress_bar)
-            .flat_map(|p1| up.par_iter().filter_map(|p2| find_route(gt, p1, p2)));
+        let stairs_paths = down.par_iter().progress_with(progress_bar).flat_map(|p1| {
+            up.par_iter()
+                .filter(move |p2| p1 != *p2)
+                .filter_map(move |p2| find_route(gt, p1, p2))
+        });
 
         HashSet::from_par_iter(stairs_paths)
     }
@@ -226,8 +1042,8 @@ impl Floor {
         mouths: &HashMap<u16, Structure>,
         layer: &str,
     ) -> HashMap<u16, HashSet<Route>> {
-        // Down stairs are the only positions from where you can go to the grandstands, agents will arrive at down-stairs
-        let down = structures.get(&10).expect("");
+        // Entries are the only positions from where you can go to the grandstands, agents will arrive at them
+        let down: Vec<&Structure> = Floor::filter_structures(structures, &ENTRIES).collect();
 
         // Progress bar
         let progress_bar = ProgressBar::new(down.len().try_into().unwrap());
@@ -258,28 +1074,271 @@ impl Floor {
         HashMap::from_par_iter(mouths_routes)
     }
 
-    pub fn ground_truth(blueprint: &Matrix<u8>) -> Matrix<u8> {
-        Matrix::ground_thruth(
-            blueprint,
-            HashMap::from([
-                // Walls
-                (84, 1),  // Obstacles
-                (202, 1), // No transit
-                (159, 1), // Kitchen
-                (7, 1),   // Technical staff
-                (241, 1), // Lockers
-                (103, 1), // SAT
-                (131, 1), // Men restroom
-                (118, 1), // Women restroom
-                (164, 1), // Restroom
-                (122, 1), // Bar
-                // Map jumps
-                (109, 10), // Down stair
-                (232, 11), // Up stair
-                (112, 20), // Down ramp
-                (189, 21), // Up ramp
-                (182, 3),  // Elevator
-            ]),
-        )
+    // Codified blueprint. A legend next to the map takes precedence over the configured one
+    pub fn ground_truth(path: &str, layer: &str, legend: &Legend) -> Matrix<u8> {
+        let (rgb, luma) = match map_legend(path) {
+            Some(own) => codification(&own),
+            None => codification(legend),
+        };
+
+        let (ground_truth, unmatched) = Matrix::load_codified(path, &rgb, &luma);
+
+        if !unmatched.is_empty() {
+            let mut values: Vec<([u8; 3], usize)> = unmatched.into_iter().collect();
+            values.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+
+            warn!(
+                Loaders,
+                "{layer}: {} pixel values match no class (walkable), most common: {:?}",
+                values.len(),
+                &values[..values.len().min(10)]
+            );
+        }
+
+        ground_truth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iotwins_model::{routes::Leg, structures::BAR};
+
+    const SIDE: usize = 627;
+
+    // Open floor with a wall along the first row
+    fn floor() -> Matrix<u8> {
+        let mut data = vec![0; SIDE * SIDE];
+        data[..SIDE].iter_mut().for_each(|cell| *cell = 1);
+
+        Matrix { data, n_rows: SIDE }
+    }
+
+    #[test]
+    fn free_start_keeps_free_arrivals() {
+        let start = 10 * SIDE + 10;
+        let path = vec![start, start + 1];
+
+        let (spread, offset) = Floor::free_start(&floor(), path.to_vec(), &HashSet::new());
+
+        assert_eq!((spread, offset), (path, 0));
+    }
+
+    #[test]
+    fn free_start_walks_back_onto_the_path() {
+        let gt = floor();
+        let start = SIDE + 10;
+        let path = vec![start, start + 1];
+
+        // Arrival cell and both cells beside it are taken
+        let taken = HashSet::from([start, start - 1, start + 1]);
+        let (spread, offset) = Floor::free_start(&gt, path.to_vec(), &taken);
+
+        assert_eq!(offset, 1);
+        assert_eq!(spread[1..], path);
+        assert!(!taken.contains(&spread[0]));
+        assert!(path_finding::movements(spread[0], &gt).contains(&start));
+    }
+
+    #[test]
+    fn agents_leaving_one_transit_take_their_own_cells() {
+        let start = 10 * SIDE + 10;
+        let mut floor = Floor {
+            ground_truth: floor(),
+            ..Default::default()
+        };
+
+        floor.structures_paths.insert(
+            (1, 2),
+            Route {
+                paths: vec![vec![start, start + 1, start + 2]],
+                ..Default::default()
+            },
+        );
+
+        let mut transit = Transit::new(Link {
+            traversal: 1,
+            flow: 2,
+            ..Default::default()
+        });
+
+        (0..2).for_each(|id| {
+            let mut agent = Agent::default();
+            agent.id = id;
+            agent.target = 2;
+            agent.plan.push_back(Leg::default());
+
+            transit.push(agent);
+        });
+        floor.structures_buffer.insert(1, transit);
+
+        // Boarding, then both reach the floor on the same step
+        floor.insert_buffered_agents(&Registry::default());
+        floor.insert_buffered_agents(&Registry::default());
+
+        assert_eq!(floor.crowd.agents.len(), 2);
+        assert_eq!(floor.crowd.positions[0], start);
+        assert_ne!(floor.crowd.positions[0], floor.crowd.positions[1]);
+    }
+
+    #[test]
+    fn one_cell_routes_are_placed() {
+        let mut floor = Floor {
+            ground_truth: floor(),
+            ..Default::default()
+        };
+
+        (0..2).for_each(|id| {
+            let mut agent = Agent::default();
+            agent.id = id;
+            agent.plan.push_back(Leg::default());
+
+            floor.insert_path(agent, vec![SIDE + 10], &Registry::default());
+        });
+
+        assert_ne!(floor.crowd.positions[0], floor.crowd.positions[1]);
+    }
+
+    #[test]
+    fn served_agents_rejoin_on_a_free_cell() {
+        let cell = 10 * SIDE + 10;
+        let mut floor = Floor {
+            ground_truth: floor(),
+            ..Default::default()
+        };
+
+        // Someone stepped onto the service cell while the agent was being served
+        floor.place(Agent::default(), vec![cell]);
+
+        let mut agent = Agent::default();
+        agent.id = 1;
+        let mut walk = Walk::default();
+        walk.steps = 1;
+        floor.rejoin(agent, walk, vec![cell - 1, cell, cell + 1]);
+
+        let path = floor.crowd.path(1);
+        assert_ne!(floor.crowd.positions[1], cell);
+        assert_eq!(path[0], cell - 1);
+        assert_eq!(&path[path.len() - 2..], &[cell, cell + 1]);
+        assert_eq!(floor.crowd.walks[1].steps, 1);
+    }
+
+    // Agent -> (from, to)
+    fn moves(cells: &[(usize, usize, usize)]) -> HashMap<usize, (usize, usize)> {
+        cells
+            .iter()
+            .map(|(idx, from, to)| (*idx, (*from, *to)))
+            .collect()
+    }
+
+    #[test]
+    fn waiting_agents_keep_their_cell() {
+        // 0 waits on 1, 1 was followed by 2 and 2 by 3
+        let moves = moves(&[(0, 1, 0), (1, 2, 1), (2, 3, 2), (3, 9, 8)]);
+        let mut outcomes = HashMap::from([
+            (0, Outcome::Wait(1)),
+            (1, Outcome::Moved),
+            (2, Outcome::Moved),
+            (3, Outcome::Moved),
+        ]);
+
+        Floor::keep_left_cells(&mut outcomes, &moves);
+
+        assert!(matches!(outcomes[&1], Outcome::Wait(2)));
+        assert!(matches!(outcomes[&2], Outcome::Wait(3)));
+        assert!(matches!(outcomes[&3], Outcome::Moved));
+    }
+
+    #[test]
+    fn head_on_swaps_wait() {
+        let moves = moves(&[(0, 1, 2), (1, 2, 1), (2, 5, 6), (3, 6, 7)]);
+        let mut outcomes = HashMap::from([
+            (0, Outcome::Moved),
+            (1, Outcome::Moved),
+            (2, Outcome::Moved),
+            (3, Outcome::Moved),
+        ]);
+
+        Floor::keep_left_cells(&mut outcomes, &moves);
+
+        assert!(matches!(outcomes[&0], Outcome::Wait(1)));
+        assert!(matches!(outcomes[&1], Outcome::Wait(2)));
+        assert!(matches!(outcomes[&2], Outcome::Moved));
+        assert!(matches!(outcomes[&3], Outcome::Moved));
+    }
+
+    // Floor with a bar on each of the given cells, registered on layer 0
+    fn bars(cells: &[usize]) -> (Matrix<u8>, Registry, HashMap<StructureId, ServicePoint>) {
+        let mut gt = floor();
+        let mut registry = Registry::default();
+
+        let services = cells
+            .iter()
+            .map(|cell| {
+                gt.data[*cell] = BAR;
+
+                let bar = Structure {
+                    position: Position::new(*cell, SIDE),
+                    location: vec![*cell],
+                    code: BAR,
+                };
+
+                (registry.intern(0, &bar), ServicePoint::default())
+            })
+            .collect();
+
+        (gt, registry, services)
+    }
+
+    fn thirsty() -> Agent {
+        let mut agent = Agent::default();
+        agent.agenda.push_back(Service::Bar);
+        agent
+    }
+
+    #[test]
+    fn detours_step_through_the_service_cell() {
+        let bar = 20 * SIDE + 15;
+        let (gt, registry, services) = bars(&[bar]);
+
+        let path: Vec<usize> = (10..30).map(|row| row * SIDE + 10).collect();
+        let mut agent = thirsty();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let detour = Floor::detour(
+            &gt,
+            &registry,
+            &services,
+            &mut agent,
+            path.to_vec(),
+            &mut rng,
+        );
+
+        assert_eq!(detour.first(), path.first());
+        assert_eq!(detour.last(), path.last());
+        assert!(detour.windows(2).all(|pair| {
+            pair[0] == pair[1] || path_finding::movements(pair[0], &gt).contains(&pair[1])
+        }));
+
+        // The agent is served on a cell next to the bar
+        let (idx, point) = agent.visit.unwrap();
+        assert_eq!(point, 0);
+        assert!(path_finding::movements(detour[idx], &floor()).contains(&bar));
+    }
+
+    #[test]
+    fn equally_close_services_go_to_the_lowest_id() {
+        let origin = 20 * SIDE + 20;
+        let (gt, registry, services) = bars(&[20 * SIDE + 25, 20 * SIDE + 15]);
+
+        (0..10).for_each(|seed| {
+            let mut agent = thirsty();
+            let path = vec![origin, origin + SIDE];
+            let mut rng = StdRng::seed_from_u64(seed);
+
+            Floor::detour(&gt, &registry, &services, &mut agent, path, &mut rng);
+
+            assert_eq!(agent.visit.map(|(_, point)| point), Some(0));
+        });
     }
 }
@@ -1,17 +1,90 @@
-use crate::engine::matrix::{Matrix, Position};
+use crate::{
+    engine::{
+        logging::{info, warn},
+        matrix::{Matrix, Position},
+    },
+    iotwins_model::arrivals::Arrival,
+};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{hash_map::Entry, HashMap, HashSet},
     hash::{Hash, Hasher},
-    iter::zip,
 };
 
 use rayon::prelude::*;
 
+// Ground truth codes of inter-floor connections
+pub const ELEVATOR: u8 = 3;
+pub const DOWN_STAIR: u8 = 10;
+pub const UP_STAIR: u8 = 11;
+pub const DOWN_RAMP: u8 = 20;
+pub const UP_RAMP: u8 = 21;
+pub const DOWN_ESCALATOR: u8 = 30;
+pub const UP_ESCALATOR: u8 = 31;
+
+pub const CONNECTIONS: [u8; 7] = [
+    ELEVATOR,
+    DOWN_STAIR,
+    UP_STAIR,
+    DOWN_RAMP,
+    UP_RAMP,
+    DOWN_ESCALATOR,
+    UP_ESCALATOR,
+];
+
+// Ground truth codes of points of interest
+pub const BAR: u8 = 40;
+pub const RESTROOM: u8 = 41;
+pub const KIOSK: u8 = 42;
+
+pub const SERVICES: [u8; 3] = [BAR, RESTROOM, KIOSK];
+
+// Structures from where agents leave the floor
+pub const EXITS: [u8; 4] = [UP_STAIR, UP_RAMP, UP_ESCALATOR, ELEVATOR];
+
+// Structures where agents coming from other floors appear
+pub const ENTRIES: [u8; 4] = [DOWN_STAIR, DOWN_RAMP, DOWN_ESCALATOR, ELEVATOR];
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ConnectionKind {
+    Stair,
+    Ramp,
+    Escalator,
+    Elevator,
+}
+
+impl ConnectionKind {
+    pub fn from_code(code: u8) -> Option<ConnectionKind> {
+        match code {
+            DOWN_STAIR | UP_STAIR => Some(ConnectionKind::Stair),
+            DOWN_RAMP | UP_RAMP => Some(ConnectionKind::Ramp),
+            DOWN_ESCALATOR | UP_ESCALATOR => Some(ConnectionKind::Escalator),
+            ELEVATOR => Some(ConnectionKind::Elevator),
+            _ => None,
+        }
+    }
+
+    // Structure code where this kind of connection lands on the other floor
+    pub fn entry_code(&self) -> u8 {
+        match self {
+            ConnectionKind::Stair => DOWN_STAIR,
+            ConnectionKind::Ramp => DOWN_RAMP,
+            ConnectionKind::Escalator => DOWN_ESCALATOR,
+            ConnectionKind::Elevator => ELEVATOR,
+        }
+    }
+
+    // Step-free connections, preferred by accessibility-needs agents
+    pub fn is_accessible(&self) -> bool {
+        matches!(self, ConnectionKind::Ramp | ConnectionKind::Elevator)
+    }
+}
+
 #[derive(Serialize, Deserialize, Eq, Clone, Default, Debug)]
 pub struct Structure {
     pub position: Position,
     pub location: Vec<usize>,
+    pub code: u8, // Ground truth code, 0 for tagged structures (gates, mouths)
 }
 
 impl PartialEq for Structure {
@@ -27,6 +100,10 @@ impl Hash for Structure {
 }
 
 impl Structure {
+    pub fn kind(&self) -> Option<ConnectionKind> {
+        ConnectionKind::from_code(self.code)
+    }
+
     /// CAUTION: This function computes a^2 + b^2
     fn distance(&self, other: &Structure) -> i32 {
         self.position.distance(&other.position)
@@ -46,49 +123,30 @@ impl Structure {
 pub fn generate_structures(ground_truth: &Matrix<u8>) -> HashMap<u8, HashSet<Structure>> {
     let mut visited = HashSet::with_capacity(ground_truth.n_rows * ground_truth.n_rows);
 
-    // let mut elevators: Vec<Vec<usize>> = Vec::new();
-    let mut up_stairs: Vec<Vec<usize>> = Vec::new();
-    let mut down_stairs: Vec<Vec<usize>> = Vec::new();
-
-    let mut up_stairs_pos: Vec<Position> = Vec::new();
-    let mut down_stairs_pos: Vec<Position> = Vec::new();
+    // Every connection and service type is present, even if the layer has none
+    let mut relation: HashMap<u8, HashSet<Structure>> = HashMap::from_iter(
+        CONNECTIONS
+            .iter()
+            .chain(SERVICES.iter())
+            .map(|code| (*code, HashSet::new())),
+    );
 
     for (idx, value) in ground_truth.data.iter().enumerate() {
         // Skip already visited locations and useless positions
-        if visited.contains(&idx) || !HashSet::from([10, 11]).contains(value) {
+        if visited.contains(&idx) || !relation.contains_key(value) {
             continue;
         }
 
         let facility = find_structure(ground_truth, idx, &mut visited);
 
-        match value {
-            // Down-stairs
-            10 => {
-                down_stairs_pos.push(Position::middle_location(&facility, ground_truth.n_rows));
-                down_stairs.push(facility);
-            }
-            // Up-stairs
-            11 => {
-                up_stairs_pos.push(Position::middle_location(&facility, ground_truth.n_rows));
-                up_stairs.push(facility);
-            }
-
-            _ => (),
-        }
+        relation.get_mut(value).unwrap().insert(Structure {
+            position: Position::middle_location(&facility, ground_truth.n_rows),
+            location: facility,
+            code: *value,
+        });
     }
 
-    // Generate paths
-    let down_stair: HashSet<Structure> = HashSet::from_iter(
-        zip(down_stairs, down_stairs_pos)
-            .map(|(location, position)| Structure { position, location }),
-    );
-
-    let up_stair: HashSet<Structure> = HashSet::from_iter(
-        zip(up_stairs, up_stairs_pos).map(|(location, position)| Structure { position, location }),
-    );
-
     // Reduce size as much as posible
-    let mut relation = HashMap::from([(10, down_stair), (11, up_stair)]);
     relation.shrink_to_fit();
 
     relation
@@ -102,11 +160,10 @@ struct RawMouth {
     y: usize,
 }
 
-pub fn load_mouths(layer: &str) -> HashMap<u16, Structure> {
+pub fn load_mouths(path: &str, layer: &str) -> HashMap<u16, Structure> {
     let mut mouths: HashMap<u16, Vec<usize>> = HashMap::new();
 
-    let mut reader = csv::Reader::from_path("resources/tagging/mouths.csv")
-        .expect("[ERROR] Mouths file not found");
+    let mut reader = csv::Reader::from_path(path).expect("[ERROR] Mouths file not found");
 
     for result in reader.deserialize() {
         let record: RawMouth = result.expect("[ERROR] Incorrect mouth format");
@@ -137,6 +194,7 @@ pub fn load_mouths(layer: &str) -> HashMap<u16, Structure> {
             Structure {
                 position: Position::middle_location(&location, 627),
                 location: location.to_vec(),
+                ..Default::default()
             },
         )
     });
@@ -155,7 +213,7 @@ struct RawGate {
     y: usize,
 }
 
-#[derive(Eq, Clone, Serialize, Deserialize)]
+#[derive(Eq, Clone, Default, Serialize, Deserialize)]
 pub struct Gate {
     pub floor: String,
     pub name: String,
@@ -174,22 +232,11 @@ impl Hash for Gate {
     }
 }
 
-impl Default for Gate {
-    fn default() -> Self {
-        Gate {
-            floor: String::from(""),
-            name: String::from(""),
-            structure: Default::default(),
-        }
-    }
-}
-
 /// HashMap of initial points (Gates). Key => usize position on matrix PB
-pub fn load_gates() -> HashSet<Gate> {
+pub fn load_gates(path: &str) -> HashSet<Gate> {
     let mut gates: HashMap<String, HashMap<String, Vec<usize>>> = HashMap::new();
 
-    let mut reader = csv::Reader::from_path("resources/tagging/gates.csv")
-        .expect("[ERROR] Gates file not found");
+    let mut reader = csv::Reader::from_path(path).expect("[ERROR] Gates file not found");
 
     // HashMap of initial points (Gates). Key => usize position on matrix PB
     for result in reader.deserialize() {
@@ -221,6 +268,7 @@ pub fn load_gates() -> HashSet<Gate> {
                 structure: Structure {
                     position: Position::middle_location(location, 627),
                     location: location.to_vec(),
+                    ..Default::default()
                 },
             });
         });
@@ -231,6 +279,151 @@ pub fn load_gates() -> HashSet<Gate> {
     data
 }
 
+#[derive(Deserialize)]
+struct RawConnection {
+    layer: String,
+    x: usize,
+    y: usize,
+    destination_layer: String,
+    destination_x: usize,
+    destination_y: usize,
+}
+
+// Explicit link between an exit on one layer and an entry on another one
+#[derive(Clone, Debug)]
+pub struct StructureMapping {
+    pub layer: String,
+    pub cell: usize,
+    pub destination_layer: String,
+    pub destination_cell: usize,
+}
+
+/// Inter-floor topology. Each structure is identified by any cell inside it
+pub fn load_connections(path: &str) -> Vec<StructureMapping> {
+    let mut reader = csv::Reader::from_path(path).expect("[ERROR] Connections file not found");
+
+    reader
+        .deserialize()
+        .map(|result| {
+            let record: RawConnection = result.expect("[ERROR] Incorrect connection format");
+
+            StructureMapping {
+                layer: record.layer,
+                cell: 627 * record.x + record.y,
+                destination_layer: record.destination_layer,
+                destination_cell: 627 * record.destination_x + record.destination_y,
+            }
+        })
+        .collect()
+}
+
+// Layer of every mouth and gate, as tagged in the venue files
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct LayerMap {
+    pub mouths: HashMap<u16, String>,
+    pub gates: HashMap<String, String>,
+}
+
+impl LayerMap {
+    pub fn mouth_layer(&self, mouth: u16) -> Option<&String> {
+        self.mouths.get(&mouth)
+    }
+
+    pub fn gate_layer(&self, gate: &str) -> Option<&String> {
+        self.gates.get(gate)
+    }
+
+    // Arrivals whose gate or mouth is not tagged in any layer
+    pub fn report_unmapped(&self, arrivals: &HashMap<u32, Vec<Arrival>>) {
+        let mut gates: HashSet<&str> = HashSet::new();
+        let mut mouths: HashSet<u16> = HashSet::new();
+
+        arrivals.values().flatten().for_each(|arrival| {
+            if self.gate_layer(&arrival.gate).is_none() {
+                gates.insert(&arrival.gate);
+            }
+
+            if self.mouth_layer(arrival.mouth).is_none() {
+                mouths.insert(arrival.mouth);
+            }
+        });
+
+        if !gates.is_empty() {
+            warn!(Loaders, "Arrivals at unmapped gates: {:?}", gates);
+        }
+
+        if !mouths.is_empty() {
+            warn!(Loaders, "Arrivals to unmapped mouths: {:?}", mouths);
+        }
+    }
+}
+
+/// Mouth and gate layers. Entries tagged on more than one layer keep the first one
+pub fn load_layer_map(mouths_path: &str, gates_path: &str) -> LayerMap {
+    let mut layers = LayerMap::default();
+    let mut contradictions = 0;
+
+    let mut reader = csv::Reader::from_path(mouths_path).expect("[ERROR] Mouths file not found");
+
+    for result in reader.deserialize() {
+        let record: RawMouth = result.expect("[ERROR] Incorrect mouth format");
+
+        record
+            .mouth
+            .split('-')
+            .map(|s| s.parse::<u16>().unwrap())
+            .for_each(|mouth| match layers.mouths.entry(mouth) {
+                Entry::Occupied(layer) => {
+                    if *layer.get() != record.layer {
+                        warn!(
+                            Loaders,
+                            "Mouth {mouth} tagged on {} and {}",
+                            layer.get(),
+                            record.layer
+                        );
+                        contradictions += 1;
+                    }
+                }
+                Entry::Vacant(layer) => {
+                    layer.insert(record.layer.to_string());
+                }
+            });
+    }
+
+    let mut reader = csv::Reader::from_path(gates_path).expect("[ERROR] Gates file not found");
+
+    for result in reader.deserialize() {
+        let record: RawGate = result.expect("[ERROR] Incorrect gate format");
+
+        match layers.gates.entry(record.gate) {
+            Entry::Occupied(layer) => {
+                if *layer.get() != record.layer {
+                    warn!(
+                        Loaders,
+                        "Gate {} tagged on {} and {}",
+                        layer.key(),
+                        layer.get(),
+                        record.layer
+                    );
+                    contradictions += 1;
+                }
+            }
+            Entry::Vacant(layer) => {
+                layer.insert(record.layer);
+            }
+        }
+    }
+
+    info!(
+        Loaders,
+        "Layers mapped: {} mouths, {} gates, {contradictions} contradictions",
+        layers.mouths.len(),
+        layers.gates.len()
+    );
+
+    layers
+}
+
 fn find_structure(
     ground_truth: &Matrix<u8>,
     position: usize,
@@ -261,5 +454,8 @@ fn find_structure(
         }
     }
 
-    facility.into_iter().collect()
+    // Cells in order, routes between structures are listed the same way on every run
+    let mut facility: Vec<usize> = facility.into_iter().collect();
+    facility.sort_unstable();
+    facility
 }
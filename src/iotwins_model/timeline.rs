@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+
+use crate::iotwins_model::config::Timeline;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug, Default)]
+pub enum Phase {
+    #[default]
+    PreMatch,
+    FirstHalf,
+    HalfTime,
+    SecondHalf,
+    PostMatch,
+}
+
+// Converts simulation steps into match minutes and phases
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct MatchClock {
+    pub start: f64, // Minute of the first step relative to kick-off
+    pub seconds_per_step: f64,
+    pub timeline: Timeline,
+}
+
+impl MatchClock {
+    pub fn new(start: f64, seconds_per_step: f64, timeline: &Timeline) -> MatchClock {
+        MatchClock {
+            start,
+            seconds_per_step,
+            timeline: timeline.to_owned(),
+        }
+    }
+
+    // Match minute of a step
+    pub fn minute(&self, step: u32) -> f64 {
+        self.start + step as f64 * self.seconds_per_step / 60.0
+    }
+
+    // Step whose interval holds a match minute, minutes before the first step fall on step 0
+    pub fn step(&self, minute: f64) -> u32 {
+        ((minute - self.start) * 60.0 / self.seconds_per_step)
+            .floor()
+            .max(0.0) as u32
+    }
+
+    pub fn steps_per_minute(&self) -> u32 {
+        ((60.0 / self.seconds_per_step).round() as u32).max(1)
+    }
+
+    pub fn phase(&self, step: u32) -> Phase {
+        let minute = self.minute(step);
+
+        match minute {
+            m if m >= self.timeline.post_match => Phase::PostMatch,
+            m if m >= self.timeline.second_half => Phase::SecondHalf,
+            m if m >= self.timeline.half_time => Phase::HalfTime,
+            m if m >= self.timeline.first_half => Phase::FirstHalf,
+            _ => Phase::PreMatch,
+        }
+    }
+}
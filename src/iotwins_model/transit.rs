@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use crate::iotwins_model::{agent::Agent, config::Connections, structures::ConnectionKind};
+
+// Traversal parameters of an inter-floor connection
+#[derive(Clone, Copy, Default, Serialize, Deserialize, Debug)]
+pub struct Link {
+    pub traversal: usize, // Steps needed to reach the other floor
+    pub capacity: usize,  // Agents inside the connection at once, 0 means unbounded
+    pub cycle: usize,     // Steps between departures (elevators), 0 means continuous
+    pub flow: usize,      // Agents boarding per departure, 0 means unbounded
+}
+
+impl Link {
+    pub fn new(kind: ConnectionKind, connections: &Connections) -> Link {
+        match kind {
+            // Stair length walked at reduced speed
+            ConnectionKind::Stair => Link {
+                traversal: (connections.stair_length as f64 / connections.stair_speed).ceil()
+                    as usize,
+                capacity: connections.stair_capacity,
+                cycle: 0,
+                flow: connections.stair_flow,
+            },
+            ConnectionKind::Ramp => Link {
+                traversal: connections.ramp_traversal,
+                capacity: connections.ramp_capacity,
+                cycle: 0,
+                flow: 0,
+            },
+            ConnectionKind::Escalator => Link {
+                traversal: connections.escalator_traversal,
+                capacity: connections.escalator_capacity,
+                cycle: 0,
+                flow: 0,
+            },
+            ConnectionKind::Elevator => Link {
+                traversal: connections.elevator_traversal,
+                capacity: connections.elevator_capacity,
+                cycle: connections.elevator_cycle,
+                flow: 0,
+            },
+        }
+    }
+}
+
+// Agents travelling through a connection towards the floor that owns it
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Transit {
+    pub link: Link,
+    pub waiting: VecDeque<Agent>,         // Queue at the entrance
+    pub moving: VecDeque<(usize, Agent)>, // Arrival clock and agent
+    clock: usize,
+}
+
+impl Transit {
+    pub fn new(link: Link) -> Transit {
+        Transit {
+            link,
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, agent: Agent) {
+        self.waiting.push_back(agent);
+    }
+
+    // One step of the connection, returns the agents reaching the floor
+    pub fn advance(&mut self) -> Vec<Agent> {
+        self.clock += 1;
+
+        // Boarding: continuous connections admit agents every step, elevators only on departure
+        if self.link.cycle == 0 || self.clock.is_multiple_of(self.link.cycle) {
+            let free = match self.link.capacity {
+                0 => self.waiting.len(),
+                capacity => capacity.saturating_sub(self.moving.len()),
+            };
+
+            let flow = match self.link.flow {
+                0 => self.waiting.len(),
+                flow => flow,
+            };
+
+            // Full connections keep agents queued at the entrance
+            let arrival = self.clock + self.link.traversal;
+            let boarding = free.min(flow).min(self.waiting.len());
+
+            self.moving
+                .extend(self.waiting.drain(..boarding).map(|agent| (arrival, agent)));
+        }
+
+        // Same traversal for everyone, so arrivals are ordered
+        let mut arrived = Vec::new();
+
+        while let Some((arrival, _)) = self.moving.front() {
+            if *arrival > self.clock {
+                break;
+            }
+
+            arrived.push(self.moving.pop_front().unwrap().1);
+        }
+
+        arrived
+    }
+
+    // Agents inside the connection
+    pub fn occupancy(&self) -> usize {
+        self.moving.len()
+    }
+
+    // Agents queued at the entrance
+    pub fn queue_length(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn agents(&self) -> impl Iterator<Item = &Agent> {
+        self.waiting
+            .iter()
+            .chain(self.moving.iter().map(|(_, agent)| agent))
+    }
+}
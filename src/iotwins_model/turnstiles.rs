@@ -0,0 +1,396 @@
+use rand::{
+    distributions::{Distribution, Uniform},
+    Rng,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::iotwins_model::{
+    arrivals::Arrival,
+    config::{Gates, ServiceDistribution},
+};
+
+// Service time of a turnstile (ticket scan, security check) in steps
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct ServiceTime {
+    pub distribution: ServiceDistribution,
+    pub mean: f64,
+    pub deviation: f64,
+}
+
+impl Default for ServiceTime {
+    fn default() -> Self {
+        ServiceTime {
+            distribution: ServiceDistribution::Fixed,
+            mean: 1.0,
+            deviation: 0.0,
+        }
+    }
+}
+
+impl ServiceTime {
+    // Configuration is given in seconds
+    pub fn new(gates: &Gates, seconds_per_step: f64) -> ServiceTime {
+        ServiceTime {
+            distribution: gates.service_time,
+            mean: gates.service_mean / seconds_per_step,
+            deviation: gates.service_deviation / seconds_per_step,
+        }
+    }
+
+    // Steps needed to serve one person, at least one
+    pub fn sample(&self, rng: &mut impl Rng) -> u32 {
+        let u = Uniform::from(f64::EPSILON..1_f64);
+
+        let steps = match self.distribution {
+            ServiceDistribution::Fixed => self.mean,
+            ServiceDistribution::Uniform => {
+                self.mean + self.deviation * (2.0 * u.sample(rng) - 1.0)
+            }
+            // Inverse transform sampling
+            ServiceDistribution::Exponential => -self.mean * u.sample(rng).ln(),
+            // Box-Muller transform
+            ServiceDistribution::Normal => {
+                let z = (-2.0 * u.sample(rng).ln()).sqrt()
+                    * (2.0 * std::f64::consts::PI * u.sample(rng)).cos();
+                self.mean + self.deviation * z
+            }
+        };
+
+        steps.round().max(1.0) as u32
+    }
+}
+
+// Queue and waiting time records of a gate
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct GateStats {
+    pub served: usize,
+    pub total_wait: u64, // Steps
+    pub max_wait: u32,
+    pub max_queue: usize,
+    pub queue_series: Vec<(u32, usize)>, // Step, people in queue
+}
+
+impl GateStats {
+    pub fn mean_wait(&self) -> f64 {
+        match self.served {
+            0 => 0.0,
+            served => self.total_wait as f64 / served as f64,
+        }
+    }
+}
+
+/// Multi-server queue: people wait in a single line for the first free turnstile. Everyone keeps
+/// the arrival batch they came with and waits past the turnstiles until the whole batch is through
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct GateQueue {
+    pub turnstiles: usize,
+    queue: VecDeque<(u32, usize)>, // Enqueue step and batch, one per person
+    serving: Vec<(u32, usize)>,    // Completion step and batch, one per busy turnstile
+    batches: BTreeMap<usize, Batch>,
+    next_batch: usize,
+    sampling: u32, // Steps between queue samples, one match minute
+    pub stats: GateStats,
+}
+
+// People of one arrival still at the gate
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+struct Batch {
+    mouth: u16,
+    first: usize, // Id of its first agent
+    people: u32,  // Not through the turnstiles yet
+    through: u32, // Waiting for the rest of the batch
+}
+
+impl GateQueue {
+    pub fn new(turnstiles: usize, sampling: u32) -> GateQueue {
+        GateQueue {
+            turnstiles,
+            sampling: sampling.max(1),
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, arrival: &Arrival, step: u32) {
+        if arrival.agents == 0 {
+            return;
+        }
+
+        let batch = self.next_batch;
+        self.next_batch += 1;
+
+        self.batches.insert(
+            batch,
+            Batch {
+                mouth: arrival.mouth,
+                first: arrival.first,
+                people: arrival.agents,
+                through: 0,
+            },
+        );
+
+        (0..arrival.agents).for_each(|_| self.queue.push_back((step, batch)));
+    }
+
+    pub fn queue_length(&self) -> usize {
+        self.queue.len()
+    }
+
+    // Destination mouth of everyone queued, at a turnstile or waiting for their batch
+    pub fn pending(&self) -> impl Iterator<Item = u16> + '_ {
+        self.batches
+            .values()
+            .flat_map(|batch| (0..batch.people + batch.through).map(|_| batch.mouth))
+    }
+
+    // People still in line leave the closed gate, each batch on its own with the last ids of it.
+    // Turnstiles finish their job and the rest of their batches go through without them
+    pub fn close(&mut self, gate: &str) -> Vec<Arrival> {
+        let mut leaving: BTreeMap<usize, u32> = BTreeMap::new();
+
+        self.queue
+            .drain(..)
+            .for_each(|(_, batch)| *leaving.entry(batch).or_default() += 1);
+
+        let leaving = leaving
+            .into_iter()
+            .map(|(batch, agents)| {
+                let batch = self.batches.get_mut(&batch).unwrap();
+                let first = batch.first + (batch.people + batch.through - agents) as usize;
+
+                batch.people -= agents;
+
+                Arrival {
+                    gate: gate.to_string(),
+                    mouth: batch.mouth,
+                    agents,
+                    first,
+                }
+            })
+            .collect();
+
+        self.batches
+            .retain(|_, batch| batch.people + batch.through > 0);
+
+        leaving
+    }
+
+    // One step of the gate, batches whose last person got through the turnstiles leave together
+    pub fn serve(
+        &mut self,
+        gate: &str,
+        step: u32,
+        service: &ServiceTime,
+        rng: &mut impl Rng,
+    ) -> Vec<Arrival> {
+        self.serving.retain(|(end, batch)| match *end <= step {
+            true => {
+                let batch = self.batches.get_mut(batch).unwrap();
+
+                batch.people -= 1;
+                batch.through += 1;
+                false
+            }
+            false => true,
+        });
+
+        // Free turnstiles take the next person in line
+        while self.serving.len() < self.turnstiles {
+            match self.queue.pop_front() {
+                Some((since, batch)) => {
+                    let wait = step - since;
+
+                    self.stats.served += 1;
+                    self.stats.total_wait += wait as u64;
+                    self.stats.max_wait = self.stats.max_wait.max(wait);
+
+                    self.serving.push((step + service.sample(rng), batch));
+                }
+                None => break,
+            }
+        }
+
+        self.stats.max_queue = self.stats.max_queue.max(self.queue.len());
+
+        // Queue sampled once per minute
+        if step.is_multiple_of(self.sampling) {
+            self.stats.queue_series.push((step, self.queue.len()));
+        }
+
+        let mut passed = Vec::new();
+
+        self.batches.retain(|_, batch| match batch.people {
+            0 => {
+                passed.push(Arrival {
+                    gate: gate.to_string(),
+                    mouth: batch.mouth,
+                    agents: batch.through,
+                    first: batch.first,
+                });
+                false
+            }
+            _ => true,
+        });
+
+        passed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iotwins_model::{agent::Agent, arrivals::form_groups};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn rng() -> StdRng {
+        StdRng::seed_from_u64(0)
+    }
+
+    fn fixed(steps: f64) -> ServiceTime {
+        ServiceTime {
+            distribution: ServiceDistribution::Fixed,
+            mean: steps,
+            deviation: 0.0,
+        }
+    }
+
+    fn arrival(mouth: u16, agents: u32) -> Arrival {
+        Arrival {
+            gate: String::from("G1"),
+            mouth,
+            agents,
+            first: 0,
+        }
+    }
+
+    #[test]
+    fn turnstiles_serve_in_parallel() {
+        let mut gate = GateQueue::new(2, 120);
+        (0..5).for_each(|_| gate.push(&arrival(1, 1), 0));
+
+        assert!(gate.serve("G1", 0, &fixed(3.0), &mut rng()).is_empty());
+        assert_eq!(gate.queue_length(), 3);
+        assert!(gate.serve("G1", 2, &fixed(3.0), &mut rng()).is_empty());
+
+        let passed = gate.serve("G1", 3, &fixed(3.0), &mut rng());
+
+        assert_eq!(passed.len(), 2);
+        assert!(passed
+            .iter()
+            .all(|arrival| (arrival.mouth, arrival.agents) == (1, 1)));
+        assert_eq!(gate.queue_length(), 1);
+        assert_eq!(gate.stats.served, 4);
+        assert_eq!(gate.stats.max_wait, 3);
+        assert_eq!(gate.stats.total_wait, 6);
+    }
+
+    #[test]
+    fn people_through_are_grouped_by_mouth() {
+        let mut gate = GateQueue::new(3, 120);
+        gate.push(&arrival(1, 2), 0);
+        gate.push(&arrival(2, 1), 0);
+
+        gate.serve("G1", 0, &fixed(1.0), &mut rng());
+        let mut passed = gate.serve("G1", 1, &fixed(1.0), &mut rng());
+        passed.sort_by_key(|arrival| arrival.mouth);
+
+        assert_eq!(
+            passed
+                .iter()
+                .map(|arrival| (arrival.gate.as_str(), arrival.mouth, arrival.agents))
+                .collect::<Vec<_>>(),
+            [("G1", 1, 2), ("G1", 2, 1)]
+        );
+        assert_eq!(gate.pending().count(), 0);
+    }
+
+    #[test]
+    fn batches_leave_with_their_last_person() {
+        let mut gate = GateQueue::new(2, 120);
+        gate.push(&arrival(1, 4), 0);
+
+        gate.serve("G1", 0, &fixed(3.0), &mut rng());
+        assert!(gate.serve("G1", 3, &fixed(3.0), &mut rng()).is_empty());
+        assert_eq!(gate.pending().count(), 4);
+
+        let passed = gate.serve("G1", 6, &fixed(3.0), &mut rng());
+
+        assert_eq!(passed.len(), 1);
+        assert_eq!((passed[0].mouth, passed[0].agents), (1, 4));
+        assert_eq!(gate.pending().count(), 0);
+    }
+
+    #[test]
+    fn one_batch_forms_one_group() {
+        let mut gate = GateQueue::new(1, 120);
+        gate.push(&arrival(1, 4), 0);
+        gate.push(&arrival(1, 1), 0);
+
+        let passed: Vec<Arrival> = (0..=5)
+            .flat_map(|step| gate.serve("G1", step, &fixed(1.0), &mut rng()))
+            .collect();
+
+        assert_eq!(passed.len(), 2);
+
+        let mut agents: Vec<Agent> = (0..passed[0].agents as usize)
+            .map(|id| {
+                let mut agent = Agent::default();
+                agent.id = id;
+                agent
+            })
+            .collect();
+        form_groups(&mut agents, 1.0, &mut rng());
+
+        assert_eq!(agents.len(), 4);
+        assert!(agents.iter().all(|agent| agent.group() == 0));
+    }
+
+    #[test]
+    fn service_takes_at_least_one_step() {
+        assert_eq!(fixed(0.2).sample(&mut rng()), 1);
+    }
+
+    #[test]
+    fn queue_is_sampled_once_per_minute() {
+        let mut gate = GateQueue::new(1, 120);
+        gate.push(&arrival(1, 400), 0);
+
+        (0..=360).for_each(|step| {
+            gate.serve("G1", step, &fixed(1.0), &mut rng());
+        });
+
+        assert_eq!(
+            gate.stats
+                .queue_series
+                .iter()
+                .map(|(step, _)| *step)
+                .collect::<Vec<_>>(),
+            [0, 120, 240, 360]
+        );
+    }
+
+    #[test]
+    fn closing_empties_the_line_only() {
+        let mut gate = GateQueue::new(1, 120);
+        gate.push(
+            &Arrival {
+                first: 10,
+                ..arrival(1, 3)
+            },
+            0,
+        );
+        gate.serve("G1", 0, &fixed(5.0), &mut rng());
+
+        let leaving = gate.close("G1");
+
+        assert_eq!((leaving[0].mouth, leaving[0].agents), (1, 2));
+        assert_eq!(gate.queue_length(), 0);
+        assert_eq!(gate.pending().count(), 1);
+
+        // The one at the turnstile keeps the first id of the batch, the line takes the rest
+        let passed = gate.serve("G1", 5, &fixed(5.0), &mut rng());
+
+        assert_eq!((passed[0].first, passed[0].agents), (10, 1));
+        assert_eq!(leaving[0].first, 11);
+    }
+}
@@ -0,0 +1,387 @@
+use image::{Rgb, RgbImage};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::{
+    config::configuration::Parameters,
+    engine::{
+        logging::{info, warn},
+        path_finding,
+    },
+    iotwins_model::{
+        arrivals::select_arrivals,
+        registry::{LayerId, Registry},
+        stadium::Floor,
+        structures::{load_gates, load_layer_map, Gate, Structure, ELEVATOR, ENTRIES, EXITS},
+        world::load_building,
+    },
+};
+
+// Kind of problem found in the venue data
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Check {
+    UnknownLayer, // Tagged on a layer with no floor
+    Unwalkable,   // Tagged cells on walls
+    Unreachable,  // No path to or from where agents come
+    NoPartner,    // Connection leading nowhere, or reached from nowhere
+    UnknownGate,  // Arrivals at a gate missing from the gates file
+    UnknownMouth, // Arrivals to a mouth missing from the mouths file
+}
+
+struct Issue {
+    check: Check,
+    layer: Option<LayerId>,
+    cells: Vec<usize>, // Marked on the layer picture
+    message: String,
+}
+
+// Red for problems, the rest of the picture is dimmed
+const PROBLEM: Rgb<u8> = Rgb([230, 30, 30]);
+const GATE: Rgb<u8> = Rgb([40, 170, 60]);
+const MOUTH: Rgb<u8> = Rgb([40, 90, 220]);
+const WALL: Rgb<u8> = Rgb([70, 70, 70]);
+const WALKABLE: Rgb<u8> = Rgb([245, 245, 245]);
+const TAGGED: Rgb<u8> = Rgb([190, 200, 215]);
+
+// Half side of the square drawn around every problem
+const MARK: i64 = 4;
+
+/// Checks the venue data before running it: walkability of tagged cells, reachability with the
+/// moves of the pathfinder and consistency between the floors, gates, mouths, connections and
+/// arrivals files. Returns the number of problems found
+pub fn validate(configuration: &Parameters, dir: &str) -> usize {
+    let venue = configuration.venue_tags.files();
+
+    info!(Loaders, "Validating venue data");
+
+    let mut registry = Registry::default();
+    let (building, conexions) = load_building(configuration, &mut registry);
+
+    let gates = load_gates(&venue.gates_info);
+    let layer_map = load_layer_map(&venue.mouths_info, &venue.gates_info);
+    let arrivals = select_arrivals(configuration, &layer_map);
+
+    // Connected walkable areas of every floor
+    let regions: HashMap<LayerId, Vec<u32>> = building
+        .par_iter()
+        .map(|(layer, floor)| (*layer, path_finding::regions(&floor.ground_truth)))
+        .collect();
+
+    let reached = |layer: LayerId, structure: &Structure| -> HashSet<u32> {
+        structure
+            .location
+            .iter()
+            .map(|cell| regions[&layer][*cell])
+            .filter(|region| *region != u32::MAX)
+            .collect()
+    };
+
+    let mut issues = Vec::new();
+
+    // Gates on every layer, with the regions they open into
+    let mut gate_regions: HashMap<LayerId, HashSet<u32>> = HashMap::new();
+
+    gates.iter().for_each(|gate| {
+        let layer = match registry.layer_id(&gate.floor) {
+            Some(layer) => layer,
+            None => {
+                issues.push(Issue {
+                    check: Check::UnknownLayer,
+                    layer: None,
+                    cells: Vec::new(),
+                    message: format!("Gate {} on unknown layer {}", gate.name, gate.floor),
+                });
+                return;
+            }
+        };
+
+        let floor = &building[&layer];
+        issues.extend(unwalkable(
+            floor,
+            layer,
+            &gate.structure,
+            &format!("Gate {}", gate.name),
+        ));
+
+        gate_regions
+            .entry(layer)
+            .or_default()
+            .extend(reached(layer, &gate.structure));
+    });
+
+    // Mouths tagged on layers with no floor are never loaded
+    let mut missing: BTreeMap<&str, Vec<u16>> = BTreeMap::new();
+
+    layer_map.mouths.iter().for_each(|(mouth, layer)| {
+        if registry.layer_id(layer).is_none() {
+            missing.entry(layer).or_default().push(*mouth);
+        }
+    });
+
+    missing.into_iter().for_each(|(layer, mut mouths)| {
+        mouths.sort_unstable();
+        issues.push(Issue {
+            check: Check::UnknownLayer,
+            layer: None,
+            cells: Vec::new(),
+            message: format!("Mouths {mouths:?} on unknown layer {layer}"),
+        });
+    });
+
+    // Connections reached from another floor
+    let landings: HashSet<(LayerId, u32)> = conexions
+        .values()
+        .flat_map(|exits| exits.values())
+        .flat_map(|entries| entries.iter().map(|(layer, entry)| (*layer, *entry)))
+        .collect();
+
+    let mut layers: Vec<&LayerId> = building.keys().collect();
+    layers.sort_unstable();
+
+    layers.into_iter().for_each(|layer| {
+        let floor = &building[layer];
+        let name = registry.layer_name(*layer);
+
+        // Agents appear on the floor through its gates and the connections from other floors
+        let mut sources = gate_regions.get(layer).cloned().unwrap_or_default();
+
+        Floor::filter_structures(&floor.structures, &ENTRIES).for_each(|entry| {
+            sources.extend(reached(*layer, entry));
+        });
+
+        let mut mouths: Vec<(&u16, &u32)> = floor.mouths.iter().collect();
+        mouths.sort_unstable();
+
+        mouths.into_iter().for_each(|(mouth, id)| {
+            let structure = registry.structure(*id);
+            issues.extend(unwalkable(
+                floor,
+                *layer,
+                structure,
+                &format!("Mouth {mouth}"),
+            ));
+
+            if reached(*layer, structure).is_disjoint(&sources) {
+                issues.push(Issue {
+                    check: Check::Unreachable,
+                    layer: Some(*layer),
+                    cells: structure.location.to_vec(),
+                    message: format!("Mouth {mouth} reached from no gate nor connection"),
+                });
+            }
+        });
+
+        // Gates with nowhere to go on their floor
+        gates
+            .iter()
+            .filter(|gate| gate.floor == name)
+            .for_each(|gate| {
+                let targets: HashSet<u32> = floor
+                    .mouths
+                    .values()
+                    .map(|id| registry.structure(*id))
+                    .chain(Floor::filter_structures(&floor.structures, &EXITS))
+                    .flat_map(|structure| reached(*layer, structure))
+                    .collect();
+
+                if reached(*layer, &gate.structure).is_disjoint(&targets) {
+                    issues.push(Issue {
+                        check: Check::Unreachable,
+                        layer: Some(*layer),
+                        cells: gate.structure.location.to_vec(),
+                        message: format!("Gate {} leads to no mouth nor connection", gate.name),
+                    });
+                }
+            });
+
+        // Exits leading to no other floor and entries no other floor leads to
+        Floor::filter_structures(&floor.structures, &EXITS).for_each(|exit| {
+            let id = registry.id(*layer, exit).unwrap();
+
+            if conexions[layer]
+                .get(&id)
+                .is_none_or(|entries| entries.is_empty())
+            {
+                issues.push(Issue {
+                    check: Check::NoPartner,
+                    layer: Some(*layer),
+                    cells: exit.location.to_vec(),
+                    message: format!("Connection at {:?} leads to no floor", exit.position),
+                });
+            } else if reached(*layer, exit).is_disjoint(&sources) {
+                issues.push(Issue {
+                    check: Check::Unreachable,
+                    layer: Some(*layer),
+                    cells: exit.location.to_vec(),
+                    message: format!("Connection at {:?} reached from no gate", exit.position),
+                });
+            }
+        });
+
+        Floor::filter_structures(&floor.structures, &ENTRIES)
+            .filter(|entry| entry.code != ELEVATOR)
+            .for_each(|entry| {
+                let id = registry.id(*layer, entry).unwrap();
+
+                if !landings.contains(&(*layer, id)) {
+                    issues.push(Issue {
+                        check: Check::NoPartner,
+                        layer: Some(*layer),
+                        cells: entry.location.to_vec(),
+                        message: format!(
+                            "Connection at {:?} reached from no floor",
+                            entry.position
+                        ),
+                    });
+                }
+            });
+    });
+
+    // Arrivals referencing gates and mouths of no file, by people
+    let mut unknown_gates: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut unknown_mouths: BTreeMap<u16, usize> = BTreeMap::new();
+
+    arrivals.values().flatten().for_each(|arrival| {
+        if layer_map.gate_layer(&arrival.gate).is_none() {
+            *unknown_gates.entry(&arrival.gate).or_default() += arrival.agents as usize;
+        }
+
+        if layer_map.mouth_layer(arrival.mouth).is_none() {
+            *unknown_mouths.entry(arrival.mouth).or_default() += arrival.agents as usize;
+        }
+    });
+
+    issues.extend(unknown_gates.into_iter().map(|(gate, people)| Issue {
+        check: Check::UnknownGate,
+        layer: None,
+        cells: Vec::new(),
+        message: format!("{people} people arrive at unknown gate {gate}"),
+    }));
+
+    issues.extend(unknown_mouths.into_iter().map(|(mouth, people)| Issue {
+        check: Check::UnknownMouth,
+        layer: None,
+        cells: Vec::new(),
+        message: format!("{people} people go to unknown mouth {mouth}"),
+    }));
+
+    report(&issues);
+    annotate(&building, &gates, &issues, &registry, dir);
+
+    issues.len()
+}
+
+// Tagged cells on walls
+fn unwalkable(floor: &Floor, layer: LayerId, structure: &Structure, name: &str) -> Option<Issue> {
+    let walls: Vec<usize> = structure
+        .location
+        .iter()
+        .filter(|cell| floor.ground_truth.data[**cell] == 1)
+        .copied()
+        .collect();
+
+    (!walls.is_empty()).then(|| Issue {
+        check: Check::Unwalkable,
+        message: format!(
+            "{name}: {} of {} cells on walls",
+            walls.len(),
+            structure.location.len()
+        ),
+        layer: Some(layer),
+        cells: walls,
+    })
+}
+
+fn report(issues: &[Issue]) {
+    let mut counts: BTreeMap<Check, usize> = BTreeMap::new();
+
+    issues.iter().for_each(|issue| {
+        *counts.entry(issue.check).or_default() += 1;
+
+        match issue.layer {
+            Some(layer) => warn!(Loaders @ layer, "{:?}: {}", issue.check, issue.message),
+            None => warn!(Loaders, "{:?}: {}", issue.check, issue.message),
+        }
+    });
+
+    match issues.is_empty() {
+        true => info!(Loaders, "Venue data is valid"),
+        false => info!(Loaders, "{} problems found: {counts:?}", issues.len()),
+    }
+}
+
+// Picture of every floor with its gates, mouths and problems
+fn annotate(
+    building: &HashMap<LayerId, Floor>,
+    gates: &HashSet<Gate>,
+    issues: &[Issue],
+    registry: &Registry,
+    dir: &str,
+) {
+    std::fs::create_dir_all(dir).expect("[ERROR] Unable to create validation directory");
+
+    building.iter().for_each(|(layer, floor)| {
+        let gt = &floor.ground_truth;
+        let side = gt.n_rows as u32;
+
+        let mut picture = RgbImage::from_fn(side, side, |y, x| {
+            match gt.data[x as usize * gt.n_rows + y as usize] {
+                0 => WALKABLE,
+                1 => WALL,
+                _ => TAGGED,
+            }
+        });
+
+        let mut paint = |cell: usize, colour: Rgb<u8>| {
+            let (x, y) = (cell / gt.n_rows, cell % gt.n_rows);
+            picture.put_pixel(y as u32, x as u32, colour);
+        };
+
+        let name = registry.layer_name(*layer);
+
+        gates
+            .iter()
+            .filter(|gate| gate.floor == name)
+            .flat_map(|gate| gate.structure.location.iter())
+            .for_each(|cell| paint(*cell, GATE));
+
+        floor
+            .mouths
+            .values()
+            .flat_map(|id| registry.structure(*id).location.iter())
+            .for_each(|cell| paint(*cell, MOUTH));
+
+        issues
+            .iter()
+            .filter(|issue| issue.layer == Some(*layer))
+            .for_each(|issue| {
+                issue.cells.iter().for_each(|cell| paint(*cell, PROBLEM));
+
+                // Square around the problem, single cells are hard to spot
+                if let Some(cell) = issue.cells.first() {
+                    let (x, y) = ((cell / gt.n_rows) as i64, (cell % gt.n_rows) as i64);
+
+                    (-MARK..=MARK).for_each(|d| {
+                        [
+                            (x - MARK, y + d),
+                            (x + MARK, y + d),
+                            (x + d, y - MARK),
+                            (x + d, y + MARK),
+                        ]
+                        .into_iter()
+                        .filter(|(x, y)| {
+                            (0..side as i64).contains(x) && (0..side as i64).contains(y)
+                        })
+                        .for_each(|(x, y)| paint(x as usize * gt.n_rows + y as usize, PROBLEM));
+                    });
+                }
+            });
+
+        let path = std::path::Path::new(dir).join(format!("{name}.png"));
+        picture
+            .save(&path)
+            .expect("[ERROR] Unable to save validation picture");
+    });
+
+    info!(Loaders, "Annotated floors written to {dir}");
+}
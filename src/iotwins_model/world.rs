@@ -1,8 +1,9 @@
 use std::{
-    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    collections::{BinaryHeap, HashMap, HashSet},
     fs::{self, File},
     io::{BufReader, BufWriter},
-    time::Instant,
+    path::Path,
+    time::{Duration, Instant},
 };
 
 use bincode::{deserialize_from, serialize_into};
@@ -14,31 +15,80 @@ use serde::{Deserialize, Serialize};
 use crate::{
     config::configuration::Parameters,
     engine::{
+        logging::{self, debug, info, warn},
+        mpi::Communicator,
         path_finding::a_star,
         saving::{self, PathSegment},
     },
     iotwins_model::{
         agent::Agent,
-        arrivals::{load_arrivals, Arrival},
-        routes::{find_route, Route},
-        stadium::{self},
-        structures::{load_gates, Gate, Structure},
+        arrivals::{form_groups, select_arrivals, Arrival},
+        config::Services,
+        incidents::{Impact, Incident, Incidents, Kind},
+        instrumentation::{micros, FloorTimes, Instrumentation, StepTimes},
+        lifecycle::{Fate, Lifecycle, Reason},
+        partition::{self, Balancer},
+        registry::{LayerId, Registry, StructureId},
+        routes::{avoid_closed, find_route, LayerGraph, Leg, Role, Route, RouteUpdate},
+        services::Service,
+        stadium::{self, draws},
+        structures::{
+            load_connections, load_gates, load_layer_map, ConnectionKind, Gate, LayerMap,
+            Structure, CONNECTIONS, ENTRIES, EXITS,
+        },
+        timeline::{MatchClock, Phase},
+        turnstiles::{GateQueue, ServiceTime},
     },
 };
 
 #[derive(Serialize, Deserialize)]
 pub struct World {
-    pub building: HashMap<String, stadium::Floor>,
-    pub building_conexions: HashMap<String, HashMap<Structure, HashMap<String, Structure>>>,
+    pub building: HashMap<LayerId, stadium::Floor>,
+    pub building_conexions: Conexions,
+    pub registry: Registry,
     pub step: u32,
+    pub seed: u64, // Random draws of every step derive from it
     pub agent_count: usize,
-    pub arrivals: HashMap<i32, Vec<Arrival>>,
+    pub arrivals: HashMap<u32, Vec<Arrival>>, // By step
     pub gates: HashSet<Gate>,
-    pub gates_buffer: HashMap<Gate, VecDeque<Arrival>>,
-    pub gates_to_stairs: HashMap<Gate, HashSet<Route>>,
+    pub gates_buffer: HashMap<Gate, GateQueue>,
+    pub turnstile_service: ServiceTime,
+    pub gates_to_stairs: HashMap<Gate, HashMap<StructureId, Route>>, // Gate -> exit -> route
     pub gates_to_mouths: HashMap<Gate, HashMap<u16, Route>>,
     pub agent_path: HashMap<usize, BinaryHeap<PathSegment>>,
     pub agent_target: HashMap<usize, u16>,
+    pub accessibility_ratio: f64,
+    pub follow_probability: f64, // Agents joining the group of the previous one in their batch
+    pub layer_graph: LayerGraph,
+    pub layer_map: LayerMap,
+    pub services: Services,
+    pub clock: MatchClock,
+    pub phase: Phase,
+    pub seated: HashMap<usize, Agent>, // Agents at their mouth, by id
+    pub exited: usize,                 // Agents out through a gate after the match
+    pub mouths: HashMap<(LayerId, u16), StructureId>, // Mouths of every floor, also the ones of other processes
+    pub owners: HashMap<LayerId, usize>,              // Process simulating each floor
+    pub outbox: Vec<(Leg, Agent)>, // Agents changing floor, they land on exchange
+    pub balancer: Balancer,        // Floors per thread and process by measured cost
+    pub instrumentation: Instrumentation,
+    pub lifecycle: Lifecycle, // Fate of every agent
+    pub incidents: Incidents, // Scripted closures and their impact
+}
+
+// Exit -> entry on every reachable layer, by floor
+type Conexions = HashMap<LayerId, HashMap<StructureId, HashMap<LayerId, StructureId>>>;
+
+// Routes from a gate to the mouths and exits of its floor
+type GateRoutes = (Gate, HashMap<u16, Route>, HashMap<StructureId, Route>);
+
+// Floor moving to another process
+#[derive(Serialize, Deserialize)]
+struct Parcel {
+    layer: LayerId,
+    floor: stadium::Floor,
+    gates: Vec<(Gate, GateQueue)>,
+    seated: Vec<Agent>,
+    routes: Vec<GateRoutes>, // Gate routes changed by closures
 }
 
 impl World {
@@ -67,35 +117,119 @@ impl World {
     // }
 
     pub fn evolve(&mut self, interest: Uniform<f64>) -> usize {
-        // 0.3s per step, 200 steps make a minute. Agent arrival only executed once per minute
-        if self.step % 200 == 0 {
-            self.load_arrival(); // Agent arrivals
+        logging::set_step(self.step);
+
+        let mut times = StepTimes {
+            step: self.step,
+            ..Default::default()
+        };
+        let mut start = Instant::now();
+
+        self.load_arrival(); // Agent arrivals
+
+        times.arrivals = micros(start.elapsed());
+        start = Instant::now();
+
+        // People go through the turnstiles of every gate
+        self.gate_entrance();
+
+        times.gate_entrance = micros(start.elapsed());
+        start = Instant::now();
+
+        // Match timeline, behaviours are spawned when a phase starts
+        let phase = self.clock.phase(self.step);
+
+        if phase != self.phase {
+            info!(
+                World,
+                "{phase:?} at minute {:.1}",
+                self.clock.minute(self.step)
+            );
+
+            self.phase = phase;
+
+            match phase {
+                Phase::HalfTime => self.half_time(),
+                Phase::PostMatch => self.post_match(),
+                _ => {}
+            }
         }
 
-        // Gates queues are freeded progresively (one arrival per 15 secs)
-        if self.step % 25 == 0 {
-            self.gate_entrance(interest);
+        if self.step.is_multiple_of(self.clock.steps_per_minute()) {
+            self.sample_seating();
         }
 
-        // All floors are executed at the same time
-        let swapping_agents: HashMap<String, HashMap<Agent, Vec<usize>>> = HashMap::from_par_iter(
-            self.building
-                .par_iter_mut()
-                .map(|(layer, floor)| (layer.to_string(), floor.evolve_floor(interest))),
-        );
+        times.timeline = micros(start.elapsed());
+        start = Instant::now();
+
+        // Thread groups run at the same time, floors of a group one after the other
+        let registry = &self.registry;
+        let instrumented = self.instrumentation.enabled;
+        let step = self.step;
+
+        let evolved: Vec<(HashMap<Agent, Vec<usize>>, Duration, FloorTimes)> = self
+            .balancer
+            .schedule(&mut self.building)
+            .into_par_iter()
+            .flat_map_iter(|group| {
+                group.into_iter().map(|(layer, floor)| {
+                    let start = Instant::now();
+                    let leaving = floor.evolve_floor(interest, registry);
+                    let elapsed = start.elapsed();
+
+                    let (walking, in_connections, at_services) = match instrumented {
+                        true => floor.population(),
+                        false => (0, 0, 0),
+                    };
+
+                    let floor_times = FloorTimes {
+                        rank: 0,
+                        step,
+                        layer,
+                        evolve: micros(elapsed),
+                        conflicts: micros(floor.conflicts_time),
+                        walking,
+                        in_connections,
+                        at_services,
+                    };
+
+                    (leaving, elapsed, floor_times)
+                })
+            })
+            .collect();
+
+        let swapping_agents: HashMap<LayerId, HashMap<Agent, Vec<usize>>> =
+            HashMap::from_iter(evolved.into_iter().map(|(leaving, elapsed, floor_times)| {
+                let layer = floor_times.layer;
+
+                self.balancer.record(layer, elapsed);
+                self.instrumentation.push_floor(floor_times);
+
+                (layer, leaving)
+            }));
+
+        times.floors = micros(start.elapsed());
+        start = Instant::now();
 
         // Store local path
         self.save_local_paths(&swapping_agents);
 
-        // Move agents into buffers THIS DO NOT WORK
-        let swapped = self.swap_agents(HashMap::from_iter(swapping_agents.into_iter().map(
-            |(leaving_layer, agents)| {
-                (
-                    leaving_layer,
-                    agents.keys().into_iter().map(|ag| ag.to_owned()).collect(),
-                )
-            },
-        )));
+        times.saving = micros(start.elapsed());
+        start = Instant::now();
+
+        // Move agents into buffers, in id order so queues do not depend on map order
+        let mut leaving: Vec<(LayerId, Agent)> = swapping_agents
+            .into_iter()
+            .flat_map(|(layer, agents)| agents.into_keys().map(move |agent| (layer, agent)))
+            .collect();
+        leaving.sort_by_key(|(_, agent)| agent.id);
+
+        let swapped = self.swap_agents(leaving);
+
+        self.collect_lost();
+
+        times.swapping = micros(start.elapsed());
+        self.instrumentation.push_step(times);
 
         self.step += 1;
         swapped
@@ -103,25 +237,53 @@ impl World {
 
     // Arrivals are queued up for each gate
     fn load_arrival(&mut self) -> i32 {
-        let time = self.get_time();
         let mut total = 0;
 
-        if let Some(arrivals) = self.arrivals.get(&time) {
+        let gate_owned = |gate: &Gate| {
+            self.layer_map
+                .gate_layer(&gate.name)
+                .and_then(|layer| self.registry.layer_id(layer))
+                .is_some_and(|layer| self.owners.contains_key(&layer))
+        };
+
+        if let Some(arrivals) = self.arrivals.get(&self.step) {
+            // People of closed gates go to another one of the floor
+            let (arrivals, closed) = self.incidents.redirect(arrivals);
+
+            if self.lifecycle.rank == 0 {
+                closed.iter().for_each(|arrival| {
+                    self.lifecycle
+                        .lose_arrival(arrival, Reason::GateClosed, None)
+                });
+            }
+
             arrivals.iter().for_each(|arrival| {
                 // Remove untracked gates (VIP)
                 if let Some(gate) = self.gates.get(&Gate {
                     name: arrival.gate.to_string(),
                     ..Default::default()
                 }) {
-                    self.gates_buffer
-                        .get_mut(gate)
-                        .unwrap()
-                        .push_back(arrival.to_owned());
-
-                    total += 1;
+                    // Gates of floors simulated by another process are skipped
+                    if let Some(queue) = self.gates_buffer.get_mut(gate) {
+                        queue.push(arrival, self.step);
+                        total += 1;
+                    } else if self.lifecycle.rank == 0 && !gate_owned(gate) {
+                        warn!(
+                            World,
+                            "{} agents lost, gate {} on no floor", arrival.agents, gate.name
+                        );
+                        self.lifecycle
+                            .lose_arrival(arrival, Reason::GateOffFloor, None);
+                    }
                 } else {
                     // Delete arrival
-                    println!("[INFO] Wrong arrival {} agents", arrival.agents);
+                    info!(World, "Wrong arrival {} agents", arrival.agents);
+
+                    // Every process reads the arrivals, only one counts them
+                    if self.lifecycle.rank == 0 {
+                        self.lifecycle
+                            .lose_arrival(arrival, Reason::UnknownGate, None);
+                    }
                 }
             });
         }
@@ -129,115 +291,220 @@ impl World {
         total
     }
 
-    // Arrivals are converted into agents
-    fn gate_entrance(&mut self, interest: Uniform<f64>) -> usize {
+    // Batches through the turnstiles are converted into agents, groups never span two batches
+    fn gate_entrance(&mut self) -> usize {
         let mut total_inserted = 0;
 
-        self.gates_buffer.iter_mut().for_each(|(gate, queue)| {
-            if let Some(arrival) = queue.pop_front() {
-                match arrival.gate_layer() == arrival.mouth_layer() {
+        // Gates in name order, ids and draws do not depend on the map order
+        let mut gates: Vec<(&Gate, &mut GateQueue)> = self.gates_buffer.iter_mut().collect();
+        gates.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name));
+
+        gates.into_iter().for_each(|(gate, queue)| {
+            let gate_id = self.registry.gate_id(&gate.name).unwrap_or_default();
+            let mut turnstiles = draws(self.seed, self.step, gate_id as usize);
+
+            for arrival in queue.serve(
+                &gate.name,
+                self.step,
+                &self.turnstile_service,
+                &mut turnstiles,
+            ) {
+                // Draws of the batch, keyed by its first agent
+                let mut rng = draws(self.seed, self.step, arrival.first);
+
+                let (gate_layer, mouth_layer) = match (
+                    self.registry.layer_id(&arrival.gate_layer(&self.layer_map)),
+                    self.registry
+                        .layer_id(&arrival.mouth_layer(&self.layer_map)),
+                ) {
+                    (Some(gate_layer), Some(mouth_layer)) => (gate_layer, mouth_layer),
+                    // Gate or mouth outside the building, arrival is lost
+                    (gate_layer, _) => {
+                        warn!(World,
+                            "{} agents lost, gate {} or mouth {} on no floor",
+                            arrival.agents,
+                            arrival.gate,
+                            arrival.mouth
+                        );
+
+                        let reason = match gate_layer {
+                            Some(_) => Reason::MouthOffFloor,
+                            None => Reason::GateOffFloor,
+                        };
+                        self.lifecycle.lose_arrival(&arrival, reason, gate_layer);
+                        continue;
+                    }
+                };
+
+                match gate_layer == mouth_layer {
                     true => {
                         // Agent does not leave floor
-                        let floor = self.building.get_mut(&arrival.gate_layer()).unwrap();
+                        let floor = self.building.get_mut(&gate_layer).unwrap();
 
                         let gate_routes = self.gates_to_mouths.get(gate).unwrap();
+                        let target = match floor.mouths.get(&arrival.mouth) {
+                            Some(target) => *target,
+                            None => {
+                                warn!(Floor @ gate_layer,
+                                    "{} agents lost, mouth {} not found",
+                                    arrival.agents,
+                                    arrival.mouth
+                                );
+                                self.lifecycle.lose_arrival(
+                                    &arrival,
+                                    Reason::UnknownMouth,
+                                    Some(gate_layer),
+                                );
+                                continue;
+                            }
+                        };
 
                         if let Some(route) = gate_routes.get(&arrival.mouth) {
-                            let target = floor.mouths.get(&arrival.mouth).unwrap();
-
-                            let agents = arrival.generate_agents(
-                                target.to_owned(),
-                                self.agent_count,
-                                interest,
+                            let mut agents = arrival.generate_agents(
+                                target,
+                                self.accessibility_ratio,
+                                mouth_layer,
+                                &self.services,
+                                &mut rng,
                             );
+                            agents.iter_mut().for_each(|ag| ag.gate = gate_id);
+                            form_groups(&mut agents, self.follow_probability, &mut rng);
 
                             self.agent_count += agents.len();
-                            total_inserted += floor.insert_agents(&agents, route.to_owned());
+                            total_inserted +=
+                                floor.insert_agents(&agents, route.to_owned(), &self.registry);
                         } else {
                             // No precomputed path, another try is done
                             let origin = gate
                                 .structure
                                 .location
-                                .choose(&mut rand::thread_rng())
+                                .choose(&mut rng)
                                 .unwrap();
 
-                            let target = floor.mouths.get(&arrival.mouth).unwrap();
-
                             if let Some(path) = a_star(
                                 &floor.ground_truth,
                                 *origin,
-                                *target.location.choose(&mut rand::thread_rng()).unwrap(),
+                                *self
+                                    .registry
+                                    .structure(target)
+                                    .location
+                                    .choose(&mut rng)
+                                    .unwrap(),
                             ) {
-                                let agents = arrival.generate_agents(
-                                    target.to_owned(),
-                                    self.agent_count,
-                                    interest,
+                                let mut agents = arrival.generate_agents(
+                                    target,
+                                    self.accessibility_ratio,
+                                    mouth_layer,
+                                    &self.services,
+                                    &mut rng,
                                 );
+                                agents.iter_mut().for_each(|ag| ag.gate = gate_id);
+                                form_groups(&mut agents, self.follow_probability, &mut rng);
 
                                 self.agent_count += agents.len();
                                 total_inserted += agents.len();
 
-                                let agents_paths =
-                                    agents.iter().map(|agent| (agent.id, path.to_vec()));
-
-                                floor.agents_paths.extend(agents_paths);
-                                floor.agents.extend(agents.to_vec());
-
-                                total_inserted += agents.len();
+                                agents.into_iter().for_each(|agent| {
+                                    floor.insert_path(agent, path.to_vec(), &self.registry);
+                                });
+                            } else {
+                                warn!(Floor @ gate_layer,
+                                    "{} agents lost, no path from gate {} to mouth {}",
+                                    arrival.agents,
+                                    arrival.gate,
+                                    arrival.mouth
+                                );
+                                self.lifecycle.lose_arrival(
+                                    &arrival,
+                                    Reason::NoPathToMouth,
+                                    Some(gate_layer),
+                                );
                             }
                         }
                     }
                     false => {
-                        if let Some(floor) = self.building.get(&arrival.mouth_layer()) {
-                            let mouth_stairs: HashSet<Structure> =
-                                HashSet::from_iter(
-                                    floor.mouths_paths.get(&arrival.mouth).into_iter().flat_map(
-                                        |route| route.iter().map(|r| r.origin.to_owned()),
-                                    ),
+                        // Gate & mouth in different floor, trip planned over the whole building
+                        let mouth = match self.mouths.get(&(mouth_layer, arrival.mouth)) {
+                            Some(mouth) => *mouth,
+                            None => {
+                                warn!(World @ mouth_layer,
+                                    "{} agents lost, mouth {} not found",
+                                    arrival.agents,
+                                    arrival.mouth
+                                );
+                                self.lifecycle.lose_arrival(
+                                    &arrival,
+                                    Reason::UnknownMouth,
+                                    Some(mouth_layer),
                                 );
+                                continue;
+                            }
+                        };
+
+                        let gate_node = self.registry.layer_id(&gate.floor).and_then(|layer| {
+                            Some((layer, self.registry.id(layer, &gate.structure)?))
+                        });
+
+                        let floor = self.building.get_mut(&gate_layer).unwrap();
+                        let gate_routes = self.gates_to_stairs.get(gate).unwrap();
+
+                        let mut agents = arrival.generate_agents(
+                            mouth,
+                            self.accessibility_ratio,
+                            mouth_layer,
+                            &self.services,
+                            &mut rng,
+                        );
+                        agents.iter_mut().for_each(|ag| ag.gate = gate_id);
+                        form_groups(&mut agents, self.follow_probability, &mut rng);
+
+                        self.agent_count += agents.len();
 
-                            // Gate & mouth in different floor
-
-                            let floor = self.building.get_mut(&arrival.gate_layer()).unwrap();
-
-                            let level = self.building_conexions.get(&arrival.gate_layer()).unwrap();
-                            let gate_routes = self.gates_to_stairs.get(gate).unwrap();
-
-                            // Get only stairs connecting to destination_floor and access to destination mouth
-                            let search_space: Vec<Structure> = level
-                                .iter()
-                                .filter_map(|(structure, conexions)| {
-                                    match conexions.get(&arrival.mouth_layer()) {
-                                        Some(s) => match mouth_stairs.contains(s) {
-                                            true => Some(structure.to_owned()),
-                                            false => None,
-                                        },
-                                        None => None,
-                                    }
-                                })
-                                .collect();
-
-                            if let Some(target) =
-                                gate.structure.get_closest_structure(&search_space)
-                            {
-                                match gate_routes.get(&Route {
-                                    origin: gate.to_owned().structure,
-                                    destination: target.to_owned(),
-                                    ..Default::default()
-                                }) {
-                                    Some(route) => {
-                                        let agents = arrival.generate_agents(
-                                            target,
-                                            self.agent_count,
-                                            interest,
-                                        );
-
-                                        self.agent_count += agents.len();
-                                        total_inserted += agents.len();
-
-                                        floor.insert_agents(&agents, route.to_owned());
-                                    }
-                                    None => {}
+                        let (accessible, regular): (Vec<Agent>, Vec<Agent>) =
+                            agents.into_iter().partition(|ag| ag.accessibility);
+
+                        for (mut group, step_free) in [(accessible, true), (regular, false)] {
+                            if group.is_empty() {
+                                continue;
+                            }
+
+                            let trip = gate_node.and_then(|gate_node| {
+                                self.layer_graph
+                                    .plan(gate_node, (mouth_layer, mouth), step_free)
+                            });
+
+                            let route = trip.and_then(|(target, legs)| {
+                                Some((target, legs, gate_routes.get(&target)?))
+                            });
+
+                            match route {
+                                Some((target, legs, route)) => {
+                                    group.iter_mut().for_each(|ag| {
+                                        ag.target = target;
+                                        ag.plan = legs.to_owned();
+                                    });
+
+                                    total_inserted += floor.insert_agents(
+                                        &group,
+                                        route.to_owned(),
+                                        &self.registry,
+                                    );
+                                }
+                                None => {
+                                    let ids: Vec<usize> = group.iter().map(|ag| ag.id).collect();
+                                    warn!(World @ gate_layer,
+                                        "Agents {ids:?} lost, no trip from gate {} to mouth {} (step free: {step_free})",
+                                        arrival.gate,
+                                        arrival.mouth
+                                    );
+                                    group.iter().for_each(|ag| {
+                                        self.lifecycle.lose(
+                                            ag,
+                                            Reason::NoTrip,
+                                            gate_layer,
+                                            &self.registry,
+                                        )
+                                    });
                                 }
                             }
                         }
@@ -249,49 +516,651 @@ impl World {
         total_inserted
     }
 
-    fn swap_agents(&mut self, swap: HashMap<String, Vec<Agent>>) -> usize {
+    fn swap_agents(&mut self, swap: Vec<(LayerId, Agent)>) -> usize {
         let mut total_swaped = 0;
 
-        swap.into_iter().for_each(|(leaving_layer, agents)| {
-            agents.into_iter().for_each(|mut agent| {
-                if let Some(up_stair_cons) = self
-                    .building_conexions
-                    .get(&leaving_layer)
-                    .unwrap()
-                    .get(&agent.target)
-                {
-                    let destination_structure =
-                        up_stair_cons.get(&agent.destination_layer).unwrap();
-
-                    let destination_floor =
-                        self.building.get_mut(&agent.destination_layer).unwrap();
-
-                    agent.steps = 0;
-                    agent.target = destination_structure.to_owned();
-
-                    destination_floor.swap_buffer(&mut agent.to_owned(), destination_structure);
-                    total_swaped += 1;
-                } else {
-                    // Agent arrived at destination: end of path at destination layer
+        swap.into_iter().for_each(|(layer, mut agent)| {
+            // Next floor of the trip, on this process or another one
+            if let Some(leg) = agent.plan.pop_front() {
+                self.outbox.push((leg, agent));
+                total_swaped += 1;
+            } else if agent.egress {
+                // Agent left the venue through its gate
+                self.exited += 1;
+                self.lifecycle
+                    .record(&agent, Fate::Exited, layer, &self.registry);
+            } else {
+                // Agent arrived at destination: end of path at destination layer
+                self.seat(agent);
+            }
+        });
+
+        total_swaped
+    }
+
+    // Agent appears at the entry of its next floor
+    fn land(floor: &mut stadium::Floor, leg: Leg, mut agent: Agent) {
+        agent.target = leg.target;
+
+        floor.swap_buffer(&mut agent, leg.entry);
+    }
+
+    // Keeps the floors and gates of this process, agent ids are given on the root as people arrive
+    pub fn partition(&mut self, owners: HashMap<LayerId, usize>, rank: usize) {
+        let owned = |layer: Option<&String>| {
+            layer
+                .and_then(|layer| self.registry.layer_id(layer))
+                .and_then(|layer| owners.get(&layer))
+                == Some(&rank)
+        };
+
+        self.building
+            .retain(|layer, _| owners.get(layer) == Some(&rank));
+        self.gates_buffer
+            .retain(|gate, _| owned(self.layer_map.gate_layer(&gate.name)));
+
+        self.instrumentation.rank = rank;
+        self.lifecycle.rank = rank;
+        self.owners = owners;
+    }
+
+    // Agents changing floor are sent to the process of their next floor. Local and remote ones
+    // land together in id order, so connection queues do not depend on how floors are split
+    pub fn exchange(&mut self, communicator: &mut Communicator) {
+        let start = Instant::now();
+        let outbox = std::mem::take(&mut self.outbox);
+        let owners = &self.owners;
+
+        let mut landing = communicator.route(outbox, |(leg, _)| owners[&leg.layer]);
+        landing.sort_by_key(|(_, agent)| agent.id);
+
+        landing.into_iter().for_each(|(leg, agent)| {
+            World::land(self.building.get_mut(&leg.layer).unwrap(), leg, agent)
+        });
+
+        self.apply_incidents(communicator);
+
+        self.instrumentation.add_exchange(start.elapsed());
+    }
+
+    // Closures of the next step. Every process applies them, route changes of the floors are
+    // shared and then agents of every floor reroute
+    fn apply_incidents(&mut self, communicator: &mut Communicator) {
+        let incidents = self.incidents.due(self.step);
+
+        if incidents.is_empty() {
+            return;
+        }
+
+        logging::set_step(self.step);
+
+        let mut updates: Vec<RouteUpdate> = Vec::new();
+        let mut blocked: HashMap<LayerId, HashSet<usize>> = HashMap::new();
+
+        incidents.iter().for_each(|incident| {
+            info!(World, "Incident: {} closed", incident.describe());
+
+            match incident.kind {
+                Kind::Gate => self.close_gate(&incident.name),
+                Kind::Connection => self.close_connection(incident),
+                Kind::Area => {
+                    if let Some((layer, cells)) = self.close_area(incident, &mut updates) {
+                        blocked.entry(layer).or_default().extend(cells);
+                    }
+                }
+            }
+        });
+
+        let (rank, size) = (communicator.rank, communicator.size);
+        let outgoing: Vec<(usize, RouteUpdate)> = (0..size)
+            .filter(|other| *other != rank)
+            .flat_map(|other| updates.iter().map(move |update| (other, *update)))
+            .collect();
+
+        communicator
+            .route(outgoing, |(other, _)| *other)
+            .into_iter()
+            .for_each(|(_, update)| self.layer_graph.update_route(&update));
+
+        // Agents crossing closed cells or heading to closed structures
+        let none = HashSet::new();
+        let mut impact = Impact::default();
+
+        self.building.iter_mut().for_each(|(layer, floor)| {
+            impact.add(&floor.reroute(
+                blocked.get(layer).unwrap_or(&none),
+                &self.layer_graph,
+                &self.registry,
+                &self.mouths,
+            ));
+        });
+
+        self.incidents.impact(self.step).add(&impact);
+    }
+
+    // Gate node is closed for every trip, its line and later arrivals go to the closest open gate
+    fn close_gate(&mut self, name: &str) {
+        let gate = match self.gates.get(&Gate {
+            name: name.to_string(),
+            ..Default::default()
+        }) {
+            Some(gate) => gate.to_owned(),
+            None => {
+                warn!(World, "Gate {name} to close not found");
+                return;
+            }
+        };
+
+        let layer = self.registry.layer_id(&gate.floor);
+
+        if let Some(layer) = layer {
+            if let Some(id) = self.registry.id(layer, &gate.structure) {
+                self.layer_graph.close(layer, id);
+            }
+        }
+
+        // Replacements are searched again, the new closed gate may be one of them
+        self.incidents.redirects.insert(gate.name.to_string(), None);
+
+        let redirects: Vec<(String, Option<String>)> = self
+            .incidents
+            .redirects
+            .keys()
+            .filter_map(|closed| {
+                let closed = self.gates.get(&Gate {
+                    name: closed.to_string(),
+                    ..Default::default()
+                })?;
+
+                let open = self
+                    .gates
+                    .iter()
+                    .filter(|other| {
+                        other.floor == closed.floor
+                            && !self.incidents.redirects.contains_key(&other.name)
+                    })
+                    .min_by_key(|other| {
+                        other
+                            .structure
+                            .position
+                            .distance(&closed.structure.position)
+                    })
+                    .map(|other| other.name.to_string());
+
+                Some((closed.name.to_string(), open))
+            })
+            .collect();
+
+        self.incidents.redirects.extend(redirects);
+
+        // Only the process of the gate holds its line
+        let waiting = match self.gates_buffer.get_mut(&gate) {
+            Some(queue) => queue.close(&gate.name),
+            None => return,
+        };
+
+        let (redirected, lost) = self.incidents.redirect(&waiting);
+        let step = self.step;
+
+        redirected.iter().for_each(|arrival| {
+            self.incidents.impact(step).redirected += arrival.agents as usize;
+
+            if let Some(queue) = self.gates_buffer.get_mut(&Gate {
+                name: arrival.gate.to_string(),
+                ..Default::default()
+            }) {
+                queue.push(arrival, step);
+            }
+        });
+
+        lost.iter().for_each(|arrival| {
+            warn!(
+                World,
+                "{} agents lost, gate {} closed with no other gate on its floor",
+                arrival.agents,
+                arrival.gate
+            );
+            self.incidents.impact(step).lost += arrival.agents as usize;
+            self.lifecycle
+                .lose_arrival(arrival, Reason::GateClosed, layer);
+        });
+    }
+
+    // Stair, ramp, escalator or elevator closed in the building graph
+    fn close_connection(&mut self, incident: &Incident) {
+        let connection = self.registry.layer_id(&incident.layer).and_then(|layer| {
+            Some((
+                layer,
+                self.registry
+                    .structure_at(layer, incident.cell(), &CONNECTIONS)?,
+            ))
+        });
+
+        match connection {
+            Some((layer, connection)) => self.layer_graph.close(layer, connection),
+            None => warn!(World, "No connection to close at {}", incident.describe()),
+        }
+    }
+
+    // Cells become walls on the process of the floor, routes through them are searched again.
+    // Route changes are returned to the other processes with the closed cells
+    fn close_area(
+        &mut self,
+        incident: &Incident,
+        updates: &mut Vec<RouteUpdate>,
+    ) -> Option<(LayerId, HashSet<usize>)> {
+        let layer = match self.registry.layer_id(&incident.layer) {
+            Some(layer) => layer,
+            None => {
+                warn!(World, "Unknown layer of {}", incident.describe());
+                return None;
+            }
+        };
+
+        let floor = self.building.get_mut(&layer)?;
+        let cells = incident.cells();
+
+        let mut changed = floor.close_cells(&cells);
+
+        // Routes from the gates of the floor
+        let (gt, mouths) = (&floor.ground_truth, &floor.mouths);
+
+        self.gates
+            .iter()
+            .filter(|gate| gate.floor == incident.layer)
+            .for_each(|gate| {
+                let origin = match self.registry.id(layer, &gate.structure) {
+                    Some(origin) => origin,
+                    None => return,
+                };
+
+                if let Some(routes) = self.gates_to_mouths.get_mut(gate) {
+                    changed.extend(avoid_closed(routes, gt, &cells, |mouth, cost| {
+                        RouteUpdate {
+                            layer,
+                            ends: (origin, mouths[&mouth]),
+                            roles: (Role::Gate, Role::Mouth),
+                            cost,
+                        }
+                    }));
+                }
+
+                if let Some(routes) = self.gates_to_stairs.get_mut(gate) {
+                    changed.extend(avoid_closed(routes, gt, &cells, |exit, cost| RouteUpdate {
+                        layer,
+                        ends: (origin, exit),
+                        roles: (Role::Gate, Role::Exit),
+                        cost,
+                    }));
                 }
             });
+
+        changed
+            .iter()
+            .for_each(|update| self.layer_graph.update_route(update));
+
+        self.incidents.impact(self.step).count_routes(&changed);
+        self.incidents.closed_areas.insert(layer);
+        updates.extend(changed);
+
+        Some((layer, cells))
+    }
+
+    // Every rebalancing_freq steps floors move to the least loaded processes and threads
+    pub fn rebalance(&mut self, communicator: &mut Communicator) {
+        if !self.balancer.due(self.step) {
+            return;
+        }
+
+        let costs = self.balancer.take_costs();
+
+        if communicator.size > 1 {
+            // Root decides with the costs of every process
+            let decision = communicator.gather(costs.to_owned()).map(|all| {
+                let costs: HashMap<LayerId, u64> = all.into_iter().flatten().collect();
+                let unbalance = partition::unbalance(&costs, &self.owners);
+
+                let owners = (unbalance > self.balancer.max_unbalance).then(|| {
+                    partition::split_floors(
+                        costs
+                            .iter()
+                            .map(|(layer, cost)| (*layer, *cost as usize))
+                            .collect(),
+                        communicator.size,
+                        true,
+                    )
+                });
+
+                // Floors only move if the new split is better
+                owners
+                    .map(|owners| (partition::unbalance(&costs, &owners), owners))
+                    .filter(|(balanced, _)| *balanced < unbalance)
+                    .map(|(balanced, owners)| {
+                        info!(
+                            World,
+                            "Rebalancing at step {}: {unbalance:.0}% unbalance, now {balanced:.0}%",
+                            self.step
+                        );
+                        owners
+                    })
+            });
+
+            if let Some(owners) = communicator.broadcast(decision) {
+                self.migrate(owners, communicator);
+            }
+        }
+
+        self.balancer.pack(
+            &costs,
+            self.building.keys().copied().collect(),
+            rayon::current_num_threads(),
+        );
+    }
+
+    // Floors changing process travel with their queues and seated agents
+    fn migrate(&mut self, owners: HashMap<LayerId, usize>, communicator: &mut Communicator) {
+        let rank = communicator.rank;
+
+        let leaving: Vec<LayerId> = self
+            .building
+            .keys()
+            .filter(|layer| owners[layer] != rank)
+            .copied()
+            .collect();
+
+        let parcels: Vec<Parcel> = leaving
+            .into_iter()
+            .map(|layer| {
+                let name = self.registry.layer_name(layer);
+
+                let gates: Vec<Gate> = self
+                    .gates_buffer
+                    .keys()
+                    .filter(|gate| {
+                        self.layer_map.gate_layer(&gate.name).map(|l| l.as_str()) == Some(name)
+                    })
+                    .cloned()
+                    .collect();
+
+                let seated: Vec<usize> = self
+                    .seated
+                    .values()
+                    .filter(|agent| agent.destination_layer == layer)
+                    .map(|agent| agent.id)
+                    .collect();
+
+                // Routes of the gates only differ from the other processes after closures
+                let routes = match self.incidents.closed_areas.contains(&layer) {
+                    true => gates
+                        .iter()
+                        .map(|gate| {
+                            (
+                                gate.to_owned(),
+                                self.gates_to_mouths[gate].to_owned(),
+                                self.gates_to_stairs[gate].to_owned(),
+                            )
+                        })
+                        .collect(),
+                    false => Vec::new(),
+                };
+
+                Parcel {
+                    layer,
+                    floor: self.building.remove(&layer).unwrap(),
+                    gates: gates
+                        .into_iter()
+                        .map(|gate| self.gates_buffer.remove_entry(&gate).unwrap())
+                        .collect(),
+                    seated: seated
+                        .into_iter()
+                        .map(|id| self.seated.remove(&id).unwrap())
+                        .collect(),
+                    routes,
+                }
+            })
+            .collect();
+
+        communicator
+            .route(parcels, |parcel| owners[&parcel.layer])
+            .into_iter()
+            .for_each(|parcel| {
+                self.building.insert(parcel.layer, parcel.floor);
+                self.gates_buffer.extend(parcel.gates);
+                self.seated
+                    .extend(parcel.seated.into_iter().map(|agent| (agent.id, agent)));
+
+                if !parcel.routes.is_empty() {
+                    self.incidents.closed_areas.insert(parcel.layer);
+                }
+
+                parcel
+                    .routes
+                    .into_iter()
+                    .for_each(|(gate, mouths, stairs)| {
+                        self.gates_to_mouths.insert(gate.to_owned(), mouths);
+                        self.gates_to_stairs.insert(gate, stairs);
+                    });
+            });
+
+        self.owners = owners;
+    }
+
+    // Floors and results of another process are joined into this world
+    pub fn merge(&mut self, part: World) {
+        self.building.extend(part.building);
+        self.gates_buffer.extend(part.gates_buffer);
+
+        part.agent_path.into_iter().for_each(|(id, segments)| {
+            self.agent_path.entry(id).or_default().extend(segments);
         });
 
-        total_swaped
+        self.agent_target.extend(part.agent_target);
+        self.seated.extend(part.seated);
+        self.agent_count += part.agent_count;
+        self.exited += part.exited;
+        self.instrumentation.merge(part.instrumentation);
+        self.lifecycle.merge(part.lifecycle);
+        self.incidents.merge(part.incidents);
+    }
+
+    // Agents dropped by the floors are accounted for
+    fn collect_lost(&mut self) {
+        self.building.iter_mut().for_each(|(layer, floor)| {
+            floor.lost.drain(..).for_each(|(agent, reason)| {
+                self.lifecycle.lose(&agent, reason, *layer, &self.registry)
+            });
+        });
     }
 
-    fn save_local_paths(&mut self, paths: &HashMap<String, HashMap<Agent, Vec<usize>>>) {
+    // Agents still in the simulation get their last state, done once every floor is merged
+    pub fn census(&mut self) {
+        self.collect_lost();
+
+        self.building.iter().for_each(|(layer, floor)| {
+            floor.census().for_each(|(agent, fate)| {
+                self.lifecycle.record(agent, fate, *layer, &self.registry)
+            });
+        });
+
+        self.seated.values().for_each(|agent| {
+            self.lifecycle
+                .record(agent, Fate::Seated, agent.destination_layer, &self.registry)
+        });
+
+        self.gates_buffer.iter().for_each(|(gate, queue)| {
+            if let Some(layer) = self.registry.layer_id(&gate.floor) {
+                self.lifecycle.queued(&gate.name, queue.pending(), layer);
+            }
+        });
+    }
+
+    // Agents reaching their mouth claim a seat and walk to its block, the ones without a block
+    // stay at the mouth. Agents leave right away once the match is over
+    fn seat(&mut self, mut agent: Agent) {
+        if self.phase == Phase::PostMatch {
+            return self.leave(agent);
+        }
+
+        if agent.seat.is_none() {
+            let floor = self.building.get_mut(&agent.destination_layer).unwrap();
+
+            let claim = floor.seating.claim(agent.destination);
+            let mut rng = draws(self.seed, self.step, agent.id);
+
+            if let Some((block, aisle)) =
+                claim.and_then(|block| Some((block, floor.seating.aisle(block, &mut rng)?)))
+            {
+                agent.seat = Some(block);
+                floor.insert_path(agent, aisle, &self.registry);
+                return;
+            }
+        }
+
+        self.seated.insert(agent.id, agent);
+    }
+
+    // Agents in every seat block of the process
+    fn sample_seating(&mut self) {
+        let mut occupied: HashMap<LayerId, HashMap<u32, u32>> = HashMap::new();
+
+        self.seated.values().for_each(|agent| {
+            if let Some(block) = agent.seat {
+                *occupied
+                    .entry(agent.destination_layer)
+                    .or_default()
+                    .entry(block)
+                    .or_default() += 1;
+            }
+        });
+
+        self.building.iter_mut().for_each(|(layer, floor)| {
+            if !floor.seating.blocks.is_empty() {
+                floor
+                    .seating
+                    .sample(self.step, occupied.get(layer).unwrap_or(&HashMap::new()));
+            }
+        });
+    }
+
+    // Seated agents go to bars, restrooms and kiosks and come back to their mouth
+    fn half_time(&mut self) {
+        let factor = self.clock.timeline.half_time_visits;
+        let mut visiting = 0;
+
+        let mut ids: Vec<usize> = self.seated.keys().copied().collect();
+        ids.sort_unstable();
+
+        ids.into_iter().for_each(|id| {
+            let mut rng = draws(self.seed, self.step, id);
+            let agenda = self.services.agenda(factor, &mut rng);
+
+            if agenda.is_empty() {
+                return;
+            }
+
+            let mut agent = self.seated.remove(&id).unwrap();
+            let floor = self.building.get_mut(&agent.destination_layer).unwrap();
+
+            match floor.mouths.get(&agent.destination) {
+                Some(mouth) => {
+                    // Agents in a block come back to it
+                    let seat = agent
+                        .seat
+                        .and_then(|block| floor.seating.aisle(block, &mut rng)?.last().copied())
+                        .unwrap_or_else(|| {
+                            *self
+                                .registry
+                                .structure(*mouth)
+                                .location
+                                .choose(&mut rng)
+                                .unwrap()
+                        });
+
+                    agent.agenda = agenda;
+                    floor.insert_path(agent, vec![seat], &self.registry);
+                    visiting += 1;
+                }
+                None => {
+                    self.seated.insert(id, agent);
+                }
+            }
+        });
+
+        info!(World, "Half-time: {visiting} agents leave their seats");
+    }
+
+    // Every seated agent leaves the venue
+    fn post_match(&mut self) {
+        let leaving: Vec<Agent> = self.seated.drain().map(|(_, agent)| agent).collect();
+
+        info!(World, "Post-match: {} agents leave", leaving.len());
+
+        leaving.into_iter().for_each(|agent| self.leave(agent));
+    }
+
+    // Egress from the agent's mouth to the closest gate. Agents without a way out stay seated
+    fn leave(&mut self, mut agent: Agent) {
+        let floor = self.building.get_mut(&agent.destination_layer).unwrap();
+
+        let mouth = match floor.mouths.get(&agent.destination) {
+            Some(mouth) => *mouth,
+            None => {
+                debug!(World @ agent.destination_layer,
+                    "Agent {} stays seated, mouth {} not found",
+                    agent.id,
+                    agent.destination
+                );
+                self.seated.insert(agent.id, agent);
+                return;
+            }
+        };
+
+        let trip = self
+            .layer_graph
+            .egress((agent.destination_layer, mouth), agent.accessibility);
+
+        // Agents in a block walk their aisle back to the mouth first
+        let mut rng = draws(self.seed, self.step, agent.id);
+        let aisle = agent
+            .seat
+            .and_then(|block| floor.seating.aisle(block, &mut rng))
+            .unwrap_or_default();
+
+        match trip.and_then(|(target, legs)| {
+            floor
+                .path_between(&self.registry, mouth, target, &mut rng)
+                .map(|path| (target, legs, path))
+        }) {
+            Some((target, legs, path)) => {
+                agent.egress = true;
+                agent.agenda.clear();
+                agent.visit = None;
+                agent.target = target;
+                agent.plan = legs;
+
+                let path = aisle.into_iter().rev().chain(path).collect();
+                floor.insert_path(agent, path, &self.registry);
+            }
+            None => {
+                debug!(World @ agent.destination_layer,
+                    "Agent {} stays seated, no way out from mouth {}",
+                    agent.id,
+                    agent.destination
+                );
+                self.seated.insert(agent.id, agent);
+            }
+        }
+    }
+
+    fn save_local_paths(&mut self, paths: &HashMap<LayerId, HashMap<Agent, Vec<usize>>>) {
         paths.iter().for_each(|(layer, agents)| {
             agents.iter().for_each(|(agent, local_path)| {
-                let global_path = self
-                    .agent_path
-                    .entry(agent.id)
-                    .or_insert_with(BinaryHeap::new);
+                let global_path = self.agent_path.entry(agent.id).or_default();
 
                 global_path.push(PathSegment::new(
                     agent,
                     local_path.to_vec(),
-                    layer,
+                    *layer,
                     self.step,
                 ));
 
@@ -303,83 +1172,222 @@ impl World {
         });
     }
 
-    fn get_time(&mut self) -> i32 {
-        (self.step as i32 / 100) - 150
+    // Agents inside inter-floor connections and agents queued at their entrances
+    pub fn connections_load(&self) -> (usize, usize) {
+        self.building
+            .values()
+            .flat_map(|floor| floor.structures_buffer.values())
+            .fold((0, 0), |(moving, queued), transit| {
+                (
+                    moving + transit.occupancy(),
+                    queued + transit.queue_length(),
+                )
+            })
     }
 
     // Creates a CSV for visualization purposes
-    pub fn generate_save(&mut self) {
-        File::create("resources/paths.csv").unwrap();
-
-        let file = BufWriter::new(
-            fs::OpenOptions::new()
-                .write(true)
-                .append(true)
-                .open("resources/paths.csv")
-                .unwrap(),
-        );
+    pub fn generate_save(&mut self, path: &str) {
+        File::create(path).unwrap();
+
+        let file = BufWriter::new(fs::OpenOptions::new().append(true).open(path).unwrap());
 
         let mut writter = csv::Writer::from_writer(file);
 
         writter
-            .write_record(&["agent_id", "x", "y", "layer", "step", "target_mouth"])
+            .write_record(["agent_id", "x", "y", "layer", "step", "target_mouth"])
             .unwrap();
 
         self.agent_path.iter_mut().for_each(|(agent_id, path)| {
             let target_layer = self.agent_target.get(agent_id).unwrap();
 
-            saving::generate_path(*agent_id, path, target_layer)
+            saving::generate_path(*agent_id, path, target_layer, self.registry.layers())
                 .into_iter()
                 .for_each(|record| writter.write_record(record).unwrap());
         });
     }
 
-    pub fn save_structures(&self) {
-        println!("[INFO] Saving structures...");
+    // Gate queues: summary per gate and queue length along the simulation
+    pub fn save_gates_report(&self, summary_path: &str, queues_path: &str) {
+        let mut summary =
+            csv::Writer::from_writer(BufWriter::new(File::create(summary_path).unwrap()));
+
+        summary
+            .write_record([
+                "gate",
+                "turnstiles",
+                "served",
+                "in_queue",
+                "mean_wait_steps",
+                "max_wait_steps",
+                "max_queue",
+            ])
+            .unwrap();
+
+        let mut series =
+            csv::Writer::from_writer(BufWriter::new(File::create(queues_path).unwrap()));
+
+        series.write_record(["gate", "step", "queue"]).unwrap();
+
+        self.gates_buffer.iter().for_each(|(gate, queue)| {
+            summary
+                .write_record(&[
+                    gate.name.to_string(),
+                    format!("{}", queue.turnstiles),
+                    format!("{}", queue.stats.served),
+                    format!("{}", queue.queue_length()),
+                    format!("{:.2}", queue.stats.mean_wait()),
+                    format!("{}", queue.stats.max_wait),
+                    format!("{}", queue.stats.max_queue),
+                ])
+                .unwrap();
+
+            queue.stats.queue_series.iter().for_each(|(step, length)| {
+                series
+                    .write_record(&[
+                        gate.name.to_string(),
+                        format!("{step}"),
+                        format!("{length}"),
+                    ])
+                    .unwrap();
+            });
+        });
+    }
+
+    // Visits and queues at bars, restrooms and kiosks
+    pub fn save_services_report(&self, path: &str) {
+        let mut writter = csv::Writer::from_writer(BufWriter::new(File::create(path).unwrap()));
+
+        writter
+            .write_record(["layer", "service", "x", "y", "visits", "in_queue"])
+            .unwrap();
+
+        self.building.iter().for_each(|(layer, floor)| {
+            floor.services.iter().for_each(|(id, point)| {
+                let structure = self.registry.structure(*id);
+
+                writter
+                    .write_record(&[
+                        self.registry.layer_name(*layer).to_string(),
+                        format!("{:?}", Service::from_code(structure.code).unwrap()),
+                        format!("{}", structure.position.x),
+                        format!("{}", structure.position.y),
+                        format!("{}", point.visits),
+                        format!("{}", point.queue_length()),
+                    ])
+                    .unwrap();
+            });
+        });
+    }
+
+    // Seats claimed and agents seated in every block, once per match minute
+    pub fn save_seating_report(&self, path: &str) {
+        let mut writter = csv::Writer::from_writer(BufWriter::new(File::create(path).unwrap()));
+
+        writter
+            .write_record([
+                "step", "minute", "layer", "mouth", "block", "seats", "claimed", "occupied",
+            ])
+            .unwrap();
+
+        let (mut seats, mut claimed, mut turned_away) = (0, 0, 0);
+
+        self.building.iter().for_each(|(layer, floor)| {
+            let seating = &floor.seating;
+
+            seating.samples.iter().for_each(|sample| {
+                let block = &seating.blocks[sample.block as usize];
+
+                writter
+                    .write_record(&[
+                        format!("{}", sample.step),
+                        format!("{:.1}", self.clock.minute(sample.step)),
+                        self.registry.layer_name(*layer).to_string(),
+                        format!("{}", block.mouth),
+                        block.name.to_string(),
+                        format!("{}", block.seats),
+                        format!("{}", sample.claimed),
+                        format!("{}", sample.occupied),
+                    ])
+                    .unwrap();
+            });
+
+            seats += seating.blocks.iter().map(|block| block.seats).sum::<u32>();
+            claimed += seating
+                .blocks
+                .iter()
+                .map(|block| block.claimed)
+                .sum::<u32>();
+            turned_away += seating.turned_away;
+        });
+
+        if seats > 0 {
+            info!(
+                World,
+                "Seats claimed: {claimed} of {seats}, {turned_away} agents at full mouths"
+            );
+        }
+    }
+
+    pub fn save_structures(&self, path: &str) {
+        info!(World, "Saving structures...");
         let mut data = HashMap::new();
 
         self.building.iter().for_each(|(layer, floor)| {
-            data.insert(layer, floor.structures.to_owned());
+            data.insert(
+                self.registry.layer_name(*layer),
+                floor.structures.to_owned(),
+            );
         });
 
-        let file1 = BufWriter::new(File::create("resources/stairs.json").unwrap()); // All structures by layer
+        let file1 = BufWriter::new(File::create(path).unwrap()); // All structures by layer
         serde_json::to_writer_pretty(file1, &data).expect("");
     }
 
-    pub fn save_layer_paths(&self) {
-        println!("[INFO] Saving paths...");
+    pub fn save_layer_paths(&self, dir: &str) {
+        info!(World, "Saving paths...");
+
+        fs::create_dir_all(format!("{dir}/stairs_paths")).unwrap();
+        fs::create_dir_all(format!("{dir}/mouths_paths")).unwrap();
 
         // All routes in layer
         for (layer, floor) in &self.building {
-            let file1 = File::create(format!("resources/stairs_paths/{layer}.json")).unwrap();
+            let layer = self.registry.layer_name(*layer);
+            let file1 = File::create(format!("{dir}/stairs_paths/{layer}.json")).unwrap();
 
-            let data1: Vec<Route> = floor.structures_paths.iter().cloned().collect();
+            let data1: Vec<&Route> = floor.structures_paths.values().collect();
 
             serde_json::to_writer_pretty(file1, &data1).expect("");
 
-            let file2 = File::create(format!("resources/mouths_paths/{layer}.json")).unwrap();
+            let file2 = File::create(format!("{dir}/mouths_paths/{layer}.json")).unwrap();
+
+            // Routes to each mouth by mouth number
+            let data2: HashMap<u16, Vec<&Route>> =
+                HashMap::from_iter(floor.mouths.iter().filter_map(|(mouth, id)| {
+                    Some((*mouth, floor.mouths_paths.get(id)?.values().collect()))
+                }));
 
-            serde_json::to_writer_pretty(file2, &floor.mouths_paths).expect("");
+            serde_json::to_writer_pretty(file2, &data2).expect("");
         }
     }
 
     // HPC environment saving (Who cares about humans)
-    pub fn bincode_save(&self) {
+    pub fn bincode_save(&self, path: &str) {
         let start = Instant::now();
         // Save building completely
 
-        let mut file = BufWriter::new(File::create("resources/IoTwins.bin").unwrap());
+        let mut file = BufWriter::new(File::create(path).unwrap());
 
         serialize_into(&mut file, &self).unwrap();
 
-        println!("[INFO] Time elapsed: {:?}", start.elapsed());
+        info!(World, "Time elapsed: {:?}", start.elapsed());
     }
 
-    // Paths between gates and stairs in layer
+    // Paths between gates and exits (stairs, ramps, escalators and elevators) in layer
     fn gates_stairs(
-        building: &HashMap<String, stadium::Floor>,
+        building: &HashMap<LayerId, stadium::Floor>,
         gates: &HashSet<Gate>,
-    ) -> HashMap<Gate, HashSet<Route>> {
+        registry: &Registry,
+    ) -> HashMap<Gate, HashMap<StructureId, Route>> {
         // Progress bar
         let progress_bar = ProgressBar::new(gates.len().try_into().unwrap());
 
@@ -395,14 +1403,17 @@ impl World {
         // End of progress bar
 
         let routes = gates.iter().progress_with(progress_bar).map(|gate| {
-            let floor = building.get(&gate.floor).unwrap();
-            let up_stairs = floor.structures.get(&11).unwrap();
-
-            let stairs_paths = up_stairs
-                .par_iter()
-                .filter_map(|p2| find_route(&floor.ground_truth, &gate.structure, p2));
+            let layer = registry.layer_id(&gate.floor).unwrap();
+            let floor = building.get(&layer).unwrap();
+            let exits: Vec<&Structure> =
+                stadium::Floor::filter_structures(&floor.structures, &EXITS).collect();
+
+            let stairs_paths = exits.par_iter().filter_map(|p2| {
+                find_route(&floor.ground_truth, &gate.structure, p2)
+                    .map(|route| (registry.id(layer, p2).unwrap(), route))
+            });
 
-            (gate.to_owned(), HashSet::from_par_iter(stairs_paths))
+            (gate.to_owned(), HashMap::from_par_iter(stairs_paths))
         });
 
         HashMap::from_iter(routes)
@@ -410,8 +1421,9 @@ impl World {
 
     // Paths between gates and up-stairs in layer
     fn gates_mouths(
-        building: &HashMap<String, stadium::Floor>,
+        building: &HashMap<LayerId, stadium::Floor>,
         gates: &HashSet<Gate>,
+        registry: &Registry,
     ) -> HashMap<Gate, HashMap<u16, Route>> {
         // Progress bar
         let progress_bar = ProgressBar::new(gates.len().try_into().unwrap());
@@ -428,10 +1440,17 @@ impl World {
         // End of progress bar
 
         let routes = gates.iter().progress_with(progress_bar).map(|gate| {
-            let floor = building.get(&gate.floor).expect("");
+            let floor = building
+                .get(&registry.layer_id(&gate.floor).unwrap())
+                .expect("");
 
             let gate_routes = floor.mouths.par_iter().filter_map(|(id, mouth)| {
-                find_route(&floor.ground_truth, &gate.structure, mouth).map(|route| (*id, route))
+                find_route(
+                    &floor.ground_truth,
+                    &gate.structure,
+                    registry.structure(*mouth),
+                )
+                .map(|route| (*id, route))
             });
 
             (gate.to_owned(), HashMap::from_par_iter(gate_routes))
@@ -440,17 +1459,212 @@ impl World {
         HashMap::from_iter(routes)
     }
 
+    // Building graph for multi-floor trips, travelling through a connection costs its traversal
+    fn layer_graph(
+        building: &HashMap<LayerId, stadium::Floor>,
+        conexions: &Conexions,
+        gates_to_stairs: &HashMap<Gate, HashMap<StructureId, Route>>,
+        gates_to_mouths: &HashMap<Gate, HashMap<u16, Route>>,
+        registry: &Registry,
+    ) -> LayerGraph {
+        let mut graph = LayerGraph::default();
+
+        let gate_node = |gate: &Gate| {
+            let layer = registry.layer_id(&gate.floor).unwrap();
+            (layer, registry.id(layer, &gate.structure).unwrap())
+        };
+
+        // Same floor trips, only walked backwards by leaving agents
+        gates_to_mouths.iter().for_each(|(gate, routes)| {
+            let (layer, origin) = gate_node(gate);
+            let mouths = &building.get(&layer).unwrap().mouths;
+
+            routes.iter().for_each(|(mouth, route)| {
+                graph.add_route(
+                    layer,
+                    (origin, *mouths.get(mouth).unwrap()),
+                    (Role::Gate, Role::Mouth),
+                    route,
+                )
+            });
+        });
+
+        gates_to_stairs.iter().for_each(|(gate, routes)| {
+            let (layer, origin) = gate_node(gate);
+
+            routes.iter().for_each(|(exit, route)| {
+                graph.add_route(layer, (origin, *exit), (Role::Gate, Role::Exit), route)
+            });
+        });
+
+        building.iter().for_each(|(layer, floor)| {
+            floor.structures_paths.iter().for_each(|(ends, route)| {
+                graph.add_route(*layer, *ends, (Role::Entry, Role::Exit), route)
+            });
+
+            floor.mouths_paths.iter().for_each(|(mouth, routes)| {
+                routes.iter().for_each(|(entry, route)| {
+                    graph.add_route(*layer, (*entry, *mouth), (Role::Entry, Role::Mouth), route)
+                });
+            });
+        });
+
+        conexions.iter().for_each(|(layer, exits)| {
+            exits.iter().for_each(|(exit, destinations)| {
+                destinations.iter().for_each(|(destination_layer, entry)| {
+                    let traversal = building
+                        .get(destination_layer)
+                        .and_then(|floor| floor.structures_buffer.get(entry))
+                        .map_or(0, |transit| transit.link.traversal);
+
+                    graph.add_link(
+                        (*layer, *exit),
+                        (*destination_layer, *entry),
+                        registry.structure(*exit).kind(),
+                        1 + traversal as u64,
+                    );
+                });
+            });
+        });
+
+        graph.add_rides(|structure| {
+            registry.structure(structure).kind() == Some(ConnectionKind::Elevator)
+        });
+
+        graph
+    }
+
+    // Links between layers given by the venue topology. Every mapping is validated, wrong ones are discarded
+    fn map_structures(
+        building: &HashMap<LayerId, stadium::Floor>,
+        path: &str,
+        registry: &Registry,
+    ) -> Conexions {
+        let mut conexions: Conexions =
+            HashMap::from_iter(building.keys().map(|layer| (*layer, HashMap::new())));
+
+        let mut discarded = 0;
+
+        for (line, mapping) in load_connections(path).into_iter().enumerate() {
+            let (layer, destination_layer) = match (
+                registry.layer_id(&mapping.layer),
+                registry.layer_id(&mapping.destination_layer),
+            ) {
+                (Some(layer), Some(destination_layer)) if layer != destination_layer => {
+                    (layer, destination_layer)
+                }
+                _ => {
+                    warn!(
+                        World,
+                        "Connection {line}: wrong layers {} -> {}",
+                        mapping.layer,
+                        mapping.destination_layer
+                    );
+                    discarded += 1;
+                    continue;
+                }
+            };
+
+            let exit = building[&layer].structure_at(&EXITS, mapping.cell);
+            let entry =
+                building[&destination_layer].structure_at(&ENTRIES, mapping.destination_cell);
+
+            let (exit, entry) = match (exit, entry) {
+                (Some(exit), Some(entry)) => (exit, entry),
+                (None, _) => {
+                    warn!(
+                        World,
+                        "Connection {line}: no exit at cell {} on {}", mapping.cell, mapping.layer
+                    );
+                    discarded += 1;
+                    continue;
+                }
+                (_, None) => {
+                    warn!(
+                        World,
+                        "Connection {line}: no entry at cell {} on {}",
+                        mapping.destination_cell,
+                        mapping.destination_layer
+                    );
+                    discarded += 1;
+                    continue;
+                }
+            };
+
+            // A stair does not land on an elevator
+            if exit.kind().map(|kind| kind.entry_code()) != Some(entry.code) {
+                warn!(
+                    World,
+                    "Connection {line}: {:?} on {} lands on {:?} on {}",
+                    exit.kind(),
+                    mapping.layer,
+                    entry.kind(),
+                    mapping.destination_layer
+                );
+                discarded += 1;
+                continue;
+            }
+
+            let exits = conexions
+                .get_mut(&layer)
+                .unwrap()
+                .entry(registry.id(layer, exit).unwrap())
+                .or_default();
+
+            if let Some(previous) = exits.insert(
+                destination_layer,
+                registry.id(destination_layer, entry).unwrap(),
+            ) {
+                warn!(
+                    World,
+                    "Connection {line}: exit {:?} on {} already led to {:?} on {}, overwritten",
+                    exit.position,
+                    mapping.layer,
+                    registry.structure(previous).position,
+                    mapping.destination_layer
+                );
+            }
+        }
+
+        // Exits without destination, agents will never use them
+        building.iter().for_each(|(layer, floor)| {
+            let unmapped = stadium::Floor::filter_structures(&floor.structures, &EXITS)
+                .filter(|exit| {
+                    !conexions
+                        .get(layer)
+                        .unwrap()
+                        .contains_key(&registry.id(*layer, exit).unwrap())
+                })
+                .count();
+
+            if unmapped > 0 {
+                info!(
+                    World,
+                    "{}: {unmapped} exits without connection",
+                    registry.layer_name(*layer)
+                );
+            }
+        });
+
+        info!(World, "Connections loaded, {discarded} discarded");
+
+        conexions
+    }
+
     // For each structure in floor gets their destination (Links stairs between layers). Generates proper global structure between them all
+    // Nearest-neighbour heuristic, only used when the venue has no explicit topology
     fn connect_structures(
-        building: &HashMap<String, stadium::Floor>,
-    ) -> HashMap<String, HashMap<Structure, HashMap<String, Structure>>> {
+        building: &HashMap<LayerId, stadium::Floor>,
+        registry: &Registry,
+    ) -> Conexions {
         let conexions = building.iter().map(|(layer, floor)| {
-            let up_structures = floor.structures.get(&11).unwrap();
+            let up_structures: Vec<&Structure> =
+                stadium::Floor::filter_structures(&floor.structures, &EXITS).collect();
 
             // Progress bar
             let progress_bar = ProgressBar::new(up_structures.len().try_into().unwrap());
 
-            progress_bar.set_message(format!("{layer} - Up stairs"));
+            progress_bar.set_message(format!("{} - Exits", registry.layer_name(*layer)));
 
             progress_bar.enable_steady_tick(1000);
 
@@ -467,9 +1681,11 @@ impl World {
                     .map(|structure| {
                         let structure_exits = building
                             .par_iter()
-                            .filter(|(arriving_layer, _)| arriving_layer.to_string() != *layer) // Remove current floor
+                            .filter(|(arriving_layer, _)| *arriving_layer != layer) // Remove current floor
                             .filter_map(|(arriving_layer, arriving_floor)| {
-                                let search_space = arriving_floor.structures.get(&10).unwrap();
+                                // Connections land on the same kind of structure
+                                let code = structure.kind().unwrap().entry_code();
+                                let search_space = arriving_floor.structures.get(&code).unwrap();
 
                                 match search_space.is_empty() {
                                     true => None, // No aviable conexion
@@ -477,63 +1693,163 @@ impl World {
                                         .get_closest_structure(&Vec::from_iter(
                                             search_space.to_owned(),
                                         ))
-                                        .map(|structure| (arriving_layer.to_string(), structure)),
+                                        .map(|structure| {
+                                            (
+                                                *arriving_layer,
+                                                registry.id(*arriving_layer, &structure).unwrap(),
+                                            )
+                                        }),
                                 }
                             });
 
                         (
-                            structure.to_owned(),
+                            registry.id(*layer, structure).unwrap(),
                             HashMap::from_par_iter(structure_exits),
                         )
                     });
 
-            (layer.to_string(), HashMap::from_par_iter(layer_stairs))
+            (*layer, HashMap::from_par_iter(layer_stairs))
         });
         HashMap::from_iter(conexions)
     }
 }
 
 // Generate a unique HashMap with the whole simulation with index for checkpointing and agents
-pub fn create_world(configuration: Parameters) -> World {
-    let floors = configuration.topology.layers();
-
-    println!("[INFO] Creating world");
-    let start = Instant::now();
+// Floors of the venue and the connections between them
+pub fn load_building(
+    configuration: &Parameters,
+    registry: &mut Registry,
+) -> (HashMap<LayerId, stadium::Floor>, Conexions) {
+    let venue = configuration.venue_tags.files();
+    let floors = configuration.topology.layers(&venue.bundle);
 
     let building = HashMap::from_iter(floors.into_iter().map(|(floor, path)| {
-        (
+        let floor = stadium::Floor::create_floor(
+            path,
             floor.to_string(),
-            stadium::Floor::create_floor(path, floor.to_string()),
-        )
+            configuration,
+            &venue.mouths_info,
+            venue.seats_info.as_deref(),
+            registry,
+        );
+
+        (floor.layer, floor)
     }));
 
-    println!("[INFO] Building created");
+    info!(World, "Building created");
+
+    let building_conexions = match &venue.connections_info {
+        Some(path) if Path::new(path).exists() => World::map_structures(&building, path, registry),
+        Some(path) => {
+            warn!(
+                Loaders,
+                "Connections file {path} not found, nearest structures are linked"
+            );
+            World::connect_structures(&building, registry)
+        }
+        None => World::connect_structures(&building, registry),
+    };
+
+    (building, building_conexions)
+}
+
+pub fn create_world(configuration: &Parameters) -> World {
+    let venue = configuration.venue_tags.files();
+
+    info!(World, "Creating world");
+    let start = Instant::now();
+
+    let mut registry = Registry::default();
+    let (building, building_conexions) = load_building(configuration, &mut registry);
+
+    let mouths = HashMap::from_iter(building.iter().flat_map(|(layer, floor)| {
+        floor
+            .mouths
+            .iter()
+            .map(|(mouth, id)| ((*layer, *mouth), *id))
+    }));
+
+    let gates = load_gates(&venue.gates_info);
+
+    // Gates are nodes of the building graph too
+    gates.iter().for_each(|gate| {
+        let layer = registry
+            .layer_id(&gate.floor)
+            .expect("[ERROR] Gate on an unknown layer");
+        registry.intern(layer, &gate.structure);
+        registry.intern_gate(&gate.name);
+    });
+
+    let gates_to_stairs = World::gates_stairs(&building, &gates, &registry);
+    let gates_to_mouths = World::gates_mouths(&building, &gates, &registry);
+
+    let layer_map = load_layer_map(&venue.mouths_info, &venue.gates_info);
+    let arrivals = select_arrivals(configuration, &layer_map);
+    layer_map.report_unmapped(&arrivals);
+
+    let clock = MatchClock::new(
+        configuration.match_start(),
+        configuration.seconds_per_step(),
+        &configuration.timeline,
+    );
 
     let w = World {
         step: 0,
         agent_count: 0,
-        building_conexions: World::connect_structures(&building),
-        gates: load_gates(),
-        gates_buffer: HashMap::from_iter(
-            load_gates()
-                .iter()
-                .map(|gate| (gate.to_owned(), VecDeque::new())),
+        layer_graph: World::layer_graph(
+            &building,
+            &building_conexions,
+            &gates_to_stairs,
+            &gates_to_mouths,
+            &registry,
         ),
-        gates_to_stairs: World::gates_stairs(&building, &load_gates()),
-        gates_to_mouths: World::gates_mouths(&building, &load_gates()),
-        arrivals: load_arrivals(),
+        building_conexions,
+        registry,
+        gates_buffer: HashMap::from_iter(gates.iter().map(|gate| {
+            let turnstiles = configuration.gates.turnstiles(&gate.name);
+            (
+                gate.to_owned(),
+                GateQueue::new(turnstiles, clock.steps_per_minute()),
+            )
+        })),
+        turnstile_service: ServiceTime::new(&configuration.gates, configuration.seconds_per_step()),
+        gates_to_stairs,
+        gates_to_mouths,
+        gates,
+        arrivals,
+        layer_map,
+        services: configuration.services.to_owned(),
         building,
         agent_path: HashMap::new(),
         agent_target: HashMap::new(),
+        accessibility_ratio: configuration.connections.accessibility_ratio,
+        follow_probability: configuration.agent_data.prov_follow as f64 / 100.0,
+        seed: configuration.seed.value,
+        clock,
+        phase: Phase::PreMatch,
+        seated: HashMap::new(),
+        exited: 0,
+        mouths,
+        owners: HashMap::new(),
+        outbox: Vec::new(),
+        balancer: Balancer::new(&configuration.mpi_auto_adjustment),
+        instrumentation: Instrumentation::new(configuration.instrumentation()),
+        lifecycle: Lifecycle::default(),
+        incidents: match &configuration.incidents {
+            Some(script) if script.enabled => Incidents::load(&script.file),
+            _ => Incidents::default(),
+        },
     };
 
-    println!("[INFO] Environment created [{:?}]", start.elapsed());
+    info!(World, "Environment created [{:?}]", start.elapsed());
 
     w
 }
 
+// Loads a world written by bincode_save
+#[allow(dead_code)]
 pub fn bincode_load(path: String) -> World {
-    println!("[INFO] Loading bincode version...");
+    info!(World, "Loading bincode version...");
 
     let start = Instant::now();
 
@@ -541,7 +1857,7 @@ pub fn bincode_load(path: String) -> World {
 
     let w = deserialize_from(file).unwrap();
 
-    println!("[INFO] Elapsed time: {:?}", start.elapsed());
+    info!(World, "Elapsed time: {:?}", start.elapsed());
 
     w
 }
@@ -1,5 +1,4 @@
 #![crate_name = "pandorast"]
-#![feature(drain_filter)]
 
 mod config;
 mod engine;
@@ -8,9 +7,22 @@ mod iotwins_model {
     pub mod agent;
     pub mod arrivals;
     pub mod config;
+    pub mod crowd;
+    pub mod incidents;
+    pub mod instrumentation;
+    pub mod legend;
+    pub mod lifecycle;
+    pub mod partition;
+    pub mod registry;
     pub mod routes;
+    pub mod seating;
+    pub mod services;
     pub mod stadium;
     pub mod structures;
+    pub mod timeline;
+    pub mod transit;
+    pub mod turnstiles;
+    pub mod validation;
     pub mod world;
 }
 
@@ -20,11 +32,80 @@ use std::time::Instant;
 use mimalloc::MiMalloc;
 use rand::distributions::Uniform;
 
-use crate::iotwins_model::arrivals;
+use crate::{
+    config::configuration::Parameters,
+    engine::{
+        logging::{self, info},
+        mpi::Communicator,
+    },
+    iotwins_model::{partition, validation, world::World},
+};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+// Checks the venue data and exits instead of simulating
+const VALIDATE_FLAG: &str = "--validate";
+
+// Every process simulates its floors, the root gets the world joined with the agents swapped
+fn simulate(
+    configuration: &Parameters,
+    communicator: &mut Communicator,
+    progress_bar: ProgressBar,
+) -> Option<(World, usize)> {
+    let output = &configuration.output;
+    logging::set_rank(communicator.rank);
+
+    let world = communicator.is_root().then(|| {
+        let w = iotwins_model::world::create_world(configuration);
+        w.bincode_save(&output.path(&output.bincode_file));
+        w.save_structures(&output.path(&output.structures_file));
+        w.save_layer_paths(&output.path(&output.layer_paths_dir));
+
+        let owners = partition::split_floors(
+            partition::initial_loads(&w.building),
+            communicator.size,
+            configuration.mpi.uneven_partitioning,
+        );
+
+        (w, owners)
+    });
+
+    // Every process starts from the root world and keeps its floors
+    let (mut w, owners) = communicator.broadcast(world);
+    w.partition(owners, communicator.rank);
+    logging::set_layers(w.registry.layers());
+
+    let interest = Uniform::from(0_f64..1_f64);
+
+    let steps = configuration.num_steps.value;
+    info!(
+        Engine,
+        "Simulating {steps} steps, until minute {:.1}",
+        w.clock.minute(steps)
+    );
+
+    let mut total_swapped = 0;
+
+    (0..steps).progress_with(progress_bar).for_each(|_| {
+        total_swapped += w.evolve(interest);
+        w.exchange(communicator);
+        w.rebalance(communicator);
+    });
+
+    logging::clear_step();
+
+    let mut parts = communicator.gather((w, total_swapped))?.into_iter();
+    let (mut w, mut total_swapped) = parts.next().unwrap();
+
+    parts.for_each(|(part, swapped)| {
+        w.merge(part);
+        total_swapped += swapped;
+    });
+
+    Some((w, total_swapped))
+}
+
 fn main() {
     println!("Welcome to Pandorast!");
     let start_time = Instant::now();
@@ -39,18 +120,22 @@ fn main() {
     let configuration =
         config::configuration::Parameters::load_configuration(String::from("IoTwins_config.toml"));
 
-    let mut w = iotwins_model::world::create_world(configuration);
-    w.bincode_save();
-    w.save_structures();
-    w.save_layer_paths();
+    let output = &configuration.output;
+    configuration.init_logs();
 
-    let interest = Uniform::from(0_f64..1_f64);
+    if std::env::args().any(|arg| arg == VALIDATE_FLAG) {
+        let problems = validation::validate(&configuration, &output.path(&output.validation_dir));
+        std::process::exit((problems > 0) as i32);
+    }
 
-    // let mut w = iotwins_model::world::bincode_load(String::from("resources/IoTwins.bin"));
-    // w.arrivals = arrivals::load_arrivals();
+    // Workers are started here by the root process
+    let mut communicator = Communicator::init(configuration.mpi.processes);
 
     // Progress bar
-    let progress_bar = ProgressBar::new(40000);
+    let progress_bar = match communicator.is_root() {
+        true => ProgressBar::new(configuration.num_steps.value as u64),
+        false => ProgressBar::hidden(),
+    };
 
     progress_bar.set_style(
         ProgressStyle::default_spinner()
@@ -60,27 +145,205 @@ fn main() {
 
     // End of progress bar
 
-    let mut total_swapped = 0;
+    // Results of every process are joined by the root
+    let (mut w, total_swapped) = match simulate(&configuration, &mut communicator, progress_bar) {
+        Some(results) => results,
+        None => return,
+    };
 
-    // 0.6s per iteration (200 minutes)
-    (0..40000).progress_with(progress_bar).for_each(|_| {
-        // 250 minutes
-        total_swapped += w.evolve(interest);
-    });
+    communicator.finalize();
+    w.census();
 
-    println!("[INFO] Simulation time: {:?}", start_time.elapsed());
+    info!(Engine, "Simulation time: {:?}", start_time.elapsed());
 
     // Export pathing for each agent
-    w.generate_save();
+    w.generate_save(&output.path(&output.paths_file));
+    w.save_gates_report(
+        &output.path(&output.gates_file),
+        &output.path(&output.gates_queues_file),
+    );
+    w.save_services_report(&output.path(&output.services_file));
+    w.save_seating_report(&output.path(&output.seating_file));
+    w.incidents
+        .save(&output.path(&output.incidents_file), &w.clock);
+    w.instrumentation.save(
+        &output.path(&output.instrumentation_file),
+        &output.path(&output.instrumentation_floors_file),
+        &w.registry,
+    );
+    w.instrumentation.summary();
+    w.lifecycle.save(
+        &output.path(&output.lifecycle_agents_file),
+        &output.path(&output.lifecycle_file),
+        &w.registry,
+    );
 
     // Agents correctly simulated
     let simulated_agents = w.agent_path.len();
     // Post-simulation information
-    println!("[INFO] End of simulation");
-    println!("[INFO] Total simulation: {:?}", start_time.elapsed());
-    println!("[INFO] Total steps: {}", w.step);
-    println!("[INFO] Total agents: {}", w.agent_count);
-    println!("[INFO] Total agent with path: {}", simulated_agents);
-    println!("[INFO] Total agents swapped: {total_swapped}");
-    println!("[INFO] Agent lost: {}", w.agent_count - simulated_agents);
+    info!(Engine, "End of simulation");
+    info!(Engine, "Total simulation: {:?}", start_time.elapsed());
+    info!(Engine, "Total steps: {}", w.step);
+    info!(Engine, "Total agents: {}", w.agent_count);
+    info!(Engine, "Total agent with path: {}", simulated_agents);
+    info!(Engine, "Total agents swapped: {total_swapped}");
+
+    let (on_connections, queued) = w.connections_load();
+    info!(
+        Engine,
+        "Agents still on stairs: {on_connections} ({queued} queued)"
+    );
+    info!(Engine, "Agent lost: {}", w.lifecycle.lost());
+    info!(
+        Engine,
+        "Agents seated: {}, exited: {}",
+        w.seated.len(),
+        w.exited
+    );
+    w.lifecycle.summary(w.agent_count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+    use std::path::Path;
+    use toml::Value;
+
+    const SIDE: u32 = 627;
+
+    // Blueprint of walls with an open hall, a stair of the given luma inside it
+    fn blueprint(path: &Path, stair: Option<u8>) {
+        let mut image = GrayImage::from_pixel(SIDE, SIDE, Luma([84]));
+
+        (100..140).for_each(|row| {
+            (100..200).for_each(|col| {
+                let luma = match (stair, (118..122).contains(&row) && col >= 190) {
+                    (Some(stair), true) => stair,
+                    _ => 255,
+                };
+
+                image.put_pixel(col, row, Luma([luma]));
+            })
+        });
+
+        image.save(path).unwrap();
+    }
+
+    // Gate and up stair on PB, down stair and mouth on P1, every other floor is walled off.
+    // Runs with the given processes and returns the sorted rows of the paths and fates
+    fn run(processes: usize) -> (Vec<String>, Vec<String>) {
+        let dir = std::env::temp_dir().join(format!(
+            "pandorast_venue_{}_{processes}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str| dir.join(name).to_string_lossy().to_string();
+
+        blueprint(&dir.join("PB.png"), Some(232));
+        blueprint(&dir.join("P1.png"), Some(109));
+        GrayImage::from_pixel(SIDE, SIDE, Luma([84]))
+            .save(dir.join("walls.png"))
+            .unwrap();
+
+        std::fs::write(file("gates.csv"), "layer,gate,x,y\nPB,G1,120,105\n").unwrap();
+        std::fs::write(file("mouths.csv"), "mouth,layer,x,y\n1,P1,120,110\n").unwrap();
+        std::fs::write(
+            file("arrivals.csv"),
+            "gate,mouth,minutes_to_game,agents\nG1,1,-10,20\nG1,1,-9,20\n",
+        )
+        .unwrap();
+
+        let data = std::fs::read_to_string("IoTwins_config.toml").unwrap();
+        let mut config: Value = toml::from_str(&data).unwrap();
+        let mut set = |section: &str, key: &str, value: Value| {
+            config[section]
+                .as_table_mut()
+                .unwrap()
+                .insert(key.to_string(), value);
+        };
+
+        set("output", "results_dir", Value::from(file("data")));
+        set("num_steps", "value", Value::from(500));
+        set("logs", "print_in_console", Value::from(false));
+        set("logs", "print_instrumentation", Value::from(false));
+        set("mpi", "processes", Value::from(processes as i64));
+        set("mpi_auto_adjustment", "auto_mode", Value::from(false));
+        set("match_timings", "match_start", Value::from(-10.0));
+        set("venue_tags", "gates_info", Value::from(file("gates.csv")));
+        set("venue_tags", "mouths_info", Value::from(file("mouths.csv")));
+        set(
+            "venue_tags",
+            "arrivals_info_csv",
+            Value::from(file("arrivals.csv")),
+        );
+
+        [
+            "pb", "p05", "p1", "p15", "p2", "p3", "p35", "p4", "p5", "s1",
+        ]
+        .iter()
+        .for_each(|layer| {
+            let map = match *layer {
+                "pb" => file("PB.png"),
+                "p1" => file("P1.png"),
+                _ => file("walls.png"),
+            };
+            set("topology", &format!("layout_{layer}"), Value::from(map));
+        });
+
+        let configuration: Parameters = config.try_into().unwrap();
+        let output = &configuration.output;
+
+        // Every process runs on a thread of its own, the root gets the results
+        let results = std::thread::scope(|scope| {
+            let runs: Vec<_> = Communicator::local(processes)
+                .into_iter()
+                .map(|mut communicator| {
+                    let configuration = &configuration;
+                    scope.spawn(move || {
+                        simulate(configuration, &mut communicator, ProgressBar::hidden())
+                    })
+                })
+                .collect();
+
+            let mut results: Vec<_> = runs.into_iter().map(|run| run.join().unwrap()).collect();
+            results.swap_remove(0)
+        });
+
+        let (mut w, _) = results.unwrap();
+        w.census();
+        w.generate_save(&output.path(&output.paths_file));
+        w.lifecycle.save(
+            &output.path(&output.lifecycle_agents_file),
+            &output.path(&output.lifecycle_file),
+            &w.registry,
+        );
+
+        let rows = |name: &str| {
+            let mut rows: Vec<String> = std::fs::read_to_string(output.path(name))
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect();
+            rows.sort();
+            rows
+        };
+
+        let results = (
+            rows(&output.paths_file),
+            rows(&output.lifecycle_agents_file),
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+
+        results
+    }
+
+    #[test]
+    fn two_processes_match_one() {
+        let (paths, fates) = run(1);
+
+        // Agents crossed from the floor of one process to the floor of the other
+        assert!(fates.iter().any(|row| row.contains("seated")));
+        assert_eq!(run(2), (paths, fates));
+    }
 }